@@ -0,0 +1,340 @@
+//! SQLite persistence of accounts, public key bundles, sessions and the
+//! queue of messages for recipients that are offline.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rand_core::{OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    protocol::{unix_time, KeyBundle, KeyPairB64},
+    Error,
+};
+
+/// How long a session token resumes a login.
+const SESSION_SECS: u64 = 30 * 24 * 60 * 60;
+/// Queued messages nobody picked up within this time are dropped.
+const QUEUE_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    password TEXT NOT NULL,
+    identity TEXT NOT NULL,
+    prekey TEXT NOT NULL,
+    signature TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS onetime_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user TEXT NOT NULL,
+    key TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    -- token_hash() of the token, never the token itself
+    token TEXT PRIMARY KEY,
+    user TEXT NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    recipient TEXT NOT NULL,
+    binary INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    queued_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS queue_recipient ON queue (recipient);
+";
+
+/// A websocket message waiting for its recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Queued {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Sessions are stored by this hash of their token, so a leaked database logs nobody in.
+/// Tokens are 32 random bytes, a plain SHA-256 is enough.
+fn token_hash(token: &str) -> String {
+    BASE64_STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+
+        let now = unix_time();
+        conn.execute("DELETE FROM sessions WHERE expires < ?1", params![now])?;
+        conn.execute(
+            "DELETE FROM queue WHERE queued_at < ?1",
+            params![now.saturating_sub(QUEUE_RETENTION_SECS)],
+        )?;
+
+        Ok(Self { conn })
+    }
+
+    /// Creates the account `name` with its password hash and public bundle, `false` if the name
+    /// is taken.
+    pub fn create_user(&self, name: &str, hash: &str, bundle: &KeyBundle) -> Result<bool, Error> {
+        if self.user_exists(name)? {
+            return Ok(false);
+        }
+
+        self.conn.execute(
+            "INSERT INTO users (name, password, identity, prekey, signature) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                name,
+                hash,
+                bundle.identity.public,
+                bundle.prekey.public,
+                bundle.signature.public
+            ],
+        )?;
+        self.add_onetime_keys(name, &bundle.onetime_keys)?;
+
+        Ok(true)
+    }
+
+    pub fn user_exists(&self, name: &str) -> Result<bool, Error> {
+        let found = self
+            .conn
+            .query_row("SELECT 1 FROM users WHERE name = ?1", params![name], |_| {
+                Ok(())
+            })
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    /// Password hash of `name`, `None` for an unknown user.
+    pub fn password_hash(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT password FROM users WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Bundle of `name` for starting a session, the one-time key in it is used up.
+    pub fn take_bundle(&self, name: &str) -> Result<Option<KeyBundle>, Error> {
+        let keys = self
+            .conn
+            .query_row(
+                "SELECT identity, prekey, signature FROM users WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((identity, prekey, signature)) = keys else {
+            return Ok(None);
+        };
+
+        let onetime_key: Option<(i64, String)> = self
+            .conn
+            .query_row(
+                "SELECT id, key FROM onetime_keys WHERE user = ?1 ORDER BY id LIMIT 1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        // without one-time keys left the session is started with the 3-DH variant
+        let mut onetime_keys = Vec::new();
+        if let Some((id, key)) = onetime_key {
+            self.conn
+                .execute("DELETE FROM onetime_keys WHERE id = ?1", params![id])?;
+            onetime_keys.push(KeyPairB64::public(key));
+        }
+
+        Ok(Some(KeyBundle {
+            identity: KeyPairB64::public(identity),
+            prekey: KeyPairB64::public(prekey),
+            signature: KeyPairB64::public(signature),
+            onetime_keys,
+            ..Default::default()
+        }))
+    }
+
+    pub fn add_onetime_keys(&self, name: &str, keys: &[KeyPairB64]) -> Result<(), Error> {
+        for key in keys {
+            self.conn.execute(
+                "INSERT INTO onetime_keys (user, key) VALUES (?1, ?2)",
+                params![name, key.public],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn otk_count(&self, name: &str) -> Result<usize, Error> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM onetime_keys WHERE user = ?1",
+            params![name],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Replaces the signed prekey of `name`.
+    pub fn set_prekey(&self, name: &str, prekey: &str, signature: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE users SET prekey = ?2, signature = ?3 WHERE name = ?1",
+            params![name, prekey, signature],
+        )?;
+        Ok(())
+    }
+
+    /// New session token of `name`.
+    pub fn create_session(&self, name: &str) -> Result<String, Error> {
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = BASE64_STANDARD.encode(token);
+
+        self.conn.execute(
+            "INSERT INTO sessions (token, user, expires) VALUES (?1, ?2, ?3)",
+            params![token_hash(&token), name, unix_time() + SESSION_SECS],
+        )?;
+        Ok(token)
+    }
+
+    /// Account `token` belongs to, if it did not expire.
+    pub fn session_user(&self, token: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT user FROM sessions WHERE token = ?1 AND expires >= ?2",
+                params![token_hash(token), unix_time()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn end_session(&self, token: &str) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM sessions WHERE token = ?1", params![token_hash(token)])?;
+        Ok(())
+    }
+
+    pub fn enqueue(&self, recipient: &str, msg: &Queued) -> Result<(), Error> {
+        let (binary, data) = match msg {
+            Queued::Text(text) => (false, text.as_bytes()),
+            Queued::Binary(data) => (true, data.as_slice()),
+        };
+        self.conn.execute(
+            "INSERT INTO queue (recipient, binary, data, queued_at) VALUES (?1, ?2, ?3, ?4)",
+            params![recipient, binary, data, unix_time()],
+        )?;
+        Ok(())
+    }
+
+    /// Messages queued for `recipient` in the order they arrived, with their row id.
+    pub fn queued(&self, recipient: &str) -> Result<Vec<(i64, Queued)>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, binary, data FROM queue WHERE recipient = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![recipient], |row| {
+            let binary: bool = row.get(1)?;
+            let data: Vec<u8> = row.get(2)?;
+            let msg = match binary {
+                true => Queued::Binary(data),
+                false => Queued::Text(String::from_utf8_lossy(&data).to_string()),
+            };
+            Ok((row.get(0)?, msg))
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn dequeue(&self, id: i64) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM queue WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+/// Argon2 hash of `password` to store with the account. Slow on purpose, keep it off the
+/// executor.
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| Error::Password(e.to_string()))?
+        .to_string())
+}
+
+/// Whether `password` matches `hash`, as slow as hashing it.
+pub fn verify_password(hash: &str, password: &str) -> Result<bool, Error> {
+    let hash = PasswordHash::new(hash).map_err(|e| Error::Password(e.to_string()))?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
+#[test]
+fn check_store() {
+    let store = Store::in_memory().unwrap();
+    let bundle = KeyBundle {
+        identity: KeyPairB64::public("id".to_string()),
+        prekey: KeyPairB64::public("pk".to_string()),
+        signature: KeyPairB64::public("sig".to_string()),
+        onetime_keys: vec![KeyPairB64::public("otk".to_string())],
+        ..Default::default()
+    };
+
+    let hash = hash_password("hunter2").unwrap();
+    assert!(store.create_user("bob", &hash, &bundle).unwrap());
+    assert!(!store.create_user("bob", &hash, &bundle).unwrap());
+    let stored = store.password_hash("bob").unwrap().unwrap();
+    assert!(verify_password(&stored, "hunter2").unwrap());
+    assert!(!verify_password(&stored, "hunter3").unwrap());
+    assert_eq!(store.password_hash("eve").unwrap(), None);
+
+    // every one-time key is handed out once
+    let taken = store.take_bundle("bob").unwrap().unwrap();
+    assert_eq!(taken.onetime_keys[0].public, "otk");
+    assert!(store
+        .take_bundle("bob")
+        .unwrap()
+        .unwrap()
+        .onetime_keys
+        .is_empty());
+    assert_eq!(store.otk_count("bob").unwrap(), 0);
+
+    let token = store.create_session("bob").unwrap();
+    assert_eq!(store.session_user(&token).unwrap().as_deref(), Some("bob"));
+    let stored: String = store
+        .conn
+        .query_row("SELECT token FROM sessions WHERE user = 'bob'", [], |row| row.get(0))
+        .unwrap();
+    assert_ne!(stored, token);
+    store.end_session(&token).unwrap();
+    assert_eq!(store.session_user(&token).unwrap(), None);
+
+    store
+        .enqueue("bob", &Queued::Text("a".to_string()))
+        .unwrap();
+    store.enqueue("bob", &Queued::Binary(vec![1, 2])).unwrap();
+    let queued = store.queued("bob").unwrap();
+    assert_eq!(queued[1].1, Queued::Binary(vec![1, 2]));
+    store.dequeue(queued[0].0).unwrap();
+    assert_eq!(store.queued("bob").unwrap().len(), 1);
+}
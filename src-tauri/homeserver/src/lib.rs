@@ -0,0 +1,82 @@
+//! Reference homeserver for cipher-chat.
+//!
+//! Accounts, public key bundles, session tokens and the queue of messages
+//! for offline recipients live in SQLite. Message contents are end to end
+//! encrypted, the homeserver only sees who talks to whom.
+
+pub mod db;
+pub mod protocol;
+pub mod server;
+
+use std::sync::Arc;
+
+use log::{error, info};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+pub use db::Store;
+pub use server::Server;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Db(#[from] rusqlite::Error),
+    /// Boxed, it would make every `Result` of the server this large.
+    #[error(transparent)]
+    Tung(Box<tokio_tungstenite::tungstenite::Error>),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[error(transparent)]
+    Frame(#[from] protocol::FrameError),
+    #[error(transparent)]
+    Packet(#[from] protocol::PacketError),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+
+    #[error("password hashing failed: {0}")]
+    Password(String),
+
+    #[error("{0}")]
+    Protocol(String),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::Tung(Box::new(e))
+    }
+}
+
+/// Accept connections on `listener` until it fails, with TLS if `tls` is set.
+pub async fn serve(
+    listener: TcpListener,
+    server: Arc<Server>,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), Error> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("connection from {}", addr);
+
+        let server = server.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let stream: Box<dyn server::Io> = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => {
+                        error!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                },
+                None => Box::new(stream),
+            };
+
+            if let Err(e) = server.handle(stream).await {
+                info!("connection with {} ended: {}", addr, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,70 @@
+use std::{path::PathBuf, sync::Arc};
+
+use clap::Parser;
+use log::{info, warn};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+    TlsAcceptor,
+};
+
+use cipher_chat_homeserver::{serve, Error, Server, Store};
+
+/// Relays end to end encrypted cipher-chat messages.
+#[derive(Parser)]
+struct Args {
+    /// Address to listen on, the client connects to port 9999.
+    #[arg(long, default_value = "0.0.0.0:9999")]
+    listen: String,
+
+    /// SQLite database, created if missing.
+    #[arg(long, default_value = "homeserver.db")]
+    db: String,
+
+    /// PEM certificate chain, serves plain websockets if left out.
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate.
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    let args = Args::parse();
+
+    let tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => Some(tls_acceptor(cert, key)?),
+        _ => {
+            warn!("no certificate given, serving unencrypted websockets");
+            None
+        }
+    };
+
+    let server = Server::new(Store::open(&args.db)?);
+    let listener = TcpListener::bind(&args.listen).await?;
+    info!("listening on {}", args.listen);
+
+    serve(listener, server, tls).await
+}
+
+fn tls_acceptor(cert: &PathBuf, key: &PathBuf) -> Result<TlsAcceptor, Error> {
+    let invalid = |e| Error::Protocol(format!("could not read certificate or key: {}", e));
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,27 @@
+//! The messages the client exchanges with the homeserver.
+//!
+//! The wire format lives in the `cipher-chat-protocol` crate shared with the
+//! client. Message contents are end to end encrypted and never looked at,
+//! they are kept as raw JSON so relaying does not drop anything a newer client
+//! sends. Every client is answered in the format it speaks: the legacy
+//! `MsgPayload` until its login announced a wire version, versioned packets
+//! from then on. Relayed packets are translated to the format of their
+//! recipient. Binary frames are only opened for their header, to route them.
+
+use serde_json::Value;
+
+pub use cipher_chat_protocol::frame::{self, FrameError};
+pub use cipher_chat_protocol::packet::*;
+pub use cipher_chat_protocol::{KeyBundle, KeyPairB64, OpAuthPayload};
+
+pub type MsgPayload = cipher_chat_protocol::MsgPayload<Value>;
+pub type Packet = cipher_chat_protocol::Packet<Value>;
+pub type Handshake = cipher_chat_protocol::Handshake<Value>;
+pub type Frame<'a> = frame::Frame<'a, Value>;
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,507 @@
+//! One task per websocket connection. Authentication and key requests are
+//! answered directly, everything else is relayed to its recipient or queued
+//! until the recipient comes online. Packets are queued in the versioned
+//! format and go out in the format of the receiving connection.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+};
+
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use cipher_chat_protocol::SecretString;
+
+use crate::{
+    db::{hash_password, verify_password, Queued, Store},
+    protocol::{
+        decode, encode, frame, Ack, AuthAction, AuthRequest, AuthResponse, BundleResponse, Frame,
+        MsgPayload, OtkCount, Packet, PacketFailure, WireFormat,
+    },
+    Error,
+};
+
+/// Any stream a websocket can run on, plain TCP or TLS.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+type WsStream = WebSocketStream<Box<dyn Io>>;
+type WsSender = Arc<Mutex<PacketSink>>;
+
+/// Sending half of a connection and the format its client reads.
+struct PacketSink {
+    sink: SplitSink<WsStream, Message>,
+    format: WireFormat,
+}
+
+pub struct Server {
+    /// Only used inside `with_store`, SQLite calls block.
+    store: Arc<StdMutex<Store>>,
+    /// Connections of the logged in users.
+    online: Mutex<HashMap<String, WsSender>>,
+}
+
+/// State of a single connection.
+struct Conn {
+    ws_sender: WsSender,
+    user: Option<String>,
+    token: Option<SecretString>,
+}
+
+impl Server {
+    pub fn new(store: Store) -> Arc<Self> {
+        Arc::new(Self {
+            store: Arc::new(StdMutex::new(store)),
+            online: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Run `f` on the store on the blocking pool.
+    async fn with_store<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Store) -> Result<T, Error> + Send + 'static,
+    {
+        let store = self.store.clone();
+        blocking(move || f(&store.lock().unwrap_or_else(PoisonError::into_inner))).await
+    }
+
+    /// Whether `user` has a logged in connection.
+    pub async fn is_online(&self, user: &str) -> bool {
+        self.online.lock().await.contains_key(user)
+    }
+
+    /// Serve one client until it disconnects.
+    pub async fn handle(self: Arc<Self>, stream: Box<dyn Io>) -> Result<(), Error> {
+        let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+        let (ws_sender, mut ws_rcvr) = ws_stream.split();
+
+        let mut conn = Conn {
+            ws_sender: Arc::new(Mutex::new(PacketSink {
+                sink: ws_sender,
+                format: WireFormat::Legacy,
+            })),
+            user: None,
+            token: None,
+        };
+
+        while let Some(msg) = ws_rcvr.next().await {
+            let result = match msg? {
+                Message::Text(txt) => self.handle_text(&mut conn, txt).await,
+                Message::Binary(data) => self.handle_binary(&conn, data).await,
+                Message::Ping(data) => {
+                    // replaces the pong tungstenite queued, so only one goes out
+                    send(&conn.ws_sender, Message::Pong(data)).await
+                }
+                Message::Close(_) => break,
+                _ => Ok(()),
+            };
+
+            if let Err(e) = result {
+                error!("{}: {}", conn.user.as_deref().unwrap_or("anonymous"), e);
+
+                let failure = Packet::Error(PacketFailure {
+                    message: e.to_string(),
+                });
+                if let Err(e) = send_packet(&conn.ws_sender, &failure).await {
+                    info!("could not report the error: {}", e);
+                }
+            }
+        }
+
+        self.go_offline(&conn).await;
+        Ok(())
+    }
+
+    async fn handle_text(&self, conn: &mut Conn, txt: String) -> Result<(), Error> {
+        let (packet, format) = decode(&txt)?;
+
+        match packet {
+            Packet::AuthRequest(request) => {
+                // answered in the format the client announced with it
+                conn.ws_sender.lock().await.format = format;
+                self.authenticate(conn, request).await
+            }
+            Packet::BundleRequest(request) => {
+                logged_in(conn)?;
+                let user = request.user.clone();
+                let bundle = self
+                    .with_store(move |store| store.take_bundle(&user))
+                    .await?;
+
+                let response = BundleResponse {
+                    user: request.user.clone(),
+                    recipient: conn.user.clone().unwrap_or_default(),
+                    error: bundle.is_none().then(|| "unknown user".to_string()),
+                    keybundle: bundle,
+                };
+                send_packet(&conn.ws_sender, &Packet::BundleResponse(response)).await?;
+
+                // the owner uploads new one-time keys once it runs low
+                self.send_otk_count(&request.user).await
+            }
+            Packet::UploadPrekeys(upload) => {
+                let user = logged_in(conn)?.to_string();
+                let count = self
+                    .with_store(move |store| {
+                        store.add_onetime_keys(&user, &upload.keybundle.onetime_keys)?;
+                        store.otk_count(&user)
+                    })
+                    .await?;
+                info!("{} has {} one-time keys", logged_in(conn)?, count);
+                Ok(())
+            }
+            Packet::RotatePrekey(upload) => {
+                let user = logged_in(conn)?.to_string();
+                self.with_store(move |store| {
+                    store.set_prekey(
+                        &user,
+                        &upload.keybundle.prekey.public,
+                        &upload.keybundle.signature.public,
+                    )
+                })
+                .await?;
+                info!("{} rotated the signed prekey", logged_in(conn)?);
+                Ok(())
+            }
+            Packet::Chat(msg) => self.relay_msg(conn, msg).await,
+            // handshakes and delivery receipts are relayed like messages, but never acked
+            Packet::Handshake(handshake) => {
+                check_author(conn, &handshake.author, "handshake")?;
+                let recipient = handshake.recipient.clone();
+                self.deliver_packet(&recipient, &Packet::Handshake(handshake))
+                    .await
+            }
+            Packet::Delivered(receipt) => {
+                check_author(conn, &receipt.author, &receipt.message_id)?;
+                let recipient = receipt.recipient.clone();
+                self.deliver_packet(&recipient, &Packet::Delivered(receipt))
+                    .await
+            }
+            packet => {
+                warn!("unexpected {} packet from a client", packet.kind());
+                Ok(())
+            }
+        }
+    }
+
+    async fn authenticate(&self, conn: &mut Conn, request: AuthRequest) -> Result<(), Error> {
+        match request.action {
+            AuthAction::Register => {
+                let created = match request.keybundle.clone() {
+                    Some(bundle) => {
+                        // hashed without holding the store, it takes a while
+                        let password = request.password.clone();
+                        let hash = blocking(move || hash_password(password.expose())).await?;
+                        let user = request.user.clone();
+                        self.with_store(move |store| store.create_user(&user, &hash, &bundle))
+                            .await?
+                    }
+                    None => false,
+                };
+                match created {
+                    true => self.log_in(conn, &request, None).await,
+                    false => reject(conn, &request, "username taken or no key bundle").await,
+                }
+            }
+            AuthAction::Login => {
+                let user = request.user.clone();
+                let hash = self
+                    .with_store(move |store| store.password_hash(&user))
+                    .await?;
+                let valid = match hash {
+                    Some(hash) => {
+                        let password = request.password.clone();
+                        blocking(move || verify_password(&hash, password.expose())).await?
+                    }
+                    None => false,
+                };
+                match valid {
+                    true => {
+                        self.log_in(conn, &request, None).await?;
+                        self.send_otk_count(&request.user).await
+                    }
+                    false => reject(conn, &request, "wrong username or password").await,
+                }
+            }
+            AuthAction::Resume => {
+                let token = request.token.clone().unwrap_or_default();
+                let session_token = token.clone();
+                let user = self
+                    .with_store(move |store| store.session_user(session_token.expose()))
+                    .await?;
+                match user {
+                    Some(user) if user == request.user => {
+                        self.log_in(conn, &request, Some(token)).await
+                    }
+                    _ => reject(conn, &request, "session expired").await,
+                }
+            }
+            AuthAction::Logout => {
+                if let Some(token) = conn.token.take() {
+                    self.with_store(move |store| store.end_session(token.expose()))
+                        .await?;
+                }
+                self.go_offline(conn).await;
+                conn.user = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Relay a chat message and acknowledge it to its author, a refusal is only reported in
+    /// the acknowledgement.
+    async fn relay_msg(&self, conn: &Conn, msg: MsgPayload) -> Result<(), Error> {
+        let result = match check_author(conn, &msg.author, &msg.message_id) {
+            Ok(()) => self.relay_to_known(msg.clone()).await,
+            Err(e) => Err(e),
+        };
+
+        ack(conn, &msg, result.as_ref().err()).await
+    }
+
+    /// Relay `msg` if its recipient has an account.
+    async fn relay_to_known(&self, msg: MsgPayload) -> Result<(), Error> {
+        let recipient = msg.recipient.clone();
+        if !self
+            .with_store(move |store| store.user_exists(&recipient))
+            .await?
+        {
+            return Err(Error::Protocol(format!(
+                "unknown recipient {}",
+                msg.recipient
+            )));
+        }
+
+        let recipient = msg.recipient.clone();
+        if msg.transient {
+            return self.deliver_online(&recipient, &Packet::Chat(msg)).await;
+        }
+        self.deliver_packet(&recipient, &Packet::Chat(msg)).await
+    }
+
+    /// Relay a binary frame, whole attachments are acknowledged like messages.
+    async fn handle_binary(&self, conn: &Conn, data: Vec<u8>) -> Result<(), Error> {
+        let (header, attachment) = match frame::decode(&data)? {
+            Frame::Attachment(header, _) => (header, true),
+            Frame::Chunk(header, _, _) => (header, false),
+        };
+
+        let result = match check_author(conn, &header.author, &header.message_id) {
+            Ok(()) => self.deliver(&header.recipient, Queued::Binary(data)).await,
+            Err(e) => Err(e),
+        };
+
+        match attachment {
+            true => ack(conn, &header, result.as_ref().err()).await,
+            false => result,
+        }
+    }
+
+    /// Send `packet` to `recipient` in the format it reads, queued in the versioned format.
+    async fn deliver_packet(&self, recipient: &str, packet: &Packet) -> Result<(), Error> {
+        let text = encode(packet, WireFormat::Versioned)?;
+        self.deliver(recipient, Queued::Text(text)).await
+    }
+
+    /// Send `packet` to `recipient` if it is online, otherwise drop it.
+    async fn deliver_online(&self, recipient: &str, packet: &Packet) -> Result<(), Error> {
+        let ws_sender = self.online.lock().await.get(recipient).cloned();
+
+        match ws_sender {
+            Some(ws_sender) => send_packet(&ws_sender, packet).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send `msg` to `recipient` if it is online, otherwise queue it.
+    async fn deliver(&self, recipient: &str, msg: Queued) -> Result<(), Error> {
+        let ws_sender = self.online.lock().await.get(recipient).cloned();
+
+        if let Some(ws_sender) = ws_sender {
+            match send_queued(&ws_sender, &msg).await {
+                Ok(()) => return Ok(()),
+                Err(e) => info!("{} went away, queueing: {}", recipient, e),
+            }
+        }
+
+        let recipient = recipient.to_string();
+        self.with_store(move |store| store.enqueue(&recipient, &msg))
+            .await
+    }
+
+    /// Accept the login of `request.user`, hand out a session token and deliver what was
+    /// queued.
+    async fn log_in(
+        &self,
+        conn: &mut Conn,
+        request: &AuthRequest,
+        token: Option<SecretString>,
+    ) -> Result<(), Error> {
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let user = request.user.clone();
+                self.with_store(move |store| store.create_session(&user))
+                    .await?
+                    .into()
+            }
+        };
+        info!("{} logged in", request.user);
+
+        // the token is what the client resumes with after a reconnect
+        let response = AuthResponse {
+            action: request.action,
+            user: request.user.clone(),
+            token: Some(token.clone()),
+            error: None,
+        };
+        send_packet(&conn.ws_sender, &Packet::AuthResponse(response)).await?;
+
+        conn.user = Some(request.user.clone());
+        conn.token = Some(token);
+        self.online
+            .lock()
+            .await
+            .insert(request.user.clone(), conn.ws_sender.clone());
+
+        self.flush_queue(conn, &request.user).await
+    }
+
+    async fn flush_queue(&self, conn: &Conn, user: &str) -> Result<(), Error> {
+        let recipient = user.to_string();
+        let queued = self
+            .with_store(move |store| store.queued(&recipient))
+            .await?;
+        if !queued.is_empty() {
+            info!("delivering {} queued messages to {}", queued.len(), user);
+        }
+
+        for (id, msg) in queued {
+            // stays queued if the connection drops halfway
+            match send_queued(&conn.ws_sender, &msg).await {
+                Err(Error::Packet(e)) => warn!("dropping unreadable queued packet: {}", e),
+                result => result?,
+            }
+            self.with_store(move |store| store.dequeue(id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Tell `user`, if online, how many one-time keys it has left.
+    async fn send_otk_count(&self, user: &str) -> Result<(), Error> {
+        let Some(ws_sender) = self.online.lock().await.get(user).cloned() else {
+            return Ok(());
+        };
+
+        let owner = user.to_string();
+        let count = OtkCount {
+            user: user.to_string(),
+            count: self
+                .with_store(move |store| store.otk_count(&owner))
+                .await?,
+        };
+        send_packet(&ws_sender, &Packet::OtkCount(count)).await
+    }
+
+    async fn go_offline(&self, conn: &Conn) {
+        let Some(user) = &conn.user else {
+            return;
+        };
+
+        // a newer connection of the same user stays
+        let mut online = self.online.lock().await;
+        if online
+            .get(user)
+            .is_some_and(|ws_sender| Arc::ptr_eq(ws_sender, &conn.ws_sender))
+        {
+            online.remove(user);
+            info!("{} went offline", user);
+        }
+    }
+}
+
+/// Run `f` on the blocking pool, for SQLite and password hashing.
+async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+fn logged_in(conn: &Conn) -> Result<&str, Error> {
+    conn.user
+        .as_deref()
+        .ok_or(Error::Protocol("not logged in".to_string()))
+}
+
+/// Nobody sends in the name of someone else.
+fn check_author(conn: &Conn, author: &str, what: &str) -> Result<(), Error> {
+    if logged_in(conn)? != author {
+        return Err(Error::Protocol(format!(
+            "{} is not the author of {}",
+            logged_in(conn)?,
+            what
+        )));
+    }
+    Ok(())
+}
+
+async fn reject(conn: &Conn, request: &AuthRequest, reason: &str) -> Result<(), Error> {
+    info!(
+        "{} of {} rejected: {}",
+        request.action.as_str(),
+        request.user,
+        reason
+    );
+
+    let response = AuthResponse {
+        action: request.action,
+        user: request.user.clone(),
+        token: None,
+        error: Some(reason.to_string()),
+    };
+    send_packet(&conn.ws_sender, &Packet::AuthResponse(response)).await
+}
+
+/// Confirm to the author that `msg` was taken, or say why not.
+async fn ack(conn: &Conn, msg: &MsgPayload, error: Option<&Error>) -> Result<(), Error> {
+    if let Some(e) = error {
+        info!("refused {} of {}: {}", msg.message_id, msg.author, e);
+    }
+
+    let ack = Ack {
+        message_id: msg.message_id.clone(),
+        author: msg.author.clone(),
+        recipient: msg.recipient.clone(),
+        error: error.map(|e| e.to_string()),
+    };
+    send_packet(&conn.ws_sender, &Packet::Ack(ack)).await
+}
+
+/// Send what `deliver` queued, packets in the format of the receiving connection.
+async fn send_queued(ws_sender: &WsSender, msg: &Queued) -> Result<(), Error> {
+    match msg {
+        Queued::Text(text) => send_packet(ws_sender, &decode(text)?.0).await,
+        Queued::Binary(data) => send(ws_sender, Message::binary(data.clone())).await,
+    }
+}
+
+async fn send_packet(ws_sender: &WsSender, packet: &Packet) -> Result<(), Error> {
+    let mut ws_sender = ws_sender.lock().await;
+    let text = encode(packet, ws_sender.format)?;
+    ws_sender.sink.send(Message::text(text)).await?;
+    Ok(())
+}
+
+async fn send(ws_sender: &WsSender, msg: Message) -> Result<(), Error> {
+    ws_sender.lock().await.sink.send(msg).await?;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! Binary websocket frames for attachments.
+//!
+//! A frame is the magic `CCF1`, the length of the header as big endian u32,
+//! the header itself and the raw AEAD ciphertext. The header is a `MsgPayload`
+//! in JSON with an empty ciphertext, so the homeserver routes it like any
+//! other message. The plaintext is the mime type prefixed by its length as
+//! big endian u16 followed by the file bytes, nothing is base64 encoded.
+//!
+//! Chunks of a file transfer use the magic `CCC1` and carry the chunk index as
+//! big endian u32 between header and ciphertext.
+
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(test)]
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::MsgPayload;
+
+pub const FRAME_MAGIC: &[u8; 4] = b"CCF1";
+pub const CHUNK_MAGIC: &[u8; 4] = b"CCC1";
+
+/// A decoded frame, the ciphertext borrows from the received data.
+pub enum Frame<'a, C> {
+    Attachment(MsgPayload<C>, &'a [u8]),
+    Chunk(MsgPayload<C>, u32, &'a [u8]),
+}
+
+/// Frame errors.
+#[derive(Debug, Error)]
+pub enum FrameError {
+    /// Not a frame of this format or version.
+    #[error("unknown frame magic")]
+    BadMagic,
+
+    /// Frame or attachment ended before the announced length.
+    #[error("truncated frame")]
+    Truncated,
+
+    /// Frame header was not a valid message.
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+/// `Result` specialized for framing.
+pub type FrameResult<T> = Result<T, FrameError>;
+
+pub fn encode<C: Serialize>(header: &MsgPayload<C>, ciphertext: &[u8]) -> FrameResult<Vec<u8>> {
+    let mut frame = encode_header(FRAME_MAGIC, header, ciphertext.len())?;
+    frame.extend(ciphertext);
+
+    Ok(frame)
+}
+
+pub fn encode_chunk<C: Serialize>(
+    header: &MsgPayload<C>,
+    index: u32,
+    ciphertext: &[u8],
+) -> FrameResult<Vec<u8>> {
+    let mut frame = encode_header(CHUNK_MAGIC, header, 4 + ciphertext.len())?;
+    frame.extend(index.to_be_bytes());
+    frame.extend(ciphertext);
+
+    Ok(frame)
+}
+
+fn encode_header<C: Serialize>(
+    magic: &[u8; 4],
+    header: &MsgPayload<C>,
+    body_len: usize,
+) -> FrameResult<Vec<u8>> {
+    let header = serde_json::to_vec(header)?;
+
+    let mut frame = Vec::with_capacity(magic.len() + 4 + header.len() + body_len);
+    frame.extend(magic);
+    frame.extend((header.len() as u32).to_be_bytes());
+    frame.extend(header);
+
+    Ok(frame)
+}
+
+/// Splits a frame into its header and the ciphertext.
+pub fn decode<C: DeserializeOwned>(frame: &[u8]) -> FrameResult<Frame<'_, C>> {
+    let (magic, rest) = split_prefix::<4>(frame)?;
+    if &magic != FRAME_MAGIC && &magic != CHUNK_MAGIC {
+        return Err(FrameError::BadMagic);
+    }
+
+    let (len, rest) = split_prefix::<4>(rest)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if rest.len() < len {
+        return Err(FrameError::Truncated);
+    }
+    let (header, rest) = rest.split_at(len);
+    let header = serde_json::from_slice(header)?;
+
+    if &magic == FRAME_MAGIC {
+        return Ok(Frame::Attachment(header, rest));
+    }
+
+    let (index, ciphertext) = split_prefix::<4>(rest)?;
+    Ok(Frame::Chunk(header, u32::from_be_bytes(index), ciphertext))
+}
+
+/// Plaintext of an attachment frame.
+pub fn encode_attachment(mime_type: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + mime_type.len() + data.len());
+    body.extend((mime_type.len() as u16).to_be_bytes());
+    body.extend(mime_type.as_bytes());
+    body.extend(data);
+    body
+}
+
+/// Returns mime type and file bytes of a decrypted attachment.
+pub fn decode_attachment(body: &[u8]) -> FrameResult<(String, &[u8])> {
+    let (len, rest) = split_prefix::<2>(body)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if rest.len() < len {
+        return Err(FrameError::Truncated);
+    }
+    let (mime_type, data) = rest.split_at(len);
+
+    Ok((String::from_utf8_lossy(mime_type).to_string(), data))
+}
+
+fn split_prefix<const N: usize>(data: &[u8]) -> FrameResult<([u8; N], &[u8])> {
+    if data.len() < N {
+        return Err(FrameError::Truncated);
+    }
+    let (prefix, rest) = data.split_at(N);
+
+    Ok((prefix.try_into().unwrap(), rest))
+}
+
+#[test]
+fn check_frame_roundtrip() {
+    let header = MsgPayload::<Value> {
+        content: None,
+        timestamp: 1,
+        auth: None,
+        message_id: "id".to_string(),
+        author: "alice".to_string(),
+        recipient: "bob".to_string(),
+        transient: false,
+    };
+    let ciphertext = vec![0_u8, 1, 2, 255];
+
+    let frame = encode(&header, &ciphertext).unwrap();
+    let Frame::Attachment(decoded, decoded_ciphertext) = decode::<Value>(&frame).unwrap() else {
+        panic!("attachment decoded as chunk");
+    };
+    assert_eq!(decoded.message_id, "id");
+    assert_eq!(decoded.recipient, "bob");
+    assert_eq!(decoded_ciphertext, ciphertext.as_slice());
+
+    assert!(matches!(
+        decode::<Value>(&frame[..10]),
+        Err(FrameError::Truncated)
+    ));
+    assert!(matches!(
+        decode::<Value>(b"CCF0"),
+        Err(FrameError::BadMagic)
+    ));
+
+    let chunk = encode_chunk(&header, 7, &ciphertext).unwrap();
+    let Frame::Chunk(decoded, index, decoded_ciphertext) = decode::<Value>(&chunk).unwrap() else {
+        panic!("chunk decoded as attachment");
+    };
+    assert_eq!(decoded.message_id, "id");
+    assert_eq!(index, 7);
+    assert_eq!(decoded_ciphertext, ciphertext.as_slice());
+
+    let body = encode_attachment("image/png", &[137, 80, 78, 71]);
+    let (mime_type, data) = decode_attachment(&body).unwrap();
+    assert_eq!(mime_type, "image/png");
+    assert_eq!(data, &[137, 80, 78, 71]);
+    assert!(decode_attachment(&body[..5]).is_err());
+}
@@ -0,0 +1,130 @@
+//! Wire format of cipher-chat, shared by the client and the homeserver.
+//!
+//! Both sides read and write the same types, only the message content differs:
+//! the client works with its `MsgContent`, the homeserver keeps contents as raw
+//! JSON because they are end to end encrypted and never looked at. The packets
+//! and their legacy form are in [`packet`], the binary frames of attachments
+//! and file chunks in [`frame`].
+
+pub mod frame;
+pub mod packet;
+pub mod secret;
+
+pub use packet::{
+    decode, encode, Ack, AuthAction, AuthRequest, AuthResponse, BundleRequest, BundleResponse,
+    Delivered, Handshake, OtkCount, Packet, PacketError, PacketFailure, PrekeyUpload, WireFormat,
+    WIRE_VERSION,
+};
+pub use secret::{SecretBytes, SecretString};
+
+/// A message, and before wire versions every other packet, its kind is in `auth.action` then.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MsgPayload<C> {
+    pub content: Option<C>,
+    pub timestamp: u64,
+    pub auth: Option<OpAuthPayload>,
+    pub message_id: String,
+    pub author: String,
+    pub recipient: String,
+    /// Only relayed to a recipient that is online, the homeserver never queues it. For what is
+    /// stale once missed, like typing notifications.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub transient: bool,
+}
+
+impl<C> MsgPayload<C> {
+    /// Metadata every ciphertext is bound to, the server can not re-route or re-label a message.
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [&self.author, &self.recipient, &self.message_id] {
+            data.extend((field.len() as u64).to_be_bytes());
+            data.extend(field.as_bytes());
+        }
+        data.extend(self.timestamp.to_be_bytes());
+        data
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct OpAuthPayload {
+    pub action: String,
+    pub user: String,
+    #[serde(default)]
+    pub password: SecretString,
+    pub keybundle: Option<KeyBundle>,
+    #[serde(default)]
+    pub message: String,
+    pub success: Option<bool>,
+    /// Number of one-time prekeys the server has left for `user`, sent with `otk_count`.
+    pub otk_count: Option<usize>,
+    /// Newest wire version the client reads, announced with a login.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wire_version: Option<u32>,
+}
+
+/// Keys of an account. The private halves and the prekey history stay on the device, `strip`
+/// removes them before a bundle is sent.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct KeyBundle {
+    pub identity: KeyPairB64,
+    pub prekey: KeyPairB64,
+    pub signature: KeyPairB64,
+    pub onetime_keys: Vec<KeyPairB64>,
+    pub ephemeral_key: Option<KeyPairB64>,
+    /// Public signed prekey of the receiver an x3dh init message was derived against.
+    pub receiver_prekey: Option<String>,
+    /// Unix time the current signed prekey was generated, only kept locally.
+    pub prekey_created: Option<u64>,
+    /// Replaced signed prekeys that are still accepted during the grace window.
+    #[serde(default)]
+    pub retired_prekeys: Vec<RetiredPrekey>,
+}
+
+impl KeyBundle {
+    pub fn strip(&mut self) {
+        self.identity.strip();
+        self.prekey.strip();
+        self.signature.strip();
+        for otk in &mut self.onetime_keys {
+            otk.strip();
+        }
+        if let Some(v) = &mut self.ephemeral_key {
+            v.strip();
+        }
+        self.prekey_created = None;
+        self.retired_prekeys.clear();
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RetiredPrekey {
+    pub prekey: KeyPairB64,
+    pub retired_at: u64,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct KeyPairB64 {
+    pub public: String,
+    pub private: Option<SecretString>,
+}
+
+impl KeyPairB64 {
+    /// Public key without private half, as the homeserver hands it out.
+    pub fn public(public: String) -> Self {
+        Self {
+            public,
+            private: None,
+        }
+    }
+
+    pub fn strip(&mut self) {
+        self.private = None;
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,521 @@
+//! Packets exchanged between client and homeserver.
+//!
+//! A packet is a JSON object with its kind in `type` and the wire version in
+//! `v`. Before versioning every packet was a `MsgPayload` with its kind in
+//! `auth.action`, chat messages had no `auth` at all. That legacy format is
+//! still read, and written to a peer until it shows that it reads versions:
+//! a client announces `wire_version` with its login, which homeservers that
+//! predate versioning ignore, and switches once a versioned packet arrives.
+//! Binary frames are the same in both formats.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+#[cfg(test)]
+use crate::KeyPairB64;
+use crate::{unix_time, KeyBundle, MsgPayload, OpAuthPayload, SecretString};
+
+/// Newest wire version client and homeserver read and write.
+pub const WIRE_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    /// `MsgPayload` with the kind in `auth.action`.
+    Legacy,
+    /// Tagged packets of `WIRE_VERSION`.
+    Versioned,
+}
+
+/// Packet errors.
+#[derive(Debug, Error)]
+pub enum PacketError {
+    /// A versioned packet newer than this crate, or with a version that never existed.
+    #[error("unsupported wire version {0}")]
+    UnsupportedVersion(Value),
+
+    /// A legacy packet with an action that does not exist.
+    #[error("unknown action {0}")]
+    UnknownAction(String),
+
+    /// A legacy packet without what its action needs, e.g. a handshake without keybundle.
+    #[error("{0} without {1}")]
+    Incomplete(String, &'static str),
+
+    /// Packet was not valid JSON of its kind.
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Packet<C> {
+    AuthRequest(AuthRequest),
+    AuthResponse(AuthResponse),
+    BundleRequest(BundleRequest),
+    BundleResponse(BundleResponse),
+    /// X3DH init message, relayed to the contact.
+    Handshake(Handshake<C>),
+    /// End to end encrypted message, relayed to the recipient and acknowledged to the author.
+    Chat(MsgPayload<C>),
+    Ack(Ack),
+    Delivered(Delivered),
+    OtkCount(OtkCount),
+    UploadPrekeys(PrekeyUpload),
+    RotatePrekey(PrekeyUpload),
+    /// A packet the homeserver could not handle.
+    Error(PacketFailure),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthAction {
+    Register,
+    Login,
+    Resume,
+    Logout,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub action: AuthAction,
+    pub user: String,
+    #[serde(default)]
+    pub password: SecretString,
+    /// Public bundle of a new account.
+    pub keybundle: Option<KeyBundle>,
+    /// Session token to resume.
+    pub token: Option<SecretString>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub action: AuthAction,
+    pub user: String,
+    /// Session token to resume with after a reconnect, it logs in without the password.
+    pub token: Option<SecretString>,
+    /// Why the request was refused.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleRequest {
+    pub user: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleResponse {
+    /// Owner of the bundle.
+    pub user: String,
+    /// Who asked for it.
+    pub recipient: String,
+    pub keybundle: Option<KeyBundle>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Handshake<C> {
+    pub author: String,
+    pub recipient: String,
+    /// Public keys of the author and the keys of the recipient it used.
+    pub keybundle: KeyBundle,
+    pub content: C,
+}
+
+/// The homeserver took the message `message_id`, or refused it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ack {
+    pub message_id: String,
+    pub author: String,
+    pub recipient: String,
+    pub error: Option<String>,
+}
+
+/// `author` received the message `message_id` of `recipient`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delivered {
+    pub message_id: String,
+    pub author: String,
+    pub recipient: String,
+}
+
+/// One-time prekeys the homeserver has left for `user`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OtkCount {
+    pub user: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrekeyUpload {
+    pub user: String,
+    pub keybundle: KeyBundle,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PacketFailure {
+    pub message: String,
+}
+
+impl AuthAction {
+    fn from_legacy(action: &str) -> Option<Self> {
+        match action {
+            "register" => Some(Self::Register),
+            "login" => Some(Self::Login),
+            "resume" => Some(Self::Resume),
+            "logout" => Some(Self::Logout),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Register => "register",
+            Self::Login => "login",
+            Self::Resume => "resume",
+            Self::Logout => "logout",
+        }
+    }
+}
+
+impl<C> Packet<C> {
+    /// Name of the packet type, what is logged of a packet instead of its content.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Packet::AuthRequest(_) => "auth_request",
+            Packet::AuthResponse(_) => "auth_response",
+            Packet::BundleRequest(_) => "bundle_request",
+            Packet::BundleResponse(_) => "bundle_response",
+            Packet::Handshake(_) => "handshake",
+            Packet::Chat(_) => "chat",
+            Packet::Ack(_) => "ack",
+            Packet::Delivered(_) => "delivered",
+            Packet::OtkCount(_) => "otk_count",
+            Packet::UploadPrekeys(_) => "upload_prekeys",
+            Packet::RotatePrekey(_) => "rotate_prekey",
+            Packet::Error(_) => "error",
+        }
+    }
+
+    /// Id of the message the packet carries or refers to.
+    pub fn message_id(&self) -> Option<&str> {
+        match self {
+            Packet::Chat(msg) => Some(&msg.message_id),
+            Packet::Ack(ack) => Some(&ack.message_id),
+            Packet::Delivered(receipt) => Some(&receipt.message_id),
+            _ => None,
+        }
+    }
+
+    /// Reads a packet of the legacy format, requests and responses of the same action differ in
+    /// whether `success` is set.
+    pub fn from_legacy(msg: MsgPayload<C>) -> Result<Self, PacketError> {
+        let Some(auth) = msg.auth.clone() else {
+            return Ok(Packet::Chat(msg));
+        };
+
+        if let Some(action) = AuthAction::from_legacy(&auth.action) {
+            return Ok(match auth.success {
+                None => Packet::AuthRequest(AuthRequest {
+                    action,
+                    user: auth.user,
+                    password: auth.password,
+                    keybundle: auth.keybundle,
+                    token: (action == AuthAction::Resume).then(|| auth.message.into()),
+                }),
+                Some(success) => Packet::AuthResponse(AuthResponse {
+                    action,
+                    user: auth.user,
+                    token: (success && !auth.message.is_empty())
+                        .then(|| auth.message.clone().into()),
+                    error: (!success).then_some(auth.message),
+                }),
+            });
+        }
+
+        let incomplete = |what| PacketError::Incomplete(auth.action.clone(), what);
+        let packet = match auth.action.as_str() {
+            "fetch_bundle" => match auth.success {
+                None => Packet::BundleRequest(BundleRequest { user: auth.user }),
+                Some(success) => Packet::BundleResponse(BundleResponse {
+                    user: auth.user,
+                    recipient: msg.recipient,
+                    keybundle: auth.keybundle,
+                    error: (!success).then_some(auth.message),
+                }),
+            },
+            "x3dh" => Packet::Handshake(Handshake {
+                author: msg.author,
+                recipient: msg.recipient,
+                keybundle: auth.keybundle.ok_or_else(|| incomplete("keybundle"))?,
+                content: msg.content.ok_or_else(|| incomplete("content"))?,
+            }),
+            "ack" => Packet::Ack(Ack {
+                message_id: msg.message_id,
+                author: msg.recipient,
+                recipient: auth.user,
+                error: (auth.success == Some(false)).then_some(auth.message),
+            }),
+            "delivered" => Packet::Delivered(Delivered {
+                message_id: msg.message_id,
+                author: msg.author,
+                recipient: msg.recipient,
+            }),
+            "otk_count" => Packet::OtkCount(OtkCount {
+                user: auth.user,
+                count: auth.otk_count.unwrap_or(0),
+            }),
+            "upload_prekeys" | "rotate_prekey" => {
+                let upload = PrekeyUpload {
+                    keybundle: auth.keybundle.ok_or_else(|| incomplete("keybundle"))?,
+                    user: auth.user,
+                };
+                match auth.action.as_str() {
+                    "upload_prekeys" => Packet::UploadPrekeys(upload),
+                    _ => Packet::RotatePrekey(upload),
+                }
+            }
+            "error" => Packet::Error(PacketFailure {
+                message: auth.message,
+            }),
+            action => return Err(PacketError::UnknownAction(action.to_string())),
+        };
+
+        Ok(packet)
+    }
+
+    /// The packet in the legacy format, which is also what the frontend gets with events.
+    pub fn into_legacy(self) -> MsgPayload<C> {
+        let mut msg = MsgPayload {
+            content: None,
+            timestamp: unix_time(),
+            auth: None,
+            message_id: "".to_string(),
+            author: "".to_string(),
+            recipient: "".to_string(),
+            transient: false,
+        };
+        let op = |action: &str, user: String| OpAuthPayload {
+            action: action.to_string(),
+            user,
+            ..Default::default()
+        };
+
+        let auth = match self {
+            Packet::Chat(msg) => return msg,
+            Packet::AuthRequest(request) => OpAuthPayload {
+                password: request.password,
+                keybundle: request.keybundle,
+                message: expose(request.token),
+                // a homeserver that speaks versions answers in the versioned format
+                wire_version: Some(WIRE_VERSION),
+                ..op(request.action.as_str(), request.user)
+            },
+            Packet::AuthResponse(response) => {
+                msg.recipient = response.user.clone();
+                OpAuthPayload {
+                    success: Some(response.error.is_none()),
+                    message: response.error.unwrap_or_else(|| expose(response.token)),
+                    ..op(response.action.as_str(), response.user)
+                }
+            }
+            Packet::BundleRequest(request) => {
+                msg.author = "me".to_string();
+                op("fetch_bundle", request.user)
+            }
+            Packet::BundleResponse(response) => {
+                msg.recipient = response.recipient;
+                OpAuthPayload {
+                    keybundle: response.keybundle,
+                    success: Some(response.error.is_none()),
+                    message: response.error.unwrap_or_default(),
+                    ..op("fetch_bundle", response.user)
+                }
+            }
+            Packet::Handshake(handshake) => {
+                msg.content = Some(handshake.content);
+                msg.author = handshake.author;
+                msg.recipient = handshake.recipient;
+                OpAuthPayload {
+                    keybundle: Some(handshake.keybundle),
+                    success: Some(true),
+                    ..op("x3dh", "".to_string())
+                }
+            }
+            Packet::Ack(ack) => {
+                msg.message_id = ack.message_id;
+                msg.recipient = ack.author;
+                OpAuthPayload {
+                    success: Some(ack.error.is_none()),
+                    message: ack.error.unwrap_or_default(),
+                    ..op("ack", ack.recipient)
+                }
+            }
+            Packet::Delivered(receipt) => {
+                msg.message_id = receipt.message_id;
+                msg.author = receipt.author.clone();
+                msg.recipient = receipt.recipient;
+                op("delivered", receipt.author)
+            }
+            Packet::OtkCount(count) => {
+                msg.recipient = count.user.clone();
+                OpAuthPayload {
+                    otk_count: Some(count.count),
+                    ..op("otk_count", count.user)
+                }
+            }
+            Packet::UploadPrekeys(upload) => {
+                msg.author = upload.user.clone();
+                OpAuthPayload {
+                    keybundle: Some(upload.keybundle),
+                    ..op("upload_prekeys", upload.user)
+                }
+            }
+            Packet::RotatePrekey(upload) => {
+                msg.author = upload.user.clone();
+                OpAuthPayload {
+                    keybundle: Some(upload.keybundle),
+                    ..op("rotate_prekey", upload.user)
+                }
+            }
+            Packet::Error(failure) => OpAuthPayload {
+                message: failure.message,
+                ..op("error", "".to_string())
+            },
+        };
+
+        msg.auth = Some(auth);
+        msg
+    }
+}
+
+/// Reads a packet of either format, with the format to answer its sender in. That is the format
+/// the packet came in, except for a legacy login that announces a wire version.
+pub fn decode<C: DeserializeOwned>(text: &str) -> Result<(Packet<C>, WireFormat), PacketError> {
+    let mut value: Value = serde_json::from_str(text)?;
+
+    let Some(version) = value.as_object_mut().and_then(|object| object.remove("v")) else {
+        let msg: MsgPayload<C> = serde_json::from_value(value)?;
+        let format = match msg.auth.as_ref().and_then(|auth| auth.wire_version) {
+            Some(v) if v >= 1 => WireFormat::Versioned,
+            _ => WireFormat::Legacy,
+        };
+        return Ok((Packet::from_legacy(msg)?, format));
+    };
+
+    match version.as_u64() {
+        Some(v) if v >= 1 && v <= WIRE_VERSION as u64 => {
+            Ok((serde_json::from_value(value)?, WireFormat::Versioned))
+        }
+        _ => Err(PacketError::UnsupportedVersion(version)),
+    }
+}
+
+pub fn encode<C: Clone + Serialize>(
+    packet: &Packet<C>,
+    format: WireFormat,
+) -> Result<String, PacketError> {
+    let json = match format {
+        WireFormat::Legacy => serde_json::to_string(&packet.clone().into_legacy())?,
+        WireFormat::Versioned => {
+            let mut value = serde_json::to_value(packet)?;
+            value["v"] = WIRE_VERSION.into();
+            serde_json::to_string(&value)?
+        }
+    };
+
+    Ok(json)
+}
+
+/// The token as the legacy format carries it, in the message text.
+fn expose(token: Option<SecretString>) -> String {
+    token
+        .map(|token| token.expose().to_string())
+        .unwrap_or_default()
+}
+
+#[test]
+fn check_packet_formats() {
+    // what a homeserver without versions sends
+    let legacy = r#"{"content":null,"timestamp":1,"message_id":"m1","author":"","recipient":"alice",
+        "auth":{"action":"ack","user":"bob","password":"","keybundle":null,"message":"full",
+        "success":false,"otk_count":null}}"#;
+    let (packet, format) = decode::<Value>(legacy).unwrap();
+    assert_eq!(format, WireFormat::Legacy);
+    let Packet::Ack(ack) = &packet else {
+        panic!("not an ack: {:?}", packet);
+    };
+    assert_eq!(
+        (ack.author.as_str(), ack.recipient.as_str()),
+        ("alice", "bob")
+    );
+    assert_eq!(ack.error.as_deref(), Some("full"));
+
+    // both formats carry the same packet
+    for format in [WireFormat::Legacy, WireFormat::Versioned] {
+        let (decoded, read_as) = decode::<Value>(&encode(&packet, format).unwrap()).unwrap();
+        assert_eq!(read_as, format);
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(&packet).unwrap()
+        );
+    }
+
+    // a login announces the version and is answered in the versioned format
+    let login = Packet::<Value>::AuthRequest(AuthRequest {
+        action: AuthAction::Login,
+        user: "alice".to_string(),
+        password: SecretString::from("hunter2".to_string()),
+        keybundle: None,
+        token: None,
+    });
+    let legacy_login = login.into_legacy();
+    assert_eq!(
+        legacy_login.auth.as_ref().unwrap().wire_version,
+        Some(WIRE_VERSION)
+    );
+    let (packet, format) = decode::<Value>(&serde_json::to_string(&legacy_login).unwrap()).unwrap();
+    assert!(matches!(packet, Packet::AuthRequest(ref r) if r.password.expose() == "hunter2"));
+    assert_eq!(format, WireFormat::Versioned);
+
+    // the legacy reply tells a token from a refusal
+    let reply = r#"{"content":null,"timestamp":1,"message_id":"","author":"","recipient":"alice",
+        "auth":{"action":"resume","user":"alice","password":"","keybundle":null,
+        "message":"session expired","success":false,"otk_count":null}}"#;
+    let (Packet::AuthResponse(response), _) = decode::<Value>(reply).unwrap() else {
+        panic!("not an auth response");
+    };
+    assert_eq!(response.action, AuthAction::Resume);
+    assert!(response.token.is_none());
+    assert_eq!(response.error.as_deref(), Some("session expired"));
+
+    // a relayed handshake keeps content the homeserver does not know
+    let handshake = Packet::Handshake(Handshake {
+        author: "alice".to_string(),
+        recipient: "bob".to_string(),
+        keybundle: KeyBundle {
+            ephemeral_key: Some(KeyPairB64::public("ek".to_string())),
+            ..Default::default()
+        },
+        content: serde_json::json!({ "ciphertext": "c", "nonce": "n", "future": 1 }),
+    });
+    for format in [WireFormat::Legacy, WireFormat::Versioned] {
+        let json = encode(&handshake, format).unwrap();
+        assert_eq!(json.contains(r#""v":1"#), format == WireFormat::Versioned);
+        let (Packet::Handshake(decoded), _) = decode::<Value>(&json).unwrap() else {
+            panic!("not a handshake: {}", json);
+        };
+        assert_eq!(decoded.content["future"], 1);
+        assert_eq!(decoded.keybundle.ephemeral_key.unwrap().public, "ek");
+    }
+
+    // packets of a newer wire version are refused instead of misread
+    assert!(matches!(
+        decode::<Value>(r#"{"v":2,"type":"ack","message_id":"m1"}"#),
+        Err(PacketError::UnsupportedVersion(_))
+    ));
+    assert!(matches!(
+        decode::<Value>(r#"{"v":0,"type":"otk_count","user":"alice","count":1}"#),
+        Err(PacketError::UnsupportedVersion(_))
+    ));
+}
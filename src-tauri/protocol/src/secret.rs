@@ -0,0 +1,76 @@
+//! Wrappers for secret material.
+//!
+//! Both types are zeroized when dropped and print a placeholder instead of
+//! their content, so a `{:?}` on a bundle or payload never leaks key bytes
+//! into the log.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use base64::{prelude::BASE64_STANDARD, DecodeError, Engine};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Secret text, private keys in base64 or a password.
+#[derive(Clone, Default, Zeroize, ZeroizeOnDrop, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Base64 encodes `bytes` into a secret string.
+    pub fn encode(bytes: &[u8]) -> Self {
+        Self(BASE64_STANDARD.encode(bytes))
+    }
+
+    /// Base64 decodes the secret, the decoded bytes stay wrapped.
+    pub fn decode(&self) -> Result<SecretBytes, DecodeError> {
+        Ok(SecretBytes(BASE64_STANDARD.decode(&self.0)?))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+/// Secret key bytes.
+#[derive(Clone, Default, Zeroize, ZeroizeOnDrop)]
+pub struct SecretBytes(Vec<u8>);
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes([REDACTED])")
+    }
+}
@@ -0,0 +1,473 @@
+//! The Tauri app: the commands the frontend invokes and the `AppHandle` the
+//! engine runs on there. Built with the `gui` feature.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use tauri::{Emitter, Manager, Runtime, WebviewWindow};
+use tauri_plugin_fs::FsExt;
+use tauri_plugin_store::StoreExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    crypt,
+    envelope::Envelope,
+    host::{EventSink, KeyValueStore, Storage},
+    outbox, settings,
+    socket::{Socket, SocketFuncs},
+    transfer,
+    util::{
+        self, AccountSettings, ConnectionHealth, ConnectionInfo, ConnectionState, HeartbeatConfig,
+        MsgPayload, MsgStatus, PrekeyConfig, ReadReceipt, SafetyNumber,
+    },
+    vault,
+    x3dh::{self, get_keybundle, load_session},
+    Error, HEARTBEAT_CONFIG, HOMESERVER, PREKEY_CONFIG,
+};
+
+lazy_static::lazy_static! {
+  static ref SOCKET: Mutex<Option<Box<Socket<tauri::AppHandle>>>> = Mutex::new(None);
+  static ref MAIN_WINDOW: Mutex<Option<WebviewWindow>> = Mutex::new(None);
+}
+
+// Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+
+#[tauri::command]
+async fn encrypt(key: &str, txt: &str) -> Result<String, util::Error> {
+    let encrypted = crypt::encrypt(key.to_string(), txt.to_string())?;
+    Ok(encrypted)
+}
+
+#[tauri::command]
+async fn decrypt(key: &str, txt: &str) -> Result<String, util::Error> {
+    let decrypted = crypt::decrypt(key.to_string(), txt.to_string())?;
+    Ok(decrypted)
+}
+
+#[tauri::command]
+async fn send_msg(msg: MsgPayload, app_handle: tauri::AppHandle) -> Result<(), util::Error> {
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        if load_session(&app_handle, &msg.author, &msg.recipient)
+            .await?
+            .is_some()
+        {
+            info!("found session with recipient");
+
+            socket.send_msg(msg).await?;
+        } else {
+            socket.queue_msg(msg).await?;
+        }
+    } else {
+        // Handle the case when the Option is None
+        error!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+/// Send the file at `path` as a binary frame, once there is a session. Files larger than a
+/// chunk go through a resumable transfer that is announced like a regular message. Only files
+/// the user picked in the file dialog are read.
+#[tauri::command]
+async fn send_file(
+    mut msg: MsgPayload,
+    path: String,
+    mime_type: String,
+    app_handle: tauri::AppHandle,
+) -> Result<(), util::Error> {
+    if !picked_by_user(&app_handle, Path::new(&path)) {
+        return Err(util::Error::CustomError(format!(
+            "{} was not picked in the file dialog",
+            path
+        )));
+    }
+
+    if tokio::fs::metadata(&path).await?.len() > transfer::CHUNK_SIZE as u64 {
+        transfer::start(&app_handle, &mut msg, &path, &mime_type).await?;
+        return send_msg(msg, app_handle).await;
+    }
+
+    let data = tokio::fs::read(&path).await?;
+
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        if load_session(&app_handle, &msg.author, &msg.recipient)
+            .await?
+            .is_some()
+        {
+            socket.send_attachment(msg, mime_type, data).await?;
+        } else {
+            socket.queue_attachment(msg, mime_type, data).await?;
+        }
+    } else {
+        error!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+/// Whether the user picked `path` in the file dialog, the dialog plugin adds every pick to the
+/// fs scope.
+fn picked_by_user(app_handle: &tauri::AppHandle, path: &Path) -> bool {
+    let allowed = app_handle.fs_scope().allowed();
+    allowed.iter().any(|allowed| allowed == path)
+}
+
+#[tauri::command]
+async fn login(auth: MsgPayload, app_handle: tauri::AppHandle) -> Result<(), util::Error> {
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        let credentials = auth.auth.as_ref().ok_or(util::Error::CustomError(
+            "login without credentials".to_string(),
+        ))?;
+        // the socket unlocks the vault once the homeserver accepted the login
+        vault::unlock(
+            &app_handle,
+            &credentials.user,
+            credentials.password.expose(),
+        )
+        .await?;
+
+        socket.login(auth).await?;
+    } else {
+        // Handle the case when the Option is None
+        error!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn logout(auth: MsgPayload) -> Result<(), util::Error> {
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        let user = auth
+            .auth
+            .as_ref()
+            .ok_or(util::Error::CustomError(
+                "logout without credentials".to_string(),
+            ))?
+            .user
+            .clone();
+        socket.logout(auth).await?;
+        vault::lock(&user).await;
+    } else {
+        // Handle the case when the Option is None
+        error!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn register(auth: MsgPayload, app_handle: tauri::AppHandle) -> Result<(), util::Error> {
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        let credentials = auth.auth.as_ref().ok_or(util::Error::CustomError(
+            "register without credentials".to_string(),
+        ))?;
+        // a new vault would drop the keys and sessions of the existing account
+        if x3dh::has_bundle(&app_handle, &credentials.user).await? {
+            return Err(util::Error::CustomError(format!(
+                "{} already has keys here",
+                credentials.user
+            )));
+        }
+
+        // vault and keys are only written once the homeserver accepted the registration
+        vault::stage_new(&credentials.user, credentials.password.expose()).await?;
+        let bundle = get_keybundle(&credentials.user).await?;
+        socket.register(auth.clone(), bundle).await?;
+    } else {
+        // Handle the case when the Option is None
+        info!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_prekey_config(config: PrekeyConfig) -> Result<(), util::Error> {
+    info!("prekey config: {:?}", config);
+    *PREKEY_CONFIG.lock().await = config;
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_heartbeat_config(config: HeartbeatConfig) -> Result<(), util::Error> {
+    info!("heartbeat config: {:?}", config);
+    config.validate()?;
+    *HEARTBEAT_CONFIG.lock().await = config;
+    Ok(())
+}
+
+#[tauri::command]
+async fn connection_health() -> Result<ConnectionHealth, util::Error> {
+    let socket_lock = SOCKET.lock().await;
+    let health = match socket_lock.as_ref() {
+        Some(socket) => socket.health.lock().await.clone(),
+        None => ConnectionHealth::new(ConnectionState::Closed),
+    };
+    Ok(health)
+}
+
+/// Tell `contact` which of their messages `user` has read, unless read receipts are off.
+#[tauri::command]
+async fn send_read_receipt(
+    user: String,
+    contact: String,
+    message_ids: Vec<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), util::Error> {
+    if message_ids.is_empty()
+        || !settings::load_settings(&app_handle, &user)
+            .await?
+            .read_receipts
+    {
+        return Ok(());
+    }
+
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        let receipt = Envelope::Read(ReadReceipt { message_ids });
+        let message_id = format!("read-{}", util::unix_time_millis());
+        socket
+            .send_control(receipt.into_msg(message_id, &user, &contact))
+            .await?;
+    } else {
+        error!("Socket not initialized.");
+    }
+    Ok(())
+}
+
+/// Let `contact` know whether `user` is typing, nothing is stored or queued for this.
+#[tauri::command]
+async fn send_typing(user: String, contact: String, typing: bool) -> Result<(), util::Error> {
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        socket.send_typing(user, contact, typing).await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_account_settings(
+    user: String,
+    app_handle: tauri::AppHandle,
+) -> Result<AccountSettings, util::Error> {
+    settings::load_settings(&app_handle, &user).await
+}
+
+#[tauri::command]
+async fn set_account_settings(
+    user: String,
+    settings: AccountSettings,
+    app_handle: tauri::AppHandle,
+) -> Result<(), util::Error> {
+    info!("settings of {}: {:?}", user, settings);
+    settings::save_settings(&app_handle, &user, &settings).await
+}
+
+/// Delivery state of the messages `user` sent recently.
+#[tauri::command]
+async fn outbox_status(
+    user: String,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<MsgStatus>, util::Error> {
+    outbox::status(&app_handle, &user).await
+}
+
+#[tauri::command]
+async fn get_safety_number(
+    user: String,
+    contact: String,
+    app_handle: tauri::AppHandle,
+) -> Result<SafetyNumber, util::Error> {
+    x3dh::get_safety_number(&app_handle, &user, &contact).await
+}
+
+#[tauri::command]
+async fn set_contact_verified(
+    user: String,
+    contact: String,
+    verified: bool,
+    app_handle: tauri::AppHandle,
+) -> Result<(), util::Error> {
+    x3dh::set_contact_verified(&app_handle, &user, &contact, verified).await
+}
+
+#[tauri::command]
+async fn accept_identity_change(
+    user: String,
+    contact: String,
+    app_handle: tauri::AppHandle,
+) -> Result<(), util::Error> {
+    x3dh::accept_identity_change(&app_handle, &user, &contact).await?;
+
+    // messages held back for the contact go out once the new key is used in a handshake
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        let held = outbox::pending(&app_handle, &user)
+            .await?
+            .iter()
+            .any(|pending| pending.msg.recipient == contact);
+        if held {
+            socket.fetch_bundle(contact).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn send_enc_msg(key: &str, mut msg: MsgPayload) -> Result<(), util::Error> {
+    // msg.content = encrypt(key, &msg.content.unwrap().cleartext.unwrap()).await?;
+    // send_msg(msg).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn connect_via_url(
+    url: String,
+    app_handle: tauri::AppHandle,
+) -> Result<util::ConnectionInfo, util::Error> {
+    init_conn(url.to_string(), app_handle).await?;
+    let mut stream_type = "not defined";
+    let socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_ref() {
+        stream_type = socket.stream_type.as_str();
+    }
+    *HOMESERVER.lock().await = url.to_string();
+    Ok(ConnectionInfo {
+        host: url.to_string(),
+        stream_type: stream_type.to_string(),
+    })
+}
+
+async fn init_conn(url: String, app_handle: tauri::AppHandle) -> Result<(), Error> {
+    info!("initiating Connection");
+    // events emitted before the window is up are lost
+    if MAIN_WINDOW.lock().await.is_none() {
+        info!("error window not available yet");
+        return Ok(());
+    }
+    let mut socket = Socket::new(app_handle, url).await?;
+    socket.recv_msg().await;
+    *SOCKET.lock().await = Some(socket);
+    Ok(())
+}
+
+#[tauri::command]
+async fn close_conn() -> Result<(), Error> {
+    info!("closing...");
+    let mut socket_lock = SOCKET.lock().await;
+    if let Some(socket) = socket_lock.as_mut() {
+        socket.close().await?;
+        let socket_value = socket_lock.take().unwrap();
+        drop(socket_value);
+        *socket_lock = None;
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    pretty_env_logger::init();
+
+    tauri::Builder::default()
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_store::Builder::new().build())
+        .plugin(tauri_plugin_process::init())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_store::Builder::default().build())
+        .setup(|app| {
+            let win = app.get_webview_window("main").unwrap();
+
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            rt.block_on(async {
+                let mut main_window = MAIN_WINDOW.lock().await; // Locking the mutex
+                *main_window = Some(win); // Assigning the new window
+            });
+
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            encrypt,
+            decrypt,
+            send_msg,
+            send_file,
+            send_enc_msg,
+            connect_via_url,
+            close_conn,
+            login,
+            register,
+            logout,
+            set_prekey_config,
+            set_heartbeat_config,
+            connection_health,
+            outbox_status,
+            send_read_receipt,
+            send_typing,
+            get_account_settings,
+            set_account_settings,
+            get_safety_number,
+            set_contact_verified,
+            accept_identity_change
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+impl<R: Runtime> EventSink for tauri::AppHandle<R> {
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> Result<(), Error> {
+        Ok(Emitter::emit(self, event, payload)?)
+    }
+}
+
+impl<R: Runtime> Storage for tauri::AppHandle<R> {
+    type Store = Arc<tauri_plugin_store::Store<R>>;
+
+    fn open_store(&self, path: &str) -> Result<Self::Store, Error> {
+        Ok(self.store_builder(path).build()?)
+    }
+
+    fn data_dir(&self) -> Result<PathBuf, Error> {
+        Ok(self.path().app_data_dir()?)
+    }
+}
+
+impl<R: Runtime> KeyValueStore for Arc<tauri_plugin_store::Store<R>> {
+    fn get(&self, key: &str) -> Option<Value> {
+        (**self).get(key)
+    }
+
+    fn has(&self, key: &str) -> bool {
+        (**self).has(key)
+    }
+
+    fn set(&self, key: &str, value: Value) {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        (**self).delete(key)
+    }
+
+    fn clear(&self) {
+        (**self).clear()
+    }
+
+    fn entries(&self) -> Vec<(String, Value)> {
+        (**self).entries()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        Ok((**self).save()?)
+    }
+}
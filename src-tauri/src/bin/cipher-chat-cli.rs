@@ -0,0 +1,3 @@
+fn main() -> std::process::ExitCode {
+    cipher_chat_lib::cli::run()
+}
@@ -0,0 +1,514 @@
+//! Command line client for scripting and debugging conversations without the webview.
+//!
+//! It runs the same `Socket`, handshake and outbox as the app. Keys, sessions and
+//! messages are kept in a `DirStorage` on the data directory of the app unless
+//! `--data-dir` says otherwise, so an account can be used from both. Incoming
+//! messages are printed as `author: text`, everything else goes to stderr.
+//! Built with the `cli` feature.
+
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
+
+use cipher_chat_protocol::SecretString;
+use clap::{Parser, Subcommand};
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, time::timeout};
+use zeroize::Zeroize;
+
+use crate::{
+    host::{DirStorage, DirStore, EventSink, Storage},
+    socket::{Socket, SocketFuncs},
+    util::{unix_time, MsgContent, MsgPayload, MsgState, MsgStatus, OpAuthPayload},
+    vault,
+    x3dh::{get_keybundle, has_bundle, load_bundle, load_session},
+    Error, HOMESERVER,
+};
+
+/// Identifier of the app in `tauri.conf.json`, its data directory is named after it.
+const APP_IDENTIFIER: &str = "com.cipher-chat.app";
+
+/// Environment variable with the password of the account, it is asked for on the terminal
+/// without one. There is no argument for it, arguments are visible in the process list.
+const PASSWORD_VAR: &str = "CIPHER_CHAT_PASSWORD";
+
+/// Longest wait for an answer of the homeserver.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// After a login, what the homeserver queued is printed until nothing arrived for this long.
+const QUIET_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Parser)]
+#[command(version, about = "Command line client of cipher-chat")]
+struct Cli {
+    /// Websocket url of the homeserver
+    #[arg(
+        long,
+        env = "CIPHER_CHAT_SERVER",
+        default_value = "wss://localhost:9999"
+    )]
+    server: String,
+
+    /// Where keys, sessions and messages are kept, the data directory of the app by default
+    #[arg(long, env = "CIPHER_CHAT_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the homeserver can be reached
+    Connect,
+    /// Create an account with new keys
+    Register(Account),
+    /// Log in and print the messages the homeserver queued
+    Login(Account),
+    /// Send a text message and wait until the homeserver took it
+    Send {
+        #[command(flatten)]
+        account: Account,
+        /// Account to send to
+        to: String,
+        text: String,
+    },
+    /// Print incoming messages until interrupted
+    Listen(Account),
+    /// Print the key bundle of an account as JSON
+    ExportKeys {
+        #[command(flatten)]
+        account: Account,
+        /// Include the private keys
+        #[arg(long)]
+        private: bool,
+    },
+}
+
+#[derive(clap::Args)]
+struct Account {
+    #[arg(long, short)]
+    user: String,
+
+    /// Password of the account, it also unlocks the keys. Read after parsing, see `PASSWORD_VAR`.
+    #[arg(skip)]
+    password: SecretString,
+}
+
+impl Command {
+    fn account_mut(&mut self) -> Option<&mut Account> {
+        match self {
+            Command::Connect => None,
+            Command::Register(account) | Command::Login(account) | Command::Listen(account) => {
+                Some(account)
+            }
+            Command::Send { account, .. } | Command::ExportKeys { account, .. } => Some(account),
+        }
+    }
+}
+
+/// Hands the events of the engine to the running command.
+#[derive(Clone)]
+struct CliHost {
+    events: mpsc::UnboundedSender<(String, Value)>,
+    storage: DirStorage,
+}
+
+impl EventSink for CliHost {
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> Result<(), Error> {
+        // nobody listens anymore once the command is done
+        let _ = self
+            .events
+            .send((event.to_string(), serde_json::to_value(payload)?));
+        Ok(())
+    }
+}
+
+impl Storage for CliHost {
+    type Store = DirStore;
+
+    fn open_store(&self, path: &str) -> Result<DirStore, Error> {
+        self.storage.open_store(path)
+    }
+
+    fn data_dir(&self) -> Result<PathBuf, Error> {
+        self.storage.data_dir()
+    }
+}
+
+impl CliHost {
+    fn new(data_dir: PathBuf) -> (Self, mpsc::UnboundedReceiver<(String, Value)>) {
+        let (events, events_rcvr) = mpsc::unbounded_channel();
+        let host = Self {
+            events,
+            storage: DirStorage::new(data_dir),
+        };
+        (host, events_rcvr)
+    }
+
+    /// Whether `user` has keys in this data directory.
+    /// Check the password of `user`, without creating a vault for an unknown account. The keys
+    /// are unlocked once the homeserver accepted the login, or by `vault::commit` for offline use.
+    async fn unlock(&self, account: &Account) -> Result<(), Error> {
+        if !has_bundle(self, &account.user).await? {
+            return Err(Error::CustomError(format!(
+                "no keys of {} in {}, register first or pass --data-dir",
+                account.user,
+                self.storage.data_dir()?.display()
+            )));
+        }
+
+        vault::unlock(self, &account.user, account.password.expose()).await?;
+        Ok(())
+    }
+}
+
+/// A connection to the homeserver.
+struct Client {
+    host: CliHost,
+    socket: Box<Socket<CliHost>>,
+    events: mpsc::UnboundedReceiver<(String, Value)>,
+}
+
+impl Client {
+    async fn connect(server: &str, data_dir: PathBuf) -> Result<Self, Error> {
+        let (host, events) = CliHost::new(data_dir);
+
+        let mut socket = Socket::new(host.clone(), server.to_string()).await?;
+        socket.recv_msg().await;
+
+        Ok(Self {
+            host,
+            socket,
+            events,
+        })
+    }
+
+    async fn login(server: &str, data_dir: PathBuf, account: &Account) -> Result<Self, Error> {
+        let mut client = Self::connect(server, data_dir).await?;
+        client.host.unlock(account).await?;
+
+        client.socket.login(auth_msg("login", account)).await?;
+        client.authenticated().await?;
+        Ok(client)
+    }
+
+    /// Wait for the answer to a login or registration.
+    async fn authenticated(&mut self) -> Result<(), Error> {
+        match self
+            .next(&["register_token", "auth_failure"], RESPONSE_TIMEOUT)
+            .await
+        {
+            Some((event, _)) if event == "register_token" => Ok(()),
+            Some((_, msg)) => Err(Error::CustomError(format!(
+                "rejected: {}",
+                msg["auth"]["message"].as_str().unwrap_or_default()
+            ))),
+            None => Err(no_answer()),
+        }
+    }
+
+    /// Next of the `wanted` events, `None` if none came within `wait`. Messages that arrive
+    /// meanwhile are printed.
+    async fn next(&mut self, wanted: &[&str], wait: Duration) -> Option<(String, Value)> {
+        let next = async {
+            while let Some((event, payload)) = self.events.recv().await {
+                if event == "msg" {
+                    print_msg(&payload);
+                }
+                if wanted.contains(&event.as_str()) {
+                    return Some((event, payload));
+                }
+            }
+            None
+        };
+
+        timeout(wait, next).await.ok().flatten()
+    }
+
+    /// Print messages until nothing arrived for `QUIET_TIME`.
+    async fn drain(&mut self) {
+        while self.next(&["msg"], QUIET_TIME).await.is_some() {}
+    }
+
+    /// Wait until the homeserver took the message `message_id` to `to`.
+    async fn sent(&mut self, message_id: &str, to: &str) -> Result<MsgState, Error> {
+        loop {
+            let Some((event, payload)) = self
+                .next(&["msg_status", "bundle_rejected"], RESPONSE_TIMEOUT)
+                .await
+            else {
+                return Err(no_answer());
+            };
+
+            if event == "bundle_rejected" {
+                if payload["auth"]["user"] == to {
+                    return Err(Error::CustomError(format!(
+                        "no keys for {}: {}",
+                        to,
+                        payload["auth"]["message"].as_str().unwrap_or_default()
+                    )));
+                }
+                continue;
+            }
+
+            let status: MsgStatus = serde_json::from_value(payload)?;
+            if status.message_id != message_id {
+                continue;
+            }
+            match status.state {
+                MsgState::Acked | MsgState::Delivered => return Ok(status.state),
+                MsgState::Failed => {
+                    return Err(Error::CustomError(status.error.unwrap_or_default()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn close(mut self) -> Result<(), Error> {
+        self.socket.close().await?;
+        Ok(())
+    }
+}
+
+pub fn run() -> ExitCode {
+    pretty_env_logger::init();
+    let cli = Cli::parse();
+
+    let runtime = tokio::runtime::Runtime::new().expect("could not start the runtime");
+    match runtime.block_on(execute(cli)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn execute(cli: Cli) -> Result<(), Error> {
+    let Cli {
+        server,
+        data_dir,
+        mut command,
+    } = cli;
+
+    if let Some(account) = command.account_mut() {
+        account.password = read_password(&account.user)?;
+    }
+
+    // the stores of every homeserver are kept apart, see `get_store_path`
+    *HOMESERVER.lock().await = server.clone();
+    let data_dir = data_dir.or_else(app_data_dir).ok_or(Error::CustomError(
+        "no data directory, pass --data-dir".to_string(),
+    ))?;
+
+    match command {
+        Command::Connect => {
+            let client = Client::connect(&server, data_dir).await?;
+            eprintln!("connected to {} ({})", server, client.socket.stream_type);
+            client.close().await
+        }
+        Command::Register(account) => {
+            let mut client = Client::connect(&server, data_dir).await?;
+            // a new vault would drop the sessions of the existing account
+            if has_bundle(&client.host, &account.user).await? {
+                return Err(Error::CustomError(format!(
+                    "{} already has keys here",
+                    account.user
+                )));
+            }
+
+            // vault and keys are only written once the homeserver accepted the registration
+            vault::stage_new(&account.user, account.password.expose()).await?;
+            let auth = auth_msg("register", &account);
+            let bundle = get_keybundle(&account.user).await?;
+            client.socket.register(auth, bundle).await?;
+            client.authenticated().await?;
+
+            eprintln!("registered {}", account.user);
+            client.close().await
+        }
+        Command::Login(account) => {
+            let mut client = Client::login(&server, data_dir, &account).await?;
+            client.drain().await;
+            client.close().await
+        }
+        Command::Send { account, to, text } => {
+            let mut client = Client::login(&server, data_dir, &account).await?;
+
+            let msg = text_msg(&account.user, &to, &text);
+            let message_id = msg.message_id.clone();
+            // without a session the outbox holds it until the handshake is done
+            match load_session(&client.host, &account.user, &to).await? {
+                Some(_) => client.socket.send_msg(msg).await?,
+                None => client.socket.queue_msg(msg).await?,
+            }
+
+            let state = client.sent(&message_id, &to).await?;
+            eprintln!("{} {:?}", message_id, state);
+            client.close().await
+        }
+        Command::Listen(account) => {
+            let mut client = Client::login(&server, data_dir, &account).await?;
+            eprintln!("listening as {}", account.user);
+
+            // the socket reconnects on its own, this only ends once it gave up
+            client.next(&["connection_closed"], Duration::MAX).await;
+            Err(Error::CustomError("connection closed".to_string()))
+        }
+        Command::ExportKeys { account, private } => {
+            let (host, _) = CliHost::new(data_dir);
+            // nothing is sent, the keys are read on this device only
+            host.unlock(&account).await?;
+            vault::commit(&host, &account.user).await?;
+
+            let mut bundle = load_bundle(&host, &account.user).await?;
+            if !private {
+                bundle.strip();
+            }
+            println!("{}", serde_json::to_string_pretty(&bundle)?);
+            Ok(())
+        }
+    }
+}
+
+/// Data directory of the app, where Tauri puts it on this platform.
+fn app_data_dir() -> Option<PathBuf> {
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".local/share")))
+    };
+
+    base.map(|base| base.join(APP_IDENTIFIER))
+}
+
+/// Password of `user` from `PASSWORD_VAR`, or asked for on the terminal.
+fn read_password(user: &str) -> Result<SecretString, Error> {
+    match std::env::var(PASSWORD_VAR) {
+        Ok(password) => Ok(SecretString::from(password)),
+        Err(_) => prompt_password(&format!("password of {}: ", user)),
+    }
+}
+
+/// Read a line from the terminal with echo turned off.
+#[cfg(unix)]
+fn prompt_password(prompt: &str) -> Result<SecretString, Error> {
+    use std::os::fd::AsRawFd;
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|_| no_terminal())?;
+    let fd = tty.as_raw_fd();
+
+    // SAFETY: `fd` stays open while `tty` lives and `termios` is plain data the calls fill in
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let echo = termios;
+    termios.c_lflag &= !libc::ECHO;
+    termios.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut line = String::new();
+    let read = tty
+        .write_all(prompt.as_bytes())
+        .and_then(|_| tty.flush())
+        .and_then(|_| std::io::BufReader::new(&tty).read_line(&mut line));
+    // the echo comes back even if reading failed
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &echo) };
+    read?;
+
+    let password = SecretString::from(line.trim_end_matches(['\r', '\n']).to_string());
+    line.zeroize();
+    Ok(password)
+}
+
+#[cfg(not(unix))]
+fn prompt_password(_prompt: &str) -> Result<SecretString, Error> {
+    Err(no_terminal())
+}
+
+fn no_terminal() -> Error {
+    Error::CustomError(format!(
+        "no terminal to ask for the password, set {}",
+        PASSWORD_VAR
+    ))
+}
+
+fn no_answer() -> Error {
+    Error::CustomError("no answer of the homeserver".to_string())
+}
+
+fn auth_msg(action: &str, account: &Account) -> MsgPayload {
+    MsgPayload {
+        content: None,
+        timestamp: unix_time(),
+        auth: Some(OpAuthPayload {
+            action: action.to_string(),
+            user: account.user.clone(),
+            password: account.password.clone(),
+            keybundle: None,
+            message: "".to_string(),
+            success: None,
+            otk_count: None,
+            wire_version: None,
+        }),
+        message_id: "".to_string(),
+        author: "".to_string(),
+        recipient: "".to_string(),
+        transient: false,
+    }
+}
+
+/// Text message as the frontend writes it, the cleartext is encrypted on sending.
+fn text_msg(author: &str, recipient: &str, text: &str) -> MsgPayload {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let cleartext = json!({ "data": text, "mime_type": "text/plain" });
+
+    MsgPayload {
+        content: Some(MsgContent {
+            ciphertext: "".to_string(),
+            nonce: "".to_string(),
+            cleartext: Some(cleartext.to_string()),
+            header: None,
+        }),
+        timestamp: unix_time(),
+        auth: None,
+        message_id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+        author: author.to_string(),
+        recipient: recipient.to_string(),
+        transient: false,
+    }
+}
+
+fn print_msg(msg: &Value) {
+    let author = msg["author"].as_str().unwrap_or_default();
+    let cleartext: Value = msg["content"]["cleartext"]
+        .as_str()
+        .and_then(|cleartext| serde_json::from_str(cleartext).ok())
+        .unwrap_or_default();
+
+    match (cleartext["mime_type"].as_str(), cleartext["data"].as_str()) {
+        (Some("text/plain"), Some(text)) => println!("{}: {}", author, text),
+        (Some(mime_type), _) => println!("{}: [{}]", author, mime_type),
+        _ => println!("{}: [unreadable]", author),
+    }
+}
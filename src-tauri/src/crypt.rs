@@ -1,3 +1,4 @@
+#[cfg(any(feature = "gui", test))]
 use aes_gcm::{
     aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, OsRng},
     aes::cipher::typenum, // Or `Aes128Gcm`
@@ -6,13 +7,13 @@ use aes_gcm::{
     Nonce,
 };
 
-use sha256::digest;
-use std::{
-    error::Error as StdError,
-    str::{self},
-};
-
+#[cfg(any(feature = "gui", test))]
 use base64::{engine::general_purpose, Engine as _};
+#[cfg(any(feature = "gui", test))]
+use sha256::digest;
+use std::error::Error as StdError;
+#[cfg(any(feature = "gui", test))]
+use std::str;
 // create the error type that represents all errors possible in our program
 use crate::Error;
 
@@ -54,13 +55,14 @@ fn check_symmetry() {
     assert_eq!(&plaintext, &og_txt);
 }
 
+#[cfg(any(feature = "gui", test))]
 pub fn encrypt(key: String, plaintext: String) -> Result<String, Error> {
     let hash = digest(key.to_string()).into_bytes();
     let crypt_key = Key::<Aes256Gcm>::from_slice(&hash[..32]);
 
     let plaintext_bytes = plaintext.into_bytes();
 
-    let cipher = Aes256Gcm::new(&crypt_key);
+    let cipher = Aes256Gcm::new(crypt_key);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
     let mut ciphertext = match cipher.encrypt(&nonce, plaintext_bytes.as_ref()) {
         Ok(text) => text,
@@ -72,14 +74,15 @@ pub fn encrypt(key: String, plaintext: String) -> Result<String, Error> {
 
     let b64 = general_purpose::STANDARD.encode(ciphertext);
 
-    return Ok(b64);
+    Ok(b64)
 }
 
+#[cfg(any(feature = "gui", test))]
 pub fn decrypt(key: String, ciphertext: String) -> Result<String, Error> {
     let hash = digest(key.to_string()).into_bytes();
     let crypt_key = Key::<Aes256Gcm>::from_slice(&hash[..32]);
 
-    let mut ciphertext_bytes = general_purpose::STANDARD.decode(ciphertext.to_string())?;
+    let mut ciphertext_bytes = general_purpose::STANDARD.decode(&ciphertext)?;
 
     let mut nonce: [u8; 12] = [0; 12];
     nonce.copy_from_slice(&ciphertext_bytes[ciphertext_bytes.len() - 12..]);
@@ -87,7 +90,7 @@ pub fn decrypt(key: String, ciphertext: String) -> Result<String, Error> {
 
     let nonce_generic: Nonce<typenum::U12> = GenericArray::from(nonce);
 
-    let cipher = Aes256Gcm::new(&crypt_key);
+    let cipher = Aes256Gcm::new(crypt_key);
     let plaintext_bytes = match cipher.decrypt(&nonce_generic, ciphertext_bytes.as_ref()) {
         Ok(bytes) => bytes,
         Err(error) => return Err(Error::Aes(AesGcmErrorWrapper(error))),
@@ -95,5 +98,5 @@ pub fn decrypt(key: String, ciphertext: String) -> Result<String, Error> {
 
     let plaintext = str::from_utf8(&plaintext_bytes)?;
 
-    return Ok(plaintext.to_string());
+    Ok(plaintext.to_string())
 }
@@ -0,0 +1,107 @@
+//! Control messages inside the encrypted cleartext.
+//!
+//! The cleartext of a message is `{"data": .., "mime_type": ..}`. Control
+//! messages use mime types of their own and are handled by the client instead
+//! of being shown, the homeserver only ever sees their ciphertext.
+
+use serde_json::{json, Value};
+
+use crate::util::{unix_time, Manifest, MsgContent, MsgPayload, ReadReceipt, TransferAck, Typing};
+
+pub const MANIFEST_MIME_TYPE: &str = "application/x-cipher-chat-manifest";
+pub const TRANSFER_ACK_MIME_TYPE: &str = "application/x-cipher-chat-ack";
+pub const READ_MIME_TYPE: &str = "application/x-cipher-chat-read";
+pub const TYPING_MIME_TYPE: &str = "application/x-cipher-chat-typing";
+
+/// Control message found in a decrypted cleartext.
+pub enum Envelope {
+    Manifest(Manifest),
+    TransferAck(TransferAck),
+    Read(ReadReceipt),
+    Typing(Typing),
+}
+
+impl Envelope {
+    pub fn parse(msg: &MsgPayload) -> Option<Self> {
+        let cleartext = msg.content.as_ref()?.cleartext.as_ref()?;
+        let payload: Value = serde_json::from_str(cleartext).ok()?;
+        let data = payload.get("data")?.clone();
+
+        match payload.get("mime_type")?.as_str()? {
+            MANIFEST_MIME_TYPE => serde_json::from_value(data).ok().map(Self::Manifest),
+            TRANSFER_ACK_MIME_TYPE => serde_json::from_value(data).ok().map(Self::TransferAck),
+            READ_MIME_TYPE => serde_json::from_value(data).ok().map(Self::Read),
+            TYPING_MIME_TYPE => serde_json::from_value(data).ok().map(Self::Typing),
+            _ => None,
+        }
+    }
+
+    fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Manifest(_) => MANIFEST_MIME_TYPE,
+            Self::TransferAck(_) => TRANSFER_ACK_MIME_TYPE,
+            Self::Read(_) => READ_MIME_TYPE,
+            Self::Typing(_) => TYPING_MIME_TYPE,
+        }
+    }
+
+    /// Typing notifications are stale once missed, the homeserver does not queue them.
+    fn transient(&self) -> bool {
+        matches!(self, Self::Typing(_))
+    }
+
+    fn data(&self) -> Value {
+        match self {
+            Self::Manifest(manifest) => json!(manifest),
+            Self::TransferAck(ack) => json!(ack),
+            Self::Read(receipt) => json!(receipt),
+            Self::Typing(typing) => json!(typing),
+        }
+    }
+
+    /// Replaces the content of `msg` with this control message.
+    pub fn write_into(&self, msg: &mut MsgPayload) {
+        let cleartext = json!({ "data": self.data(), "mime_type": self.mime_type() });
+        msg.content = Some(MsgContent {
+            ciphertext: "".to_string(),
+            nonce: "".to_string(),
+            cleartext: Some(cleartext.to_string()),
+            header: None,
+        });
+    }
+
+    /// New message from `user` to `contact` carrying this control message.
+    pub fn into_msg(self, message_id: String, user: &str, contact: &str) -> MsgPayload {
+        let mut msg = MsgPayload {
+            content: None,
+            timestamp: unix_time(),
+            auth: None,
+            message_id,
+            author: user.to_string(),
+            recipient: contact.to_string(),
+            transient: self.transient(),
+        };
+        self.write_into(&mut msg);
+        msg
+    }
+}
+
+#[test]
+fn check_envelope_roundtrip() {
+    let receipt = ReadReceipt {
+        message_ids: vec!["a".to_string(), "b".to_string()],
+    };
+    let msg = Envelope::Read(receipt).into_msg("read-1".to_string(), "alice", "bob");
+    assert_eq!(msg.recipient, "bob");
+
+    let Some(Envelope::Read(parsed)) = Envelope::parse(&msg) else {
+        panic!("read receipt not recognized");
+    };
+    assert_eq!(parsed.message_ids, vec!["a", "b"]);
+
+    // regular messages are no control messages
+    let mut text = msg.clone();
+    text.content.as_mut().unwrap().cleartext =
+        Some(json!({ "data": "hi", "mime_type": "text/plain" }).to_string());
+    assert!(Envelope::parse(&text).is_none());
+}
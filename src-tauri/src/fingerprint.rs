@@ -0,0 +1,96 @@
+//! Safety numbers.
+//!
+//! Both parties derive a fingerprint from their identity key and username by
+//! iterating SHA-512, the two fingerprints are rendered as 30 digits each and
+//! concatenated in a fixed order, so both sides see the same 60 digit number.
+//! The QR payload carries the same fingerprints for scanning instead of reading.
+//! See https://signal.org/blog/safety-number-updates/
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cryptimitives::hash::sha512::Hash;
+use cryptraits::hash::Hash as _;
+
+use crate::util::SafetyNumber;
+
+/// Bumped whenever the derivation below changes, old numbers won't match anymore.
+pub const FINGERPRINT_VERSION: u16 = 0;
+
+const ITERATIONS: usize = 5200;
+
+/// Length of the fingerprint part that is rendered and encoded, 6 chunks of 5 bytes.
+const FINGERPRINT_LEN: usize = 30;
+
+/// Stable fingerprint of `user`s `identity` public key.
+pub fn fingerprint(identity: &[u8], user: &str) -> Vec<u8> {
+    let mut hash = Hash::new();
+    hash.update(&FINGERPRINT_VERSION.to_be_bytes());
+    hash.update(identity);
+    hash.update(user.as_bytes());
+    let mut digest = hash.finalize();
+
+    for _ in 0..ITERATIONS {
+        let mut hash = Hash::new();
+        hash.update(&digest);
+        hash.update(identity);
+        digest = hash.finalize();
+    }
+
+    digest.truncate(FINGERPRINT_LEN);
+    digest
+}
+
+/// Renders every 5 byte chunk of a fingerprint as a 5 digit group.
+fn digits(fingerprint: &[u8]) -> String {
+    fingerprint
+        .chunks(5)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0_u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", n % 100_000)
+        })
+        .collect()
+}
+
+/// Safety number for the conversation between two identities, independent of who computes it.
+pub fn safety_number(
+    local_identity: &[u8],
+    local_user: &str,
+    remote_identity: &[u8],
+    remote_user: &str,
+) -> SafetyNumber {
+    let local = fingerprint(local_identity, local_user);
+    let remote = fingerprint(remote_identity, remote_user);
+
+    let (mut first, mut second) = (local, remote);
+    if digits(&first) > digits(&second) {
+        std::mem::swap(&mut first, &mut second);
+    }
+
+    let mut payload = FINGERPRINT_VERSION.to_be_bytes().to_vec();
+    payload.extend(&first);
+    payload.extend(&second);
+
+    SafetyNumber {
+        digits: digits(&first) + &digits(&second),
+        qr_payload: BASE64_STANDARD.encode(payload),
+        verified: false,
+    }
+}
+
+#[test]
+fn check_safety_number_symmetry() {
+    let alice = [1_u8; 32];
+    let bob = [2_u8; 32];
+
+    let from_alice = safety_number(&alice, "alice", &bob, "bob");
+    let from_bob = safety_number(&bob, "bob", &alice, "alice");
+
+    assert_eq!(from_alice.digits.len(), 60);
+    assert!(from_alice.digits.chars().all(|c| c.is_ascii_digit()));
+    assert_eq!(from_alice.digits, from_bob.digits);
+    assert_eq!(from_alice.qr_payload, from_bob.qr_payload);
+
+    // a new identity key for bob gives a different number
+    let changed = safety_number(&alice, "alice", &[3_u8; 32], "bob");
+    assert_ne!(from_alice.digits, changed.digits);
+    assert_ne!(from_alice.qr_payload, changed.qr_payload);
+}
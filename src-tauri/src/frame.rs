@@ -0,0 +1,10 @@
+//! Binary websocket frames for attachments.
+//!
+//! The framing lives in the `cipher-chat-protocol` crate it shares with the
+//! homeserver, here the headers carry the client's `MsgContent`.
+
+pub use cipher_chat_protocol::frame::*;
+
+use crate::util::MsgContent;
+
+pub type Frame<'a> = cipher_chat_protocol::frame::Frame<'a, MsgContent>;
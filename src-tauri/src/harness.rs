@@ -0,0 +1,895 @@
+//! End to end tests of the client against the reference homeserver.
+//!
+//! The homeserver runs in-process on an ephemeral port and every client is a
+//! real `Socket` on a host that keeps its stores in memory, so the handshake,
+//! the outbox and the dispatch in `recv_msg` run exactly as they do in the app.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex, OnceLock},
+    time::Duration,
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cipher_chat_homeserver::{serve, Server, Store};
+use cipher_chat_protocol::SecretString;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::{
+    host::{EventSink, KeyValueStore, Storage},
+    outbox,
+    protocol::{self, BundleResponse, OtkCount, Packet, PrekeyUpload, WireFormat, WIRE_VERSION},
+    socket::{Socket, SocketFuncs},
+    transfer,
+    util::{
+        get_store_path, unix_time, HeartbeatConfig, MsgContent, MsgPayload, MsgState, OpAuthPayload,
+    },
+    vault,
+    x3dh::{
+        accept_identity_change, alice_x3dh, bob_x3dh, get_keybundle, has_bundle, load_bundle,
+        load_identity, load_session,
+    },
+    Error, HEARTBEAT_CONFIG, HOMESERVER, PREKEY_CONFIG,
+};
+
+const PASSWORD: &str = "correct horse battery staple";
+
+/// Longest wait for a single event, a whole exchange takes milliseconds.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The homeserver all tests share and its url. It runs on a thread of its own because every
+/// test has its own runtime, the tests keep apart by using different accounts.
+fn homeserver() -> &'static (String, Arc<Server>) {
+    static SERVER: OnceLock<(String, Arc<Server>)> = OnceLock::new();
+
+    SERVER.get_or_init(|| {
+        let server = Server::new(Store::in_memory().unwrap());
+        let (url_tx, url_rx) = std::sync::mpsc::channel();
+
+        let handle = server.clone();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let url = format!("ws://{}", listener.local_addr().unwrap());
+                url_tx.send(url).unwrap();
+
+                serve(listener, handle, None).await.unwrap();
+            });
+        });
+
+        (url_rx.recv().unwrap(), server)
+    })
+}
+
+fn homeserver_url() -> String {
+    homeserver().0.clone()
+}
+
+/// Every line the client and the homeserver logged in this process since `capture_logs`, a
+/// process only has one logger. Dependencies are left out, tungstenite traces whole frames.
+static LOGGED: StdMutex<Vec<String>> = StdMutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("cipher_chat")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Capture the log of all tests from now on. Tests that look for secrets in the log call it
+/// first, a test that installs a logger of its own would leave them looking at nothing.
+pub(crate) fn capture_logs() {
+    static INSTALLED: OnceLock<()> = OnceLock::new();
+
+    INSTALLED.get_or_init(|| {
+        log::set_logger(&CaptureLogger).expect("another logger is installed");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Logged lines that contain `needle`.
+pub(crate) fn logged_with(needle: &str) -> Vec<String> {
+    let logged = LOGGED.lock().unwrap();
+    logged
+        .iter()
+        .filter(|line| line.contains(needle))
+        .cloned()
+        .collect()
+}
+
+/// A device, it hands every event to its test and keeps the stores in memory.
+#[derive(Clone)]
+pub(crate) struct TestHost {
+    events: mpsc::UnboundedSender<(String, Value)>,
+    stores: Arc<StdMutex<HashMap<String, MemoryStore>>>,
+}
+
+impl TestHost {
+    /// A new device and the receiving end of its events.
+    pub(crate) fn new() -> (Self, mpsc::UnboundedReceiver<(String, Value)>) {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let host = Self {
+            events: events_tx,
+            stores: Arc::new(StdMutex::new(HashMap::new())),
+        };
+
+        (host, events)
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct MemoryStore(Arc<StdMutex<HashMap<String, Value>>>);
+
+impl EventSink for TestHost {
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> Result<(), Error> {
+        let _ = self
+            .events
+            .send((event.to_string(), serde_json::to_value(payload)?));
+        Ok(())
+    }
+}
+
+impl Storage for TestHost {
+    type Store = MemoryStore;
+
+    fn open_store(&self, path: &str) -> Result<MemoryStore, Error> {
+        let mut stores = self.stores.lock().unwrap();
+        Ok(stores.entry(path.to_string()).or_default().clone())
+    }
+
+    fn data_dir(&self) -> Result<PathBuf, Error> {
+        Ok(std::env::temp_dir().join("cipher-chat-harness"))
+    }
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.0.lock().unwrap().contains_key(key)
+    }
+
+    fn set(&self, key: &str, value: Value) {
+        self.0.lock().unwrap().insert(key.to_string(), value);
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.0.lock().unwrap().remove(key).is_some()
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear()
+    }
+
+    fn entries(&self) -> Vec<(String, Value)> {
+        let map = self.0.lock().unwrap();
+        map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// One account on a device of its own.
+struct Client {
+    user: String,
+    host: TestHost,
+    socket: Box<Socket<TestHost>>,
+    events: mpsc::UnboundedReceiver<(String, Value)>,
+}
+
+impl Client {
+    /// Register `user` on a new device.
+    async fn register(user: &str) -> Self {
+        Self::register_via(user, homeserver_url()).await
+    }
+
+    /// Register `user` on a new device that reaches the homeserver at `url`.
+    async fn register_via(user: &str, url: String) -> Self {
+        let mut client = Self::new_device(user, url).await;
+        client.send_registration(user).await;
+        client.expect("register_token").await;
+
+        client
+    }
+
+    /// `user` on a new device that did not authenticate yet.
+    async fn new_device(user: &str, url: String) -> Self {
+        *HOMESERVER.lock().await = homeserver_url();
+
+        let (host, events) = TestHost::new();
+        let mut socket = Socket::new(host.clone(), url).await.unwrap();
+        socket.recv_msg().await;
+
+        Self {
+            user: user.to_string(),
+            host,
+            socket,
+            events,
+        }
+    }
+
+    /// Ask the homeserver to register `user`, as the app does.
+    async fn send_registration(&mut self, user: &str) {
+        vault::stage_new(user, PASSWORD).await.unwrap();
+        let bundle = get_keybundle(user).await.unwrap();
+        let auth = auth_msg("register", user);
+        self.socket.register(auth, bundle).await.unwrap();
+    }
+
+    /// Close the connection and wait until the homeserver noticed, so nothing is sent to it
+    /// anymore.
+    async fn go_offline(&mut self) {
+        self.socket.close().await.unwrap();
+
+        let offline = async {
+            while homeserver().1.is_online(&self.user).await {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if timeout(EVENT_TIMEOUT, offline).await.is_err() {
+            panic!("{} stays online", self.user);
+        }
+    }
+
+    /// Log in again on a new connection, returns the session token the homeserver handed out.
+    async fn relogin(&mut self) -> String {
+        self.socket = open_socket(&self.host).await;
+
+        vault::unlock(&self.host, &self.user, PASSWORD)
+            .await
+            .unwrap();
+        self.socket
+            .login(auth_msg("login", &self.user))
+            .await
+            .unwrap();
+        let reply = self.expect("register_token").await;
+
+        reply["auth"]["message"].as_str().unwrap().to_string()
+    }
+
+    /// Next `event` of this client, other events on the way are skipped.
+    async fn expect(&mut self, event: &str) -> Value {
+        let next = async {
+            loop {
+                let (name, payload) = self.events.recv().await.unwrap();
+                if name == event {
+                    return payload;
+                }
+            }
+        };
+
+        match timeout(EVENT_TIMEOUT, next).await {
+            Ok(payload) => payload,
+            Err(_) => panic!("{} got no {} event", self.user, event),
+        }
+    }
+
+    /// Wait until the message `message_id` is in `state`.
+    async fn expect_state(&mut self, message_id: &str, state: &str) {
+        loop {
+            let status = self.expect("msg_status").await;
+            if status["message_id"] == message_id && status["state"] == state {
+                return;
+            }
+        }
+    }
+
+    /// Text of the next message this client receives.
+    async fn expect_text(&mut self) -> String {
+        let msg = self.expect("msg").await;
+        let cleartext: Value =
+            serde_json::from_str(msg["content"]["cleartext"].as_str().unwrap()).unwrap();
+
+        cleartext["data"].as_str().unwrap().to_string()
+    }
+}
+
+/// Sits between one client and the homeserver, bundle responses are held until the test
+/// releases them. Packets sent to `release` reach the client as if the homeserver sent them.
+struct BundleProxy {
+    url: String,
+    held: mpsc::UnboundedReceiver<(String, Message)>,
+    release: mpsc::UnboundedSender<Message>,
+    /// Contacts whose bundle the client requested, in order.
+    requests: Arc<StdMutex<Vec<String>>>,
+    /// One-time keys the client uploaded.
+    uploads: mpsc::UnboundedReceiver<PrekeyUpload>,
+}
+
+impl BundleProxy {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (held_tx, held) = mpsc::unbounded_channel();
+        let (release, mut release_rx) = mpsc::unbounded_channel::<Message>();
+        let requests = Arc::new(StdMutex::new(Vec::new()));
+        let (uploads_tx, uploads) = mpsc::unbounded_channel();
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let client = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let (server, _) = connect_async(homeserver_url()).await.unwrap();
+            let (mut client_tx, mut client_rx) = client.split();
+            let (mut server_tx, mut server_rx) = server.split();
+
+            tokio::spawn(async move {
+                while let Some(Ok(msg)) = client_rx.next().await {
+                    if let Message::Text(txt) = &msg {
+                        match protocol::decode(txt) {
+                            Ok((Packet::BundleRequest(request), _)) => {
+                                seen.lock().unwrap().push(request.user)
+                            }
+                            Ok((Packet::UploadPrekeys(upload), _)) => {
+                                uploads_tx.send(upload).unwrap()
+                            }
+                            _ => {}
+                        }
+                    }
+                    if server_tx.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            });
+
+            loop {
+                let msg = tokio::select! {
+                    Some(Ok(msg)) = server_rx.next() => msg,
+                    Some(msg) = release_rx.recv() => {
+                        client_tx.send(msg).await.unwrap();
+                        continue;
+                    }
+                    else => return,
+                };
+                if let Message::Text(txt) = &msg {
+                    if let Ok((Packet::BundleResponse(response), _)) = protocol::decode(txt) {
+                        held_tx.send((response.user, msg)).unwrap();
+                        continue;
+                    }
+                }
+                if client_tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            url,
+            held,
+            release,
+            requests,
+            uploads,
+        }
+    }
+
+    /// Hand `packet` to the client as if the homeserver sent it.
+    fn inject(&self, packet: &Packet) {
+        let txt = protocol::encode(packet, WireFormat::Versioned).unwrap();
+        self.release.send(Message::text(txt)).unwrap();
+    }
+
+    /// Next bundle response the homeserver sent and whose bundle it carries.
+    async fn next_held(&mut self) -> (String, Message) {
+        match timeout(EVENT_TIMEOUT, self.held.recv()).await {
+            Ok(held) => held.unwrap(),
+            Err(_) => panic!("no bundle response"),
+        }
+    }
+}
+
+async fn open_socket(host: &TestHost) -> Box<Socket<TestHost>> {
+    let mut socket = Socket::new(host.clone(), homeserver_url()).await.unwrap();
+    socket.recv_msg().await;
+    socket
+}
+
+fn auth_msg(action: &str, user: &str) -> MsgPayload {
+    MsgPayload {
+        content: None,
+        timestamp: unix_time(),
+        auth: Some(OpAuthPayload {
+            action: action.to_string(),
+            user: user.to_string(),
+            password: SecretString::from(PASSWORD.to_string()),
+            keybundle: None,
+            message: "".to_string(),
+            success: None,
+            otk_count: None,
+            wire_version: None,
+        }),
+        message_id: "".to_string(),
+        author: "".to_string(),
+        recipient: "".to_string(),
+        transient: false,
+    }
+}
+
+fn text_msg(message_id: &str, author: &str, recipient: &str, text: &str) -> MsgPayload {
+    let cleartext = json!({ "data": text, "mime_type": "text/plain" });
+
+    MsgPayload {
+        content: Some(MsgContent {
+            ciphertext: "".to_string(),
+            nonce: "".to_string(),
+            cleartext: Some(cleartext.to_string()),
+            header: None,
+        }),
+        timestamp: unix_time(),
+        auth: None,
+        message_id: message_id.to_string(),
+        author: author.to_string(),
+        recipient: recipient.to_string(),
+        transient: false,
+    }
+}
+
+#[tokio::test]
+async fn check_first_message_handshake() {
+    let mut alice = Client::register("alice").await;
+    let mut bob = Client::register("bob").await;
+
+    // without a session the message waits for the bundle, the handshake and the flush
+    let msg = text_msg("alice-1", "alice", "bob", "hi bob");
+    alice.socket.queue_msg(msg).await.unwrap();
+
+    assert_eq!(bob.expect_text().await, "hi bob");
+    alice.expect_state("alice-1", "delivered").await;
+
+    // bob answers on the session his side of the handshake set up
+    let msg = text_msg("bob-1", "bob", "alice", "hi alice");
+    bob.socket.send_msg(msg).await.unwrap();
+
+    assert_eq!(alice.expect_text().await, "hi alice");
+    bob.expect_state("bob-1", "delivered").await;
+}
+
+#[tokio::test]
+async fn check_queued_attachment() {
+    let mut olivia = Client::register("olivia").await;
+    let mut paul = Client::register("paul").await;
+
+    // the bytes wait in the outbox and go out as a frame once the session exists
+    let msg = text_msg("olivia-1", "olivia", "paul", "");
+    let data = vec![0, 159, 255, 1];
+    olivia
+        .socket
+        .queue_attachment(msg, "image/png".to_string(), data.clone())
+        .await
+        .unwrap();
+
+    let msg = paul.expect("msg").await;
+    let cleartext: Value =
+        serde_json::from_str(msg["content"]["cleartext"].as_str().unwrap()).unwrap();
+    assert_eq!(cleartext["mime_type"], "image/png");
+    assert_eq!(cleartext["data"], BASE64_STANDARD.encode(&data));
+
+    // with the session in place it goes out right away and is tracked like a message
+    let msg = text_msg("olivia-2", "olivia", "paul", "");
+    olivia
+        .socket
+        .send_attachment(msg, "image/png".to_string(), data.clone())
+        .await
+        .unwrap();
+    olivia.expect_state("olivia-2", "acked").await;
+    let msg = paul.expect("msg").await;
+    assert_eq!(msg["message_id"], "olivia-2");
+}
+
+#[tokio::test]
+async fn check_bundles_out_of_order() {
+    let mut proxy = BundleProxy::start().await;
+    let mut quinn = Client::register_via("quinn", proxy.url.clone()).await;
+    let mut rita = Client::register("rita").await;
+    let mut sam = Client::register("sam").await;
+
+    // the second message to rita finds her bundle already requested
+    for (id, recipient, text) in [
+        ("quinn-1", "rita", "hi rita"),
+        ("quinn-2", "sam", "hi sam"),
+        ("quinn-3", "rita", "still there?"),
+    ] {
+        let msg = text_msg(id, "quinn", recipient, text);
+        quinn.socket.queue_msg(msg).await.unwrap();
+    }
+    let (first, rita_bundle) = proxy.next_held().await;
+    let (second, sam_bundle) = proxy.next_held().await;
+    assert_eq!((first.as_str(), second.as_str()), ("rita", "sam"));
+
+    quinn.expect_state("quinn-3", "awaiting_bundle").await;
+
+    // sam's bundle arrives first, only the message to him goes out
+    proxy.release.send(sam_bundle).unwrap();
+    assert_eq!(sam.expect_text().await, "hi sam");
+    loop {
+        let status = quinn.expect("msg_status").await;
+        assert_eq!(status["recipient"], "sam", "rita was flushed with sam");
+        if status["message_id"] == "quinn-2" && status["state"] == "sent" {
+            break;
+        }
+    }
+    let waiting: Vec<_> = outbox::status(&quinn.host, "quinn")
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|status| status.state == MsgState::AwaitingBundle)
+        .map(|status| status.message_id)
+        .collect();
+    assert_eq!(waiting, ["quinn-1", "quinn-3"]);
+
+    proxy.release.send(rita_bundle).unwrap();
+    assert_eq!(rita.expect_text().await, "hi rita");
+    assert_eq!(rita.expect_text().await, "still there?");
+    assert_eq!(*proxy.requests.lock().unwrap(), ["rita", "sam"]);
+}
+
+#[tokio::test]
+async fn check_onetime_keys_replenished() {
+    let mut proxy = BundleProxy::start().await;
+    let tina = Client::register_via("tina", proxy.url.clone()).await;
+    let config = PREKEY_CONFIG.lock().await.clone();
+    let before = load_bundle(&tina.host, "tina").await.unwrap().onetime_keys;
+    let otk_count = |count| {
+        Packet::OtkCount(OtkCount {
+            user: "tina".to_string(),
+            count,
+        })
+    };
+
+    // at the threshold the homeserver has enough, below it one batch goes up
+    proxy.inject(&otk_count(config.otk_threshold));
+    proxy.inject(&otk_count(config.otk_threshold - 1));
+
+    let upload = match timeout(EVENT_TIMEOUT, proxy.uploads.recv()).await {
+        Ok(upload) => upload.unwrap(),
+        Err(_) => panic!("tina uploaded no one-time keys"),
+    };
+    assert_eq!(upload.user, "tina");
+    let uploaded = upload.keybundle.onetime_keys;
+    assert_eq!(uploaded.len(), config.otk_batch_size);
+
+    // the upload holds the public halves of the new keys only
+    let after = load_bundle(&tina.host, "tina").await.unwrap().onetime_keys;
+    assert_eq!(after.len(), before.len() + config.otk_batch_size);
+    for (key, stored) in uploaded.iter().zip(&after[before.len()..]) {
+        assert_eq!(key.public, stored.public);
+        assert!(key.private.is_none());
+    }
+    assert!(upload.keybundle.identity.private.is_none());
+    assert!(upload.keybundle.prekey.private.is_none());
+
+    let more = timeout(Duration::from_millis(500), proxy.uploads.recv()).await;
+    assert!(more.is_err(), "uploaded at the threshold");
+}
+
+#[tokio::test]
+async fn check_rejected_registration() {
+    let _uma = Client::register("uma").await;
+
+    // the name is taken, the other device keeps neither keys nor a vault of it
+    let mut other = Client::new_device("uma", homeserver_url()).await;
+    other.send_registration("uma").await;
+    other.expect("auth_failure").await;
+    assert!(!has_bundle(&other.host, "uma").await.unwrap());
+    let vault_store = get_store_path("uma/vault.bin").await;
+    assert!(!other.host.open_store(&vault_store).unwrap().has("salt"));
+
+    // so it can register under a free name right away
+    other.user = "uma-2".to_string();
+    other.send_registration("uma-2").await;
+    other.expect("register_token").await;
+    assert!(has_bundle(&other.host, "uma-2").await.unwrap());
+    assert!(load_bundle(&other.host, "uma-2").await.is_ok());
+}
+
+#[tokio::test]
+async fn check_offline_delivery() {
+    let mut carol = Client::register("carol").await;
+    let mut dave = Client::register("dave").await;
+
+    let msg = text_msg("carol-1", "carol", "dave", "hello");
+    carol.socket.queue_msg(msg).await.unwrap();
+    assert_eq!(dave.expect_text().await, "hello");
+
+    // the homeserver queues what arrives while dave is away
+    dave.go_offline().await;
+    let mut msg = text_msg("carol-typing", "carol", "dave", "typing");
+    msg.transient = true;
+    carol.socket.send_msg(msg).await.unwrap();
+    carol.expect_state("carol-typing", "acked").await;
+    let msg = text_msg("carol-2", "carol", "dave", "while you were away");
+    carol.socket.send_msg(msg).await.unwrap();
+    carol.expect_state("carol-2", "acked").await;
+
+    // but not a transient message, it would be stale by now
+    dave.relogin().await;
+    assert_eq!(dave.expect_text().await, "while you were away");
+    carol.expect_state("carol-2", "delivered").await;
+}
+
+#[tokio::test]
+async fn check_legacy_client() {
+    // a client that predates wire versions is answered in its own format, one that announces
+    // the version with its login in the versioned format
+    for wire_version in [None, Some(WIRE_VERSION)] {
+        let (mut ws, _) = connect_async(homeserver_url()).await.unwrap();
+
+        let mut login = auth_msg("login", "nobody");
+        login.auth.as_mut().unwrap().wire_version = wire_version;
+        let json = serde_json::to_string(&login).unwrap();
+        ws.send(Message::text(json)).await.unwrap();
+
+        let reply = match timeout(EVENT_TIMEOUT, ws.next()).await {
+            Ok(Some(Ok(Message::Text(txt)))) => serde_json::from_str::<Value>(&txt).unwrap(),
+            other => panic!("no reply to the login: {:?}", other),
+        };
+        match wire_version {
+            None => {
+                assert_eq!(reply["auth"]["success"], false);
+                assert_eq!(reply["auth"]["message"], "wrong username or password");
+            }
+            Some(version) => {
+                assert_eq!(reply["v"], version);
+                assert_eq!(reply["type"], "auth_response");
+                assert_eq!(reply["error"], "wrong username or password");
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn check_manifest_not_logged() {
+    capture_logs();
+    let mut erin = Client::register("erin").await;
+    let mut frank = Client::register("frank").await;
+
+    let msg = text_msg("erin-1", "erin", "frank", "a file is coming");
+    erin.socket.queue_msg(msg).await.unwrap();
+    assert_eq!(frank.expect_text().await, "a file is coming");
+    frank.go_offline().await;
+
+    // a file larger than a chunk is announced with a manifest that holds its key
+    let path = std::env::temp_dir().join("cipher-chat-harness-manifest.bin");
+    tokio::fs::write(&path, vec![7u8; transfer::CHUNK_SIZE + 1])
+        .await
+        .unwrap();
+    let mut msg = text_msg("erin-2", "erin", "frank", "");
+    transfer::start(
+        &erin.host,
+        &mut msg,
+        path.to_str().unwrap(),
+        "application/octet-stream",
+    )
+    .await
+    .unwrap();
+    let cleartext: Value =
+        serde_json::from_str(msg.content.as_ref().unwrap().cleartext.as_ref().unwrap()).unwrap();
+    let key = cleartext["data"]["key"].as_str().unwrap().to_string();
+
+    erin.socket.send_msg(msg).await.unwrap();
+    erin.expect_state("erin-2", "acked").await;
+
+    assert!(!logged_with("erin-2").is_empty());
+    assert_eq!(logged_with(&key), Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn check_secrets_not_logged() {
+    capture_logs();
+    // registering generates the keys, the first message runs both sides of the handshake and
+    // the answer goes out through `send_msg` on the new session
+    let mut gina = Client::register("gina").await;
+    let mut hank = Client::register("hank").await;
+
+    let msg = text_msg("gina-1", "gina", "hank", "the eagle lands at noon");
+    gina.socket.queue_msg(msg).await.unwrap();
+    assert_eq!(hank.expect_text().await, "the eagle lands at noon");
+
+    let msg = text_msg("hank-1", "hank", "gina", "the eagle has landed");
+    hank.socket.send_msg(msg).await.unwrap();
+    assert_eq!(gina.expect_text().await, "the eagle has landed");
+
+    // the session token resumes a login without the password
+    gina.go_offline().await;
+    let token = gina.relogin().await;
+    assert!(!token.is_empty());
+
+    let mut secrets = vec![
+        PASSWORD.to_string(),
+        token,
+        "the eagle lands at noon".to_string(),
+        "the eagle has landed".to_string(),
+    ];
+    for (client, contact) in [(&gina, "hank"), (&hank, "gina")] {
+        let bundle = load_bundle(&client.host, &client.user).await.unwrap();
+        for key in [&bundle.identity, &bundle.prekey]
+            .into_iter()
+            .chain(bundle.onetime_keys.iter())
+        {
+            secrets.push(key.private.as_ref().unwrap().expose().to_string());
+        }
+
+        let session = load_session(&client.host, &client.user, contact)
+            .await
+            .unwrap()
+            .unwrap();
+        let session = serde_json::to_value(session).unwrap();
+        for key in [
+            &session["rk"],
+            &session["cks"],
+            &session["ckr"],
+            &session["dhs"]["private"],
+        ] {
+            secrets.extend(key.as_str().map(str::to_string));
+        }
+    }
+
+    assert!(!logged_with("gina-1").is_empty());
+    for secret in &secrets {
+        assert_eq!(logged_with(secret), Vec::<String>::new());
+    }
+}
+
+#[tokio::test]
+async fn check_identity_pinning() {
+    let ivan = Client::register("ivan").await;
+    let judy = Client::register("judy").await;
+    let other = Client::register("judy-other-device").await;
+
+    let public_bundle = |client: &Client| {
+        let host = client.host.clone();
+        let user = client.user.clone();
+        async move {
+            let mut bundle = load_bundle(&host, &user).await.unwrap();
+            bundle.strip();
+            bundle
+        }
+    };
+    let response = |keybundle| BundleResponse {
+        user: "judy".to_string(),
+        recipient: "ivan".to_string(),
+        keybundle: Some(keybundle),
+        error: None,
+    };
+    let pinned = |host: &TestHost, owner: &'static str, contact: &'static str| {
+        let host = host.clone();
+        async move { load_identity(&host, owner, contact).await.unwrap() }
+    };
+
+    // a bundle whose prekey signature does not check out pins nothing
+    let judy_bundle = public_bundle(&judy).await;
+    let mut forged = judy_bundle.clone();
+    forged.prekey = public_bundle(&other).await.prekey;
+    assert!(alice_x3dh(ivan.host.clone(), &response(forged))
+        .await
+        .is_err());
+    assert!(pinned(&ivan.host, "ivan", "judy").await.is_none());
+
+    // the verified bundle is trusted on first use
+    let handshake = alice_x3dh(ivan.host.clone(), &response(judy_bundle.clone()))
+        .await
+        .unwrap();
+    let known = pinned(&ivan.host, "ivan", "judy").await.unwrap();
+    assert_eq!(known.identity, judy_bundle.identity.public);
+
+    // on the receiving side a handshake that fails the AEAD check pins nothing either
+    let mut tampered = handshake.clone();
+    let mut ciphertext = BASE64_STANDARD
+        .decode(&tampered.content.ciphertext)
+        .unwrap();
+    ciphertext[0] ^= 1;
+    tampered.content.ciphertext = BASE64_STANDARD.encode(ciphertext);
+    assert!(bob_x3dh(judy.host.clone(), tampered).await.is_err());
+    assert!(pinned(&judy.host, "judy", "ivan").await.is_none());
+    bob_x3dh(judy.host.clone(), handshake).await.unwrap();
+    assert!(pinned(&judy.host, "judy", "ivan").await.is_some());
+
+    // a different identity key is held back until the change is accepted
+    let changed = public_bundle(&other).await;
+    let result = alice_x3dh(ivan.host.clone(), &response(changed.clone())).await;
+    assert!(matches!(result, Err(Error::IdentityChanged(_))));
+    let known = pinned(&ivan.host, "ivan", "judy").await.unwrap();
+    assert_eq!(known.identity, judy_bundle.identity.public);
+    assert_eq!(known.pending, Some(changed.identity.public.clone()));
+
+    accept_identity_change(&ivan.host, "ivan", "judy")
+        .await
+        .unwrap();
+    let known = pinned(&ivan.host, "ivan", "judy").await.unwrap();
+    assert_eq!(known.identity, changed.identity.public);
+    assert_eq!(known.pending, None);
+    alice_x3dh(ivan.host.clone(), &response(changed))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_vault_unlocked_on_login() {
+    let mut lena = Client::register("lena").await;
+    let _mike = Client::register("mike").await;
+    let sealable =
+        |user: &'static str| async move { vault::seal(user, "check", &json!(1)).await.is_ok() };
+
+    // logging out one account leaves the others unlocked
+    lena.go_offline().await;
+    vault::lock("lena").await;
+    assert!(!sealable("lena").await);
+    assert!(sealable("mike").await);
+
+    // the passphrase is right but the homeserver refuses the login, the keys stay locked
+    lena.socket = open_socket(&lena.host).await;
+    vault::unlock(&lena.host, "lena", PASSWORD).await.unwrap();
+    let mut login = auth_msg("login", "lena");
+    login.auth.as_mut().unwrap().password = SecretString::from("not the password".to_string());
+    lena.socket.login(login).await.unwrap();
+    lena.expect("auth_failure").await;
+    assert!(!sealable("lena").await);
+
+    // an accepted login unlocks them
+    vault::unlock(&lena.host, "lena", PASSWORD).await.unwrap();
+    assert!(!sealable("lena").await);
+    lena.socket.login(auth_msg("login", "lena")).await.unwrap();
+    lena.expect("register_token").await;
+    assert!(sealable("lena").await);
+}
+
+#[tokio::test]
+async fn check_dead_connection_detected() {
+    *HEARTBEAT_CONFIG.lock().await = HeartbeatConfig {
+        ping_interval_secs: 1,
+        pong_timeout_secs: 3,
+    };
+
+    // a homeserver that keeps talking but never reads, so it never answers a ping
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let (mut sink, _stream) = ws.split();
+        loop {
+            if sink.send(Message::text("{}")).await.is_err() {
+                return;
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    });
+
+    let (host, mut events) = TestHost::new();
+    let mut socket = Socket::new(host, url).await.unwrap();
+    socket.recv_msg().await;
+
+    let reconnecting = async {
+        loop {
+            let (name, _) = events.recv().await.unwrap();
+            if name == "reconnecting" {
+                return;
+            }
+        }
+    };
+    if timeout(EVENT_TIMEOUT, reconnecting).await.is_err() {
+        panic!("a connection without pongs stays open");
+    }
+}
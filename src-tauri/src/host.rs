@@ -0,0 +1,185 @@
+//! What the messaging engine needs from the program it runs in.
+//!
+//! `Socket`, the handshake and the outbox only report events and keep keys,
+//! sessions and messages through these traits. The Tauri app implements them
+//! on its `AppHandle` in `app.rs`, so events reach the frontend and everything
+//! is kept in `tauri_plugin_store` files, a test or a bot brings its own
+//! implementation.
+//! `DirStorage` keeps the same files without Tauri.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Error;
+
+/// Receives the events the frontend listens to, e.g. `msg` or `msg_status`.
+pub trait EventSink: Clone + Send + Sync + 'static {
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> Result<(), Error>;
+
+    /// Emits `event` from a network task, nobody listening anymore, e.g. once the window is
+    /// closed, must not take the task down. The error is only logged.
+    fn emit_or_log<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        if let Err(e) = self.emit(event, payload) {
+            error!("could not emit {}: {}", event, e);
+        }
+    }
+}
+
+/// A file of JSON values by key. Changes are kept once `save` returned.
+pub trait KeyValueStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<Value>;
+    fn has(&self, key: &str) -> bool;
+    fn set(&self, key: &str, value: Value);
+    /// Whether there was a value to delete.
+    fn delete(&self, key: &str) -> bool;
+    fn clear(&self);
+    fn entries(&self) -> Vec<(String, Value)>;
+    fn save(&self) -> Result<(), Error>;
+}
+
+/// Keeps the stores of all accounts and the attachments under one data directory.
+pub trait Storage: Clone + Send + Sync + 'static {
+    type Store: KeyValueStore;
+
+    /// Store at `path` in the data directory, as `get_store_path` builds it. Opening a path
+    /// twice gives the same values.
+    fn open_store(&self, path: &str) -> Result<Self::Store, Error>;
+
+    fn data_dir(&self) -> Result<PathBuf, Error>;
+}
+
+/// Everything the engine runs on.
+pub trait Host: EventSink + Storage {}
+
+impl<T: EventSink + Storage> Host for T {}
+
+/// Stores as JSON files below a data directory, in the format `tauri_plugin_store` writes, so
+/// the command line client can work on the data directory of the app.
+#[derive(Clone)]
+pub struct DirStorage {
+    root: PathBuf,
+    /// Stores opened so far, later opens get the same values.
+    open: Arc<Mutex<HashMap<PathBuf, DirStore>>>,
+}
+
+#[derive(Clone)]
+pub struct DirStore {
+    path: PathBuf,
+    values: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl DirStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Storage for DirStorage {
+    type Store = DirStore;
+
+    fn open_store(&self, path: &str) -> Result<DirStore, Error> {
+        let path = self.root.join(path);
+
+        let mut open = self.open.lock().unwrap();
+        if let Some(store) = open.get(&path) {
+            return Ok(store.clone());
+        }
+
+        let values = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let store = DirStore {
+            path: path.clone(),
+            values: Arc::new(Mutex::new(values)),
+        };
+        open.insert(path, store.clone());
+
+        Ok(store)
+    }
+
+    fn data_dir(&self) -> Result<PathBuf, Error> {
+        Ok(self.root.clone())
+    }
+}
+
+impl KeyValueStore for DirStore {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.values.lock().unwrap().get(key).cloned()
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.values.lock().unwrap().contains_key(key)
+    }
+
+    fn set(&self, key: &str, value: Value) {
+        self.values.lock().unwrap().insert(key.to_string(), value);
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.values.lock().unwrap().remove(key).is_some()
+    }
+
+    fn clear(&self) {
+        self.values.lock().unwrap().clear()
+    }
+
+    fn entries(&self) -> Vec<(String, Value)> {
+        let values = self.values.lock().unwrap();
+        values.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let data = serde_json::to_vec_pretty(&*self.values.lock().unwrap())?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // a crash halfway leaves the old file intact
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn check_dir_storage() {
+    let root = std::env::temp_dir().join(format!("cipher-chat-dir-storage-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let storage = DirStorage::new(root.clone());
+    let store = storage.open_store("server/alice/secrets.bin").unwrap();
+    store.set("bob", serde_json::json!({ "sealed": "abc" }));
+    assert!(storage
+        .open_store("server/alice/secrets.bin")
+        .unwrap()
+        .has("bob"));
+    store.save().unwrap();
+
+    // another process reads what was saved, as plain JSON like tauri_plugin_store keeps it
+    let file: HashMap<String, Value> =
+        serde_json::from_slice(&std::fs::read(root.join("server/alice/secrets.bin")).unwrap())
+            .unwrap();
+    assert_eq!(file["bob"]["sealed"], "abc");
+    let reopened = DirStorage::new(root.clone())
+        .open_store("server/alice/secrets.bin")
+        .unwrap();
+    assert!(reopened.delete("bob"));
+    assert!(!reopened.has("bob"));
+
+    std::fs::remove_dir_all(root).unwrap();
+}
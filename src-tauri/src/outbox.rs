@@ -0,0 +1,461 @@
+//! Outgoing messages that survive a restart.
+//!
+//! Every message is written sealed into `{user}/outbox.bin` before it goes
+//! out and keeps its delivery state there. Queued messages and those waiting
+//! for a bundle are replayed after login and after a reconnect, finished ones
+//! are dropped once they are older than `RETENTION_SECS`. Every state change
+//! is emitted as `msg_status`. An attachment queued before a session existed
+//! keeps its bytes here until the homeserver acknowledged it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    host::Host,
+    util::{unix_time, unix_time_millis, MsgPayload, MsgState, MsgStatus},
+    vault, Error,
+};
+
+const OUTBOX: &str = "outbox.bin";
+
+/// How long finished messages stay queryable.
+const RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Last `queued_at` handed out, see [`next_queued_at`].
+static LAST_QUEUED: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+  /// Held while messages move from the outbox onto the wire, so a replay and a direct send
+  /// never pick up the same message.
+  pub static ref OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    msg: MsgPayload,
+    state: MsgState,
+    /// Unix time in milliseconds, keeps the replay in the order messages were written.
+    queued_at: u64,
+    updated_at: u64,
+    #[serde(default)]
+    error: Option<String>,
+    /// How often the message was handed to the homeserver.
+    #[serde(default)]
+    attempts: u32,
+    /// Sent to a homeserver that acknowledges messages, only those are sent again without one.
+    #[serde(default)]
+    awaiting_ack: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attachment: Option<Attachment>,
+}
+
+/// File contents sent as an attachment frame instead of the cleartext of the message.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub mime_type: String,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Attachment bytes as base64, a JSON array of numbers takes up to four times the space.
+mod base64_bytes {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// A message that is due to go out.
+pub struct Pending {
+    pub msg: MsgPayload,
+    pub attachment: Option<Attachment>,
+}
+
+impl Entry {
+    fn status(&self) -> MsgStatus {
+        MsgStatus {
+            message_id: self.msg.message_id.clone(),
+            recipient: self.msg.recipient.clone(),
+            state: self.state,
+            updated_at: self.updated_at,
+            error: self.error.clone(),
+        }
+    }
+
+    fn into_pending(self) -> Pending {
+        Pending {
+            msg: self.msg,
+            attachment: self.attachment,
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        let finished = matches!(
+            self.state,
+            MsgState::Sent | MsgState::Acked | MsgState::Delivered | MsgState::Failed
+        );
+        finished && now.saturating_sub(self.updated_at) > RETENTION_SECS
+    }
+}
+
+/// Writes `msg` to the outbox of its author.
+pub async fn push<H: Host>(app_handle: &H, msg: &MsgPayload, state: MsgState) -> Result<(), Error> {
+    push_entry(app_handle, msg, state, None).await
+}
+
+/// Writes `msg` with the `attachment` it carries to the outbox of its author.
+#[cfg(any(feature = "gui", test))]
+pub async fn push_attachment<H: Host>(
+    app_handle: &H,
+    msg: &MsgPayload,
+    state: MsgState,
+    attachment: Attachment,
+) -> Result<(), Error> {
+    push_entry(app_handle, msg, state, Some(attachment)).await
+}
+
+async fn push_entry<H: Host>(
+    app_handle: &H,
+    msg: &MsgPayload,
+    state: MsgState,
+    attachment: Option<Attachment>,
+) -> Result<(), Error> {
+    let entry = Entry {
+        msg: msg.clone(),
+        state,
+        queued_at: next_queued_at(),
+        updated_at: unix_time(),
+        error: None,
+        attempts: 0,
+        awaiting_ack: false,
+        attachment,
+    };
+    vault::store(app_handle, &msg.author, OUTBOX, &msg.message_id, &entry).await?;
+
+    app_handle.emit_or_log("msg_status", entry.status());
+    Ok(())
+}
+
+/// Current time in milliseconds, but later than any value returned before so messages written
+/// within the same millisecond keep their order.
+fn next_queued_at() -> u64 {
+    let now = unix_time_millis();
+    let last = LAST_QUEUED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap_or_default();
+
+    now.max(last + 1)
+}
+
+/// Moves a message of `user` to `state`, unknown messages are ignored. Every move to `Sent`
+/// counts as an attempt, a delivered message stays delivered. The bytes of an attachment are
+/// dropped once the homeserver has it, only its status is kept until it expires.
+pub async fn set_state<H: Host>(
+    app_handle: &H,
+    user: &str,
+    message_id: &str,
+    state: MsgState,
+    error: Option<String>,
+) -> Result<(), Error> {
+    update(app_handle, user, message_id, state, error, false).await
+}
+
+/// Marks a message of `user` handed to the homeserver. Without `awaiting_ack`, as on a legacy
+/// homeserver that never acknowledges, `Sent` is as far as the message gets and it is not retried.
+pub async fn set_sent<H: Host>(
+    app_handle: &H,
+    user: &str,
+    message_id: &str,
+    awaiting_ack: bool,
+) -> Result<(), Error> {
+    update(
+        app_handle,
+        user,
+        message_id,
+        MsgState::Sent,
+        None,
+        awaiting_ack,
+    )
+    .await
+}
+
+async fn update<H: Host>(
+    app_handle: &H,
+    user: &str,
+    message_id: &str,
+    state: MsgState,
+    error: Option<String>,
+    awaiting_ack: bool,
+) -> Result<(), Error> {
+    let Some(mut entry) = vault::load::<_, Entry>(app_handle, user, OUTBOX, message_id).await?
+    else {
+        return Ok(());
+    };
+    if entry.state == MsgState::Delivered {
+        return Ok(());
+    }
+
+    entry.state = state;
+    entry.updated_at = unix_time();
+    entry.error = error;
+    entry.awaiting_ack = awaiting_ack;
+    if state == MsgState::Sent {
+        entry.attempts += 1;
+    }
+    if matches!(state, MsgState::Acked | MsgState::Delivered) {
+        entry.attachment = None;
+    }
+    vault::store(app_handle, user, OUTBOX, message_id, &entry).await?;
+
+    app_handle.emit_or_log("msg_status", entry.status());
+    Ok(())
+}
+
+/// Marks a message of `user` delivered, if `contact` is who it was sent to.
+pub async fn set_delivered<H: Host>(
+    app_handle: &H,
+    user: &str,
+    contact: &str,
+    message_id: &str,
+) -> Result<(), Error> {
+    match vault::load::<_, Entry>(app_handle, user, OUTBOX, message_id).await? {
+        Some(entry) if entry.msg.recipient == contact => {
+            set_state(app_handle, user, message_id, MsgState::Delivered, None).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Messages of `user` the homeserver did not acknowledge within `timeout_secs` and that get
+/// another attempt, oldest first. Those already sent `max_attempts` times are marked failed.
+pub async fn due_for_retry<H: Host>(
+    app_handle: &H,
+    user: &str,
+    timeout_secs: u64,
+    max_attempts: u32,
+) -> Result<Vec<Pending>, Error> {
+    let now = unix_time();
+    let entries = entries(app_handle, user).await?;
+
+    let mut due = Vec::new();
+    for entry in entries {
+        let waiting = entry.state == MsgState::Sent && entry.awaiting_ack;
+        if !waiting || now.saturating_sub(entry.updated_at) < timeout_secs {
+            continue;
+        }
+        if entry.attempts >= max_attempts {
+            let error = "no acknowledgement from the homeserver".to_string();
+            set_state(
+                app_handle,
+                user,
+                &entry.msg.message_id,
+                MsgState::Failed,
+                Some(error),
+            )
+            .await?;
+            continue;
+        }
+        due.push(entry.into_pending());
+    }
+
+    Ok(due)
+}
+
+/// Messages of `user` that still have to go out, oldest first.
+pub async fn pending<H: Host>(app_handle: &H, user: &str) -> Result<Vec<Pending>, Error> {
+    let mut entries = entries(app_handle, user).await?;
+    entries.retain(|entry| matches!(entry.state, MsgState::Queued | MsgState::AwaitingBundle));
+
+    Ok(entries.into_iter().map(Entry::into_pending).collect())
+}
+
+/// Delivery state of every message of `user` in the outbox, oldest first.
+#[cfg(any(feature = "gui", test))]
+pub async fn status<H: Host>(app_handle: &H, user: &str) -> Result<Vec<MsgStatus>, Error> {
+    let entries = entries(app_handle, user).await?;
+
+    Ok(entries.iter().map(Entry::status).collect())
+}
+
+/// All entries in order, expired ones are removed on the way.
+async fn entries<H: Host>(app_handle: &H, user: &str) -> Result<Vec<Entry>, Error> {
+    let now = unix_time();
+
+    let mut entries = Vec::new();
+    for (message_id, entry) in vault::load_all::<_, Entry>(app_handle, user, OUTBOX).await? {
+        if entry.expired(now) {
+            vault::remove(app_handle, user, OUTBOX, &message_id).await?;
+            continue;
+        }
+        entries.push(entry);
+    }
+    entries.sort_by_key(|entry| entry.queued_at);
+
+    Ok(entries)
+}
+
+#[test]
+fn check_outbox_expiry() {
+    let msg = MsgPayload {
+        content: None,
+        timestamp: 0,
+        auth: None,
+        message_id: "id".to_string(),
+        author: "alice".to_string(),
+        recipient: "bob".to_string(),
+        transient: false,
+    };
+    let mut entry = Entry {
+        msg,
+        state: MsgState::Sent,
+        queued_at: 0,
+        updated_at: 1_000,
+        error: None,
+        attempts: 1,
+        awaiting_ack: true,
+        attachment: None,
+    };
+
+    assert!(!entry.expired(1_000 + RETENTION_SECS));
+    assert!(entry.expired(1_001 + RETENTION_SECS));
+
+    // undelivered messages are kept no matter how old
+    entry.state = MsgState::AwaitingBundle;
+    assert!(!entry.expired(u64::MAX));
+    assert_eq!(entry.status().state, MsgState::AwaitingBundle);
+}
+
+#[cfg(test)]
+async fn outbox_state(host: &crate::harness::TestHost, user: &str, message_id: &str) -> MsgState {
+    let entries = status(host, user).await.unwrap();
+    let entry = entries.iter().find(|entry| entry.message_id == message_id);
+
+    entry.unwrap().state
+}
+
+#[tokio::test]
+async fn check_outbox_transitions() {
+    let (host, _events) = crate::harness::TestHost::new();
+    vault::stage_new("outbox-olga", "correct horse")
+        .await
+        .unwrap();
+    vault::commit(&host, "outbox-olga").await.unwrap();
+    let msg = |message_id: &str| MsgPayload {
+        content: None,
+        timestamp: 0,
+        auth: None,
+        message_id: message_id.to_string(),
+        author: "outbox-olga".to_string(),
+        recipient: "outbox-pete".to_string(),
+        transient: false,
+    };
+    let state = |message_id: &'static str| outbox_state(&host, "outbox-olga", message_id);
+
+    // acked, then delivered, a late ack does not take the delivery back
+    push(&host, &msg("acked"), MsgState::Sent).await.unwrap();
+    set_state(&host, "outbox-olga", "acked", MsgState::Acked, None)
+        .await
+        .unwrap();
+    assert_eq!(state("acked").await, MsgState::Acked);
+    set_delivered(&host, "outbox-olga", "someone-else", "acked")
+        .await
+        .unwrap();
+    assert_eq!(state("acked").await, MsgState::Acked);
+    set_delivered(&host, "outbox-olga", "outbox-pete", "acked")
+        .await
+        .unwrap();
+    assert_eq!(state("acked").await, MsgState::Delivered);
+    set_state(&host, "outbox-olga", "acked", MsgState::Acked, None)
+        .await
+        .unwrap();
+    assert_eq!(state("acked").await, MsgState::Delivered);
+
+    // only queued and bundle waiting messages are replayed
+    push(&host, &msg("queued"), MsgState::Queued).await.unwrap();
+    let queued = pending(&host, "outbox-olga").await.unwrap();
+    let ids: Vec<_> = queued.iter().map(|p| p.msg.message_id.as_str()).collect();
+    assert_eq!(ids, ["queued"]);
+
+    // an attachment keeps its bytes until it goes out
+    let attachment = Attachment {
+        mime_type: "image/png".to_string(),
+        data: vec![0, 159, 255],
+    };
+    assert_eq!(serde_json::to_value(&attachment).unwrap()["data"], "AJ//");
+    push_attachment(&host, &msg("file"), MsgState::AwaitingBundle, attachment)
+        .await
+        .unwrap();
+    let queued = pending(&host, "outbox-olga").await.unwrap();
+    let file = queued.iter().find(|p| p.msg.message_id == "file").unwrap();
+    assert_eq!(file.attachment.as_ref().unwrap().data, [0, 159, 255]);
+    set_state(&host, "outbox-olga", "file", MsgState::Acked, None)
+        .await
+        .unwrap();
+    let stored = vault::load::<_, Entry>(&host, "outbox-olga", OUTBOX, "file")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.attachment.is_none());
+
+    // a sent message is retried until it ran out of attempts, then it failed
+    push(&host, &msg("unacked"), MsgState::Queued)
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        set_sent(&host, "outbox-olga", "unacked", true)
+            .await
+            .unwrap();
+        let due = due_for_retry(&host, "outbox-olga", 0, 3).await.unwrap();
+        let ids: Vec<_> = due.iter().map(|p| p.msg.message_id.as_str()).collect();
+        assert_eq!(ids, ["unacked"]);
+    }
+    set_sent(&host, "outbox-olga", "unacked", true)
+        .await
+        .unwrap();
+    assert!(due_for_retry(&host, "outbox-olga", 0, 3)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(state("unacked").await, MsgState::Failed);
+
+    // a legacy homeserver never acks, what it was handed stays sent
+    push(&host, &msg("legacy"), MsgState::Queued).await.unwrap();
+    set_sent(&host, "outbox-olga", "legacy", false)
+        .await
+        .unwrap();
+    assert!(due_for_retry(&host, "outbox-olga", 0, 3)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(state("legacy").await, MsgState::Sent);
+
+    // not retried before the timeout
+    push(&host, &msg("recent"), MsgState::Sent).await.unwrap();
+    set_sent(&host, "outbox-olga", "recent", true)
+        .await
+        .unwrap();
+    assert!(due_for_retry(&host, "outbox-olga", 60, 2)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(state("recent").await, MsgState::Sent);
+}
+
+#[test]
+fn check_queued_at_order() {
+    // written within the same millisecond, the second message still sorts after the first
+    let first = next_queued_at();
+    let second = next_queued_at();
+    assert!(second > first);
+}
@@ -0,0 +1,11 @@
+//! Packets exchanged with the homeserver.
+//!
+//! The wire format lives in the `cipher-chat-protocol` crate it shares with
+//! the homeserver, here its packets carry the client's `MsgContent`.
+
+pub use cipher_chat_protocol::packet::*;
+
+use crate::util::MsgContent;
+
+pub type Packet = cipher_chat_protocol::Packet<MsgContent>;
+pub type Handshake = cipher_chat_protocol::Handshake<MsgContent>;
@@ -0,0 +1,418 @@
+//! Double Ratchet session layer.
+//!
+//! Seeded with the shared secret produced by the X3DH handshake, every
+//! message is encrypted with its own message key derived from a sending or
+//! receiving chain, and the chains are reset by a Diffie-Hellman ratchet step
+//! whenever the peer presents a new ratchet key.
+//! See https://signal.org/docs/specifications/doubleratchet/
+
+use base64::{prelude::BASE64_STANDARD, DecodeError, Engine};
+use cipher_chat_protocol::{SecretBytes, SecretString};
+use cryptimitives::aead::aes_gcm::Aes256Gcm;
+use cryptimitives::errors::{AeadError, HmacError, KdfError, KeyPairError};
+use cryptimitives::key::x25519_ristretto::{KeyPair, PublicKey, SecretKey};
+use cryptraits::{
+    aead::Aead,
+    convert::{FromBytes, ToVec},
+    hmac::Hmac,
+    kdf::Kdf,
+    key::{Generate, KeyPair as _},
+    key_exchange::DiffieHellman,
+};
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+
+use crate::util::{KeyPairB64, RatchetHeader};
+
+/// Maximum number of message keys that are derived ahead for a single chain.
+pub const MAX_SKIP: u32 = 1000;
+
+pub const RATCHET_INFO: &str = "CipherChatRatchet";
+
+/// Double Ratchet errors.
+#[derive(Debug, Error)]
+pub enum RatchetError {
+    /// We did not receive a message from the peer yet, so there is no sending chain.
+    #[error("no sending chain established yet")]
+    NoSendingChain,
+
+    /// We did not receive a message on the current ratchet key yet.
+    #[error("no receiving chain established yet")]
+    NoReceivingChain,
+
+    /// The peer skipped more messages than we are willing to store keys for.
+    #[error("too many skipped messages")]
+    TooManySkipped,
+
+    /// Stored session or header contained invalid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// Error occurred in the underlying KDF function.
+    #[error("{0:?}")]
+    KdfError(KdfError),
+
+    /// Error occurred in the underlying HMAC function.
+    #[error("{0:?}")]
+    HmacError(HmacError),
+
+    /// Error occurred in the underlying keypair.
+    #[error("{0:?}")]
+    KeypairError(KeyPairError),
+
+    /// Error occurred in the underlying AEAD cipher.
+    #[error("{0:?}")]
+    AeadError(AeadError),
+}
+
+impl From<KdfError> for RatchetError {
+    fn from(e: KdfError) -> Self {
+        Self::KdfError(e)
+    }
+}
+
+impl From<HmacError> for RatchetError {
+    fn from(e: HmacError) -> Self {
+        Self::HmacError(e)
+    }
+}
+
+impl From<KeyPairError> for RatchetError {
+    fn from(e: KeyPairError) -> Self {
+        Self::KeypairError(e)
+    }
+}
+
+impl From<AeadError> for RatchetError {
+    fn from(e: AeadError) -> Self {
+        Self::AeadError(e)
+    }
+}
+
+/// `Result` specialized for ratchet operations.
+pub type RatchetResult<T> = Result<T, RatchetError>;
+
+/// Message key kept for a message that has not arrived yet.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct SkippedKey {
+    dh: String,
+    n: u32,
+    mk: SecretString,
+}
+
+/// Ratchet state of one conversation, persisted per contact in `secrets.bin`.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    dhs: KeyPairB64,
+    dhr: Option<String>,
+    rk: SecretString,
+    cks: Option<SecretString>,
+    ckr: Option<SecretString>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<SkippedKey>,
+    /// Both identity keys, initiator first, bound into every message.
+    #[serde(default)]
+    ad: String,
+}
+
+impl Session {
+    /// Initiator side, `sk` is the X3DH secret and `remote_ratchet_key` the receivers signed prekey.
+    ///
+    /// `ad` is the X3DH associated data, see [`identity_ad`].
+    pub fn init_alice(sk: &[u8], remote_ratchet_key: &PublicKey, ad: &[u8]) -> RatchetResult<Self> {
+        let dhs = KeyPair::generate_with(OsRng);
+        let (rk, cks) = kdf_rk(sk, &dh(dhs.secret(), remote_ratchet_key))?;
+
+        Ok(Self {
+            dhs: encode_key_pair(&dhs),
+            dhr: Some(BASE64_STANDARD.encode(remote_ratchet_key.to_vec())),
+            rk: SecretString::encode(&rk),
+            cks: Some(SecretString::encode(&cks)),
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            ad: BASE64_STANDARD.encode(ad),
+        })
+    }
+
+    /// Responder side, the signed prekey doubles as the first ratchet key.
+    pub fn init_bob(sk: &[u8], ratchet_key_pair: &KeyPair, ad: &[u8]) -> RatchetResult<Self> {
+        Ok(Self {
+            dhs: encode_key_pair(ratchet_key_pair),
+            dhr: None,
+            rk: SecretString::encode(sk),
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            ad: BASE64_STANDARD.encode(ad),
+        })
+    }
+
+    /// Encrypt `plaintext`, returns the header to send along with nonce and ciphertext.
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        ad: &[u8],
+    ) -> RatchetResult<(RatchetHeader, Vec<u8>, Vec<u8>)> {
+        let cks = match &self.cks {
+            Some(v) => v.decode()?,
+            None => return Err(RatchetError::NoSendingChain),
+        };
+        let (cks, mk) = kdf_ck(&cks)?;
+
+        let header = RatchetHeader {
+            dh: self.dhs.public.clone(),
+            pn: self.pn,
+            n: self.ns,
+        };
+
+        let mut nonce = vec![0; Aes256Gcm::NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ad = header_ad(&self.full_ad(ad)?, &header);
+
+        let cipher = Aes256Gcm::new(&mk);
+        let ciphertext = cipher.encrypt(&nonce, plaintext, Some(&ad))?;
+
+        self.cks = Some(SecretString::encode(&cks));
+        self.ns += 1;
+
+        Ok((header, nonce, ciphertext))
+    }
+
+    /// Decrypt a received message. The session is only advanced if decryption succeeds.
+    pub fn decrypt(
+        &mut self,
+        header: &RatchetHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        ad: &[u8],
+    ) -> RatchetResult<Vec<u8>> {
+        let mut state = self.clone();
+        let plaintext = state.decrypt_in_place(header, nonce, ciphertext, ad)?;
+        *self = state;
+
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(
+        &mut self,
+        header: &RatchetHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        ad: &[u8],
+    ) -> RatchetResult<Vec<u8>> {
+        let ad = header_ad(&self.full_ad(ad)?, header);
+
+        if let Some(pos) = self
+            .skipped
+            .iter()
+            .position(|k| k.dh == header.dh && k.n == header.n)
+        {
+            let mk = self.skipped[pos].mk.decode()?;
+            let plaintext = Aes256Gcm::new(&mk).decrypt(nonce, ciphertext, Some(&ad))?;
+            self.skipped.remove(pos);
+            return Ok(plaintext);
+        }
+
+        if self.dhr.as_deref() != Some(header.dh.as_str()) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(header)?;
+        }
+
+        self.skip_message_keys(header.n)?;
+
+        let ckr = self
+            .ckr
+            .as_ref()
+            .ok_or(RatchetError::NoReceivingChain)?
+            .decode()?;
+        let (ckr, mk) = kdf_ck(&ckr)?;
+        self.ckr = Some(SecretString::encode(&ckr));
+        self.nr += 1;
+
+        Ok(Aes256Gcm::new(&mk).decrypt(nonce, ciphertext, Some(&ad))?)
+    }
+
+    /// Session identity binding followed by the per message associated data.
+    fn full_ad(&self, ad: &[u8]) -> RatchetResult<Vec<u8>> {
+        let mut data = BASE64_STANDARD.decode(&self.ad)?;
+        data.extend(ad);
+        Ok(data)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> RatchetResult<()> {
+        if self.nr + MAX_SKIP < until {
+            return Err(RatchetError::TooManySkipped);
+        }
+
+        let (dhr, ckr) = match (&self.dhr, &self.ckr) {
+            (Some(dhr), Some(ckr)) => (dhr.clone(), ckr.decode()?),
+            _ => return Ok(()),
+        };
+
+        let mut ckr = ckr;
+        while self.nr < until {
+            let (next, mk) = kdf_ck(&ckr)?;
+            self.skipped.push(SkippedKey {
+                dh: dhr.clone(),
+                n: self.nr,
+                mk: SecretString::encode(&mk),
+            });
+            ckr = next;
+            self.nr += 1;
+        }
+        self.ckr = Some(SecretString::encode(&ckr));
+
+        if self.skipped.len() > MAX_SKIP as usize {
+            let overflow = self.skipped.len() - MAX_SKIP as usize;
+            self.skipped.drain(..overflow);
+        }
+
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &RatchetHeader) -> RatchetResult<()> {
+        let dhr = PublicKey::from_bytes(&BASE64_STANDARD.decode(&header.dh)?)?;
+
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = Some(header.dh.clone());
+
+        let rk = self.rk.decode()?;
+        let dhs = decode_key_pair(&self.dhs)?;
+        let (rk, ckr) = kdf_rk(&rk, &dh(dhs.secret(), &dhr))?;
+
+        let dhs = KeyPair::generate_with(OsRng);
+        let (rk, cks) = kdf_rk(&rk, &dh(dhs.secret(), &dhr))?;
+
+        self.dhs = encode_key_pair(&dhs);
+        self.rk = SecretString::encode(&rk);
+        self.ckr = Some(SecretString::encode(&ckr));
+        self.cks = Some(SecretString::encode(&cks));
+
+        Ok(())
+    }
+}
+
+/// X3DH associated data, the identity key of the initiator followed by the one of the receiver.
+pub fn identity_ad(initiator_identity: &PublicKey, receiver_identity: &PublicKey) -> Vec<u8> {
+    let mut data = initiator_identity.to_vec();
+    data.extend(receiver_identity.to_vec());
+    data
+}
+
+fn dh(sk: &SecretKey, pk: &PublicKey) -> SecretBytes {
+    sk.diffie_hellman(pk).to_vec().into()
+}
+
+/// Root chain step, returns the next root key and a fresh chain key.
+fn kdf_rk(rk: &[u8], dh_out: &[u8]) -> RatchetResult<(SecretBytes, SecretBytes)> {
+    let h = cryptimitives::kdf::sha256::Kdf::new(Some(rk), dh_out);
+
+    let mut okm = SecretBytes::from(vec![0_u8; 64]);
+    h.expand(RATCHET_INFO.as_bytes(), &mut okm)?;
+
+    Ok((okm[..32].to_vec().into(), okm[32..].to_vec().into()))
+}
+
+/// Symmetric chain step, returns the next chain key and a message key.
+fn kdf_ck(ck: &[u8]) -> RatchetResult<(SecretBytes, SecretBytes)> {
+    let mut mac = cryptimitives::hmac::sha256::Hmac::new_from_slice(ck)?;
+    mac.update(&[0x01]);
+    let mk = mac.finalize();
+
+    let mut mac = cryptimitives::hmac::sha256::Hmac::new_from_slice(ck)?;
+    mac.update(&[0x02]);
+    let ck = mac.finalize();
+
+    Ok((ck.into(), mk.into()))
+}
+
+fn header_ad(ad: &[u8], header: &RatchetHeader) -> Vec<u8> {
+    let mut data = ad.to_vec();
+    data.extend(header.dh.as_bytes());
+    data.extend(header.pn.to_be_bytes());
+    data.extend(header.n.to_be_bytes());
+    data
+}
+
+fn encode_key_pair(key_pair: &KeyPair) -> KeyPairB64 {
+    KeyPairB64 {
+        public: BASE64_STANDARD.encode(key_pair.public().to_vec()),
+        private: Some(SecretString::encode(&key_pair.secret().to_vec())),
+    }
+}
+
+fn decode_key_pair(key_pair: &KeyPairB64) -> RatchetResult<KeyPair> {
+    let private = key_pair.private.clone().unwrap_or_default().decode()?;
+
+    Ok(KeyPair::from(SecretKey::from_bytes(&private)?))
+}
+
+#[test]
+fn check_ratchet_roundtrip() {
+    let sk = vec![7_u8; 32];
+    let alice_identity = KeyPair::generate_with(OsRng);
+    let bob_identity = KeyPair::generate_with(OsRng);
+    let bob_prekey = KeyPair::generate_with(OsRng);
+    let ad = identity_ad(alice_identity.public(), bob_identity.public());
+
+    let mut alice = Session::init_alice(&sk, bob_prekey.public(), &ad).unwrap();
+    let mut bob = Session::init_bob(&sk, &bob_prekey, &ad).unwrap();
+
+    assert!(bob.encrypt(b"too early", b"").is_err());
+
+    // in order
+    let (header, nonce, ciphertext) = alice.encrypt(b"hi bob", b"msg-1").unwrap();
+    assert_eq!(
+        bob.decrypt(&header, &nonce, &ciphertext, b"msg-1").unwrap(),
+        b"hi bob"
+    );
+
+    // reply triggers a DH ratchet step on both sides
+    let (header, nonce, ciphertext) = bob.encrypt(b"hi alice", b"").unwrap();
+    assert_eq!(
+        alice.decrypt(&header, &nonce, &ciphertext, b"").unwrap(),
+        b"hi alice"
+    );
+
+    // out of order delivery uses the skipped message keys
+    let first = alice.encrypt(b"one", b"").unwrap();
+    let second = alice.encrypt(b"two", b"").unwrap();
+    assert_eq!(
+        bob.decrypt(&second.0, &second.1, &second.2, b"").unwrap(),
+        b"two"
+    );
+    assert_eq!(
+        bob.decrypt(&first.0, &first.1, &first.2, b"").unwrap(),
+        b"one"
+    );
+
+    // replaying a message fails and leaves the session untouched
+    assert!(bob.decrypt(&first.0, &first.1, &first.2, b"").is_err());
+    let (header, nonce, ciphertext) = bob.encrypt(b"still here", b"").unwrap();
+    assert_eq!(
+        alice.decrypt(&header, &nonce, &ciphertext, b"").unwrap(),
+        b"still here"
+    );
+
+    // a relabelled message does not verify
+    let (header, nonce, ciphertext) = alice.encrypt(b"for bob", b"to bob").unwrap();
+    assert!(bob
+        .decrypt(&header, &nonce, &ciphertext, b"to eve")
+        .is_err());
+    assert_eq!(
+        bob.decrypt(&header, &nonce, &ciphertext, b"to bob")
+            .unwrap(),
+        b"for bob"
+    );
+}
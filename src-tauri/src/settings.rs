@@ -0,0 +1,34 @@
+//! Per-account preferences in `{user}/settings.bin`.
+
+#[cfg(feature = "gui")]
+use serde_json::json;
+
+use crate::{
+    host::{Host, KeyValueStore},
+    util::{get_store_path, AccountSettings},
+    Error,
+};
+
+pub async fn load_settings<H: Host>(app_handle: &H, user: &str) -> Result<AccountSettings, Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/settings.bin", user)).await)?;
+
+    let settings = match store.get("settings") {
+        Some(v) => serde_json::from_value(v)?,
+        None => AccountSettings::default(),
+    };
+
+    Ok(settings)
+}
+
+#[cfg(feature = "gui")]
+pub async fn save_settings<H: Host>(
+    app_handle: &H,
+    user: &str,
+    settings: &AccountSettings,
+) -> Result<(), Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/settings.bin", user)).await)?;
+    store.set("settings", json!(settings));
+    store.save()?;
+
+    Ok(())
+}
@@ -1,30 +1,25 @@
-use aes_gcm::Key;
 use async_trait::async_trait;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use cryptimitives::{
-    aead::aes_gcm::Aes256Gcm,
-    key::{self, ed25519::SecretKey, x25519_ristretto, KeyPair},
-};
-use cryptraits::{
-    aead::Aead,
-    convert::{FromBytes, Len},
-    key::Generate,
-};
 use log::info;
 use rand_core::{OsRng, RngCore};
-use tauri::{Emitter, WebviewWindow, Window};
 
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use std::{fs::File, io::BufReader, path::Path, sync::Arc};
-use tauri_plugin_store::StoreExt;
-use tokio::{net::TcpStream, sync::Mutex};
+use std::{
+    collections::{HashSet, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
 use tokio_rustls::rustls::{
     self,
-    crypto::cipher,
-    pki_types::{pem::PemObject, CertificateDer, TrustAnchor},
+    pki_types::{pem::PemObject, CertificateDer},
     RootCertStore,
 };
 use tokio_tungstenite::{
@@ -33,73 +28,182 @@ use tokio_tungstenite::{
     Connector, MaybeTlsStream, WebSocketStream,
 };
 
-use cryptraits::convert::ToVec;
+use cipher_chat_protocol::{SecretBytes, SecretString};
+
+#[cfg(any(feature = "gui", test))]
+use crate::outbox::Attachment;
+#[cfg(feature = "gui")]
+use crate::util::Typing;
+#[cfg(feature = "gui")]
+use std::collections::HashMap;
+#[cfg(any(feature = "gui", test))]
+use std::time::Instant;
 
 use crate::{
-    util::{self, get_store_path, KeyBundle, KeyPairB64, MsgContent, MsgPayload, OpAuthPayload},
-    x3dh::{self, alice_x3dh, bob_x3dh},
-    xxxdh::Protocol,
-    HOMESERVER,
+    envelope::Envelope,
+    frame::{self, Frame},
+    host::Host,
+    outbox::{self, Pending, OUTBOX_LOCK},
+    protocol::{
+        self, AuthAction, AuthRequest, AuthResponse, BundleRequest, Delivered, Packet,
+        PrekeyUpload, WireFormat,
+    },
+    settings::load_settings,
+    transfer::{self, Received},
+    util::{
+        self, unix_time, unix_time_millis, ConnectionHealth, ConnectionState, IdentityChange,
+        KeyBundle, MsgContent, MsgPayload, MsgRead, MsgState, PrekeyConfig, Reconnecting,
+        TypingState,
+    },
+    vault,
+    x3dh::{
+        self, alice_x3dh, bob_x3dh, ensure_identity_trusted, load_session, replenish_onetime_keys,
+        rotate_signed_prekey, save_session,
+    },
+    HEARTBEAT_CONFIG, HOMESERVER, PREKEY_CONFIG,
 };
 
-pub struct Socket {
-    ctx: WebviewWindow,
-    pub ws_sender: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>,
-    ws_rcvr: Option<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+/// How often the signed prekey age is checked while logged in.
+const PREKEY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// First reconnect delay, doubled for every failed attempt up to `RECONNECT_MAX_DELAY`.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Failed attempts after which the connection is given up and `connection_closed` emitted.
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// A sent message the homeserver did not acknowledge within this time is sent again.
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+const ACK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Sends per message before it is marked failed.
+const MAX_SEND_ATTEMPTS: u32 = 3;
+
+/// Ids of received messages remembered to drop the copies a retry produces.
+const SEEN_IDS: usize = 1024;
+
+/// While typing goes on, "started" is sent again this often so the indicator of the contact
+/// does not time out.
+#[cfg(any(feature = "gui", test))]
+const TYPING_REFRESH: Duration = Duration::from_secs(5);
+/// Shortest time between two typing notifications that switch the state.
+#[cfg(any(feature = "gui", test))]
+const TYPING_MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Typing notifications older than this are stale, e.g. when the homeserver held them back.
+const TYPING_MAX_AGE_SECS: u64 = 10;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsSender = Arc<Mutex<PacketSink>>;
+
+/// Sending half of the connection, packets go out in the format the homeserver reads.
+pub struct PacketSink {
+    sink: SplitSink<WsStream, Message>,
+    /// `Legacy` until the homeserver sent a versioned packet.
+    format: WireFormat,
+}
+
+impl PacketSink {
+    fn new(sink: SplitSink<WsStream, Message>) -> Self {
+        Self {
+            sink,
+            format: WireFormat::Legacy,
+        }
+    }
+
+    /// Whether the homeserver acknowledges what it is sent, a legacy one never does.
+    fn acks(&self) -> bool {
+        self.format == WireFormat::Versioned
+    }
+
+    async fn send_packet(&mut self, packet: &Packet) -> Result<(), util::Error> {
+        let json = protocol::encode(packet, self.format)?;
+        self.sink.send(Message::text(json)).await?;
+        Ok(())
+    }
+
+    /// Send a binary frame or a ping.
+    async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        self.sink.send(msg).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.sink.flush().await
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        self.sink.close().await
+    }
+}
+
+pub struct Socket<H: Host> {
+    pub ws_sender: WsSender,
+    ws_rcvr: Option<SplitStream<WsStream>>,
     pub stream_type: String,
-    pub msg_queue: Arc<Mutex<Vec<MsgPayload>>>,
-    pub app_handle: tauri::AppHandle,
+    /// Receives the events and keeps the keys, sessions and messages.
+    pub app_handle: H,
+    /// Account that is currently logged in on this connection.
+    pub user: Arc<Mutex<Option<String>>>,
+    rotation_task: Option<JoinHandle<()>>,
+    heartbeat_task: Option<JoinHandle<()>>,
+    retry_task: Option<JoinHandle<()>>,
+    pub health: Arc<Mutex<ConnectionHealth>>,
+    /// Request to authenticate with again after a reconnect, a `resume` with the session token
+    /// once the server issued one.
+    resume_auth: Arc<Mutex<Option<AuthRequest>>>,
+    /// Contacts whose bundle was requested but did not arrive yet.
+    pending_bundles: Arc<Mutex<HashSet<String>>>,
+    /// Set by `close`, a closed connection is not reconnected.
+    closing: Arc<AtomicBool>,
+    /// Recently received messages as `author/message_id`.
+    seen: Arc<Mutex<VecDeque<String>>>,
+    /// Last typing state sent per `user/contact` and when.
+    #[cfg(feature = "gui")]
+    typing_sent: HashMap<String, (bool, Instant)>,
 }
 
 #[async_trait]
-pub trait SocketFuncs {
-    async fn new(
-        ctx: WebviewWindow,
-        url: String,
-        app_handle: tauri::AppHandle,
-    ) -> Result<Box<Self>, util::Error>;
-    async fn send_msg(&mut self, msg: MsgPayload, sk: &str) -> Result<(), util::Error>;
+pub trait SocketFuncs<H: Host> {
+    async fn new(app_handle: H, url: String) -> Result<Box<Self>, util::Error>;
+    async fn send_msg(&mut self, msg: MsgPayload) -> Result<(), util::Error>;
+    async fn queue_msg(&mut self, msg: MsgPayload) -> Result<(), util::Error>;
+    #[cfg(any(feature = "gui", test))]
+    async fn queue_attachment(
+        &mut self,
+        msg: MsgPayload,
+        mime_type: String,
+        data: Vec<u8>,
+    ) -> Result<(), util::Error>;
+    #[cfg(feature = "gui")]
+    async fn send_control(&mut self, msg: MsgPayload) -> Result<(), util::Error>;
+    #[cfg(feature = "gui")]
+    async fn send_typing(
+        &mut self,
+        user: String,
+        contact: String,
+        typing: bool,
+    ) -> Result<(), util::Error>;
+    #[cfg(any(feature = "gui", test))]
+    async fn send_attachment(
+        &mut self,
+        msg: MsgPayload,
+        mime_type: String,
+        data: Vec<u8>,
+    ) -> Result<(), util::Error>;
     async fn recv_msg(&mut self);
     async fn close(&mut self) -> Result<(), Error>;
 
     async fn login(&mut self, auth: MsgPayload) -> Result<(), util::Error>;
     async fn register(&mut self, auth: MsgPayload, keybundle: KeyBundle)
         -> Result<(), util::Error>;
+    #[cfg(feature = "gui")]
     async fn logout(&mut self, auth: MsgPayload) -> Result<(), util::Error>;
 
     async fn fetch_bundle(&mut self, user: String) -> Result<(), util::Error>;
 }
 
 #[async_trait]
-impl SocketFuncs for Socket {
-    async fn new(
-        ctx: WebviewWindow,
-        url: String,
-        app_handle: tauri::AppHandle,
-    ) -> Result<Box<Self>, util::Error> {
-        let tls_config = match Path::new("rootCA.crt").exists() {
-            true => {
-                let mut root_cert_store = RootCertStore::empty();
-
-                let cert_der = CertificateDer::from_pem_file("rootCA.crt").unwrap();
-                root_cert_store.add(cert_der).unwrap();
-
-                info!("using provided root ca");
-
-                rustls::ClientConfig::builder()
-                    .with_root_certificates(root_cert_store)
-                    .with_no_client_auth()
-            }
-            false => {
-                info!("defaulting to os native certs");
-                rustls_platform_verifier::tls_config()
-            }
-        };
-
-        let connector = Connector::Rustls(Arc::new(tls_config));
-
-        let (ws_stream, _) =
-            connect_async_tls_with_config(url, None, false, Some(connector)).await?;
+impl<H: Host> SocketFuncs<H> for Socket<H> {
+    async fn new(app_handle: H, url: String) -> Result<Box<Self>, util::Error> {
+        let ws_stream = connect(url).await?;
 
         let stream_type = match ws_stream.get_ref() {
             MaybeTlsStream::Plain(_) => "unencrypted",
@@ -110,242 +214,1232 @@ impl SocketFuncs for Socket {
         let (ws_sender, ws_rcvr) = ws_stream.split();
 
         Ok(Box::new(Socket {
-            ctx: ctx,
-            ws_sender: Arc::new(Mutex::new(ws_sender)),
+            ws_sender: Arc::new(Mutex::new(PacketSink::new(ws_sender))),
             ws_rcvr: Some(ws_rcvr),
             stream_type: stream_type.to_string(),
-            msg_queue: Arc::new(Mutex::new(Vec::new())),
             app_handle,
+            user: Arc::new(Mutex::new(None)),
+            rotation_task: None,
+            heartbeat_task: None,
+            retry_task: None,
+            health: Arc::new(Mutex::new(ConnectionHealth::new(
+                ConnectionState::Connected,
+            ))),
+            resume_auth: Arc::new(Mutex::new(None)),
+            pending_bundles: Arc::new(Mutex::new(HashSet::new())),
+            closing: Arc::new(AtomicBool::new(false)),
+            seen: Arc::new(Mutex::new(VecDeque::new())),
+            #[cfg(feature = "gui")]
+            typing_sent: HashMap::new(),
         }))
     }
 
-    async fn send_msg(&mut self, mut msg: MsgPayload, sk: &str) -> Result<(), util::Error> {
-        info!("sending: {:?}", msg.content.clone());
+    async fn send_msg(&mut self, msg: MsgPayload) -> Result<(), util::Error> {
+        info!("sending {} to {}", msg.message_id, msg.recipient);
 
-        let payload = encrypt_msg(msg, sk).await?;
-        self.ws_sender.lock().await.send(payload).await?;
+        let _sending = OUTBOX_LOCK.lock().await;
+        outbox::push(&self.app_handle, &msg, MsgState::Queued).await?;
+
+        let payload = encrypt_msg(&self.app_handle, msg.clone()).await?;
+        let awaiting_ack = self.ws_sender.lock().await.acks();
+        match self.ws_sender.lock().await.send_packet(&payload).await {
+            Ok(()) => {
+                outbox::set_sent(&self.app_handle, &msg.author, &msg.message_id, awaiting_ack)
+                    .await?
+            }
+            // encrypted again from the outbox once the connection is back
+            Err(e) => info!("queueing msg until reconnected: {}", e),
+        }
         Ok(())
     }
 
-    async fn fetch_bundle(&mut self, user: String) -> Result<(), util::Error> {
-        let msg = MsgPayload {
-            content: None,
-            timestamp: 0,
-            auth: Some(OpAuthPayload {
-                action: "fetch_bundle".to_string(),
-                user: user,
-                password: "".to_string(),
-                keybundle: None,
-                message: "".to_string(),
-                success: None,
-            }),
-            message_id: "".to_string(),
-            author: "me".to_string(),
-            recipient: "".to_string(),
-        };
+    /// Keep `msg` in the outbox until a session with its recipient exists.
+    async fn queue_msg(&mut self, msg: MsgPayload) -> Result<(), util::Error> {
+        outbox::push(&self.app_handle, &msg, MsgState::AwaitingBundle).await?;
+        self.fetch_bundle(msg.recipient).await
+    }
+
+    /// Keep an attachment in the outbox until a session with its recipient exists, it is
+    /// framed then.
+    #[cfg(any(feature = "gui", test))]
+    async fn queue_attachment(
+        &mut self,
+        msg: MsgPayload,
+        mime_type: String,
+        data: Vec<u8>,
+    ) -> Result<(), util::Error> {
+        let attachment = Attachment { mime_type, data };
+        outbox::push_attachment(&self.app_handle, &msg, MsgState::AwaitingBundle, attachment)
+            .await?;
+        self.fetch_bundle(msg.recipient).await
+    }
+
+    /// Encrypt and send a control message, those are neither kept in the outbox nor retried.
+    #[cfg(feature = "gui")]
+    async fn send_control(&mut self, msg: MsgPayload) -> Result<(), util::Error> {
+        let payload = encrypt_msg(&self.app_handle, msg).await?;
+        self.ws_sender.lock().await.send_packet(&payload).await?;
+        Ok(())
+    }
+
+    /// Tell `contact` that `user` started or stopped typing. Notifications faster than the
+    /// rate limit allows are dropped, as are those to contacts without a session.
+    #[cfg(feature = "gui")]
+    async fn send_typing(
+        &mut self,
+        user: String,
+        contact: String,
+        typing: bool,
+    ) -> Result<(), util::Error> {
+        let key = format!("{}/{}", user, contact);
+        let now = Instant::now();
+        if !typing_due(self.typing_sent.get(&key).copied(), typing, now) {
+            return Ok(());
+        }
+        if load_session(&self.app_handle, &user, &contact)
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
 
-        info!("sending: {:?}", msg.clone());
+        let message_id = format!("typing-{}", unix_time_millis());
+        let msg = Envelope::Typing(Typing { typing }).into_msg(message_id, &user, &contact);
+        self.send_control(msg).await?;
+        self.typing_sent.insert(key, (typing, now));
+        Ok(())
+    }
 
-        let json = serde_json::to_string(&msg)?;
-        let payload = Message::text(json);
+    /// Frame and send an attachment, it is kept in the outbox and retried like a message.
+    #[cfg(any(feature = "gui", test))]
+    async fn send_attachment(
+        &mut self,
+        msg: MsgPayload,
+        mime_type: String,
+        data: Vec<u8>,
+    ) -> Result<(), util::Error> {
+        info!("sending {} attachment of {} bytes", mime_type, data.len());
 
-        self.ws_sender.lock().await.send(payload).await?;
+        let _sending = OUTBOX_LOCK.lock().await;
+        let attachment = Attachment { mime_type, data };
+        outbox::push_attachment(&self.app_handle, &msg, MsgState::Queued, attachment.clone())
+            .await?;
 
+        let payload = encrypt_attachment(
+            &self.app_handle,
+            msg.clone(),
+            &attachment.mime_type,
+            &attachment.data,
+        )
+        .await?;
+        let awaiting_ack = self.ws_sender.lock().await.acks();
+        match self.ws_sender.lock().await.send(payload).await {
+            Ok(()) => {
+                outbox::set_sent(&self.app_handle, &msg.author, &msg.message_id, awaiting_ack)
+                    .await?
+            }
+            // framed again from the outbox once the connection is back
+            Err(e) => info!("queueing attachment until reconnected: {}", e),
+        }
         Ok(())
     }
 
+    async fn fetch_bundle(&mut self, user: String) -> Result<(), util::Error> {
+        request_bundle(&self.ws_sender, &self.pending_bundles, user).await
+    }
+
     async fn login(&mut self, auth: MsgPayload) -> Result<(), util::Error> {
-        info!("logging in: {}", auth.auth.clone().unwrap().user.clone());
-        let json = serde_json::to_string(&auth)?;
-        let payload = Message::text(json);
-        self.ws_sender.lock().await.send(payload).await?;
+        let request = auth_request(auth)?;
+        info!("logging in: {}", request.user);
+        *self.resume_auth.lock().await = Some(request.clone());
+        let packet = Packet::AuthRequest(request);
+        self.ws_sender.lock().await.send_packet(&packet).await?;
         Ok(())
     }
 
+    #[cfg(feature = "gui")]
     async fn logout(&mut self, auth: MsgPayload) -> Result<(), util::Error> {
-        info!("logging out: {}", auth.auth.clone().unwrap().user.clone());
-        self.msg_queue.lock().await.clear();
-        let json = serde_json::to_string(&auth)?;
-        let payload = Message::text(json);
-        self.ws_sender.lock().await.send(payload).await?;
+        let request = auth_request(auth)?;
+        info!("logging out: {}", request.user);
+        self.pending_bundles.lock().await.clear();
+        *self.user.lock().await = None;
+        *self.resume_auth.lock().await = None;
+        let packet = Packet::AuthRequest(request);
+        self.ws_sender.lock().await.send_packet(&packet).await?;
         Ok(())
     }
 
     async fn register(
         &mut self,
-        mut auth: MsgPayload,
+        auth: MsgPayload,
         keybundle: KeyBundle,
     ) -> Result<(), util::Error> {
-        info!(
-            "registering as: {}",
-            auth.auth.clone().unwrap().user.clone()
-        );
-        // auth.auth.unwrap().keybundle = Some(keybundle);
-        if let Some(auth_data) = auth.auth.as_mut() {
-            auth_data.keybundle = Some(keybundle);
-        }
-        let json = serde_json::to_string(&auth)?;
-        let payload = Message::text(json);
-        self.ws_sender.lock().await.send(payload).await?;
+        let mut request = auth_request(auth)?;
+        info!("registering as: {}", request.user);
+        request.keybundle = Some(keybundle);
+        *self.resume_auth.lock().await = Some(request.clone());
+        let packet = Packet::AuthRequest(request);
+        self.ws_sender.lock().await.send_packet(&packet).await?;
         Ok(())
     }
 
     async fn recv_msg(&mut self) {
         let mut ws_rcvr = self.ws_rcvr.take().unwrap();
         let ws_sender = self.ws_sender.clone();
-        let ctx = self.ctx.clone();
-
-        let msg_queue = self.msg_queue.clone();
         let app_handle = self.app_handle.clone();
+        let user = self.user.clone();
+        let resume_auth = self.resume_auth.clone();
+        let pending_bundles = self.pending_bundles.clone();
+        let closing = self.closing.clone();
+        let health = self.health.clone();
+        let seen = self.seen.clone();
+
+        self.rotation_task = Some(tokio::spawn(rotation_loop(
+            app_handle.clone(),
+            ws_sender.clone(),
+            user.clone(),
+        )));
+        self.heartbeat_task = Some(tokio::spawn(heartbeat_loop(ws_sender.clone())));
+        self.retry_task = Some(tokio::spawn(retry_loop(
+            app_handle.clone(),
+            ws_sender.clone(),
+            user.clone(),
+        )));
 
         tokio::spawn(async move {
-            while let Some(Ok(msg)) = ws_rcvr.next().await {
-                match msg {
-                    Message::Text(txt) => {
-                        // let msg: MsgPayload = serde_json::from_str(&txt).unwrap();
-                        match serde_json::from_str::<MsgPayload>(&txt) {
-                            Ok(mut msg) => {
-                                info!("received: {:?}", msg);
-
-                                match msg.clone().auth {
-                                    Some(v) => {
-                                        if v.action == "register" || v.action == "login" {
-                                            match v.success {
-                                                Some(v) => {
-                                                    if v == true {
-                                                        ctx.emit("register_token", msg).unwrap();
-                                                    } else {
-                                                        ctx.emit("auth_failure", msg).unwrap();
-                                                    }
-                                                }
-                                                None => return,
-                                            };
-                                        } else if v.action == "fetch_bundle" {
-                                            let x = alice_x3dh(app_handle.clone(), msg).await;
-                                            let json = serde_json::to_string(&x).unwrap();
-                                            let payload = Message::text(json);
-                                            ws_sender.lock().await.send(payload).await.unwrap();
-                                            info!("sent x3dh payload");
-
-                                            let mut z = msg_queue.lock().await;
-
-                                            for msg in (*z).iter() {
-                                                let path = get_store_path(&format!(
-                                                    "{}/secrets.bin",
-                                                    msg.author
-                                                ))
-                                                .await;
-
-                                                info!(
-                                                    "brr_using: {}",
-                                                    format!("{}/secrets.bin", msg.author)
-                                                );
-
-                                                let store =
-                                                    app_handle.store_builder(path).build().unwrap();
-                                                let sk = store.get(&msg.recipient).unwrap();
-
-                                                let payload =
-                                                    encrypt_msg(msg.clone(), sk.as_str().unwrap())
-                                                        .await
-                                                        .unwrap();
-                                                ws_sender.lock().await.send(payload).await.unwrap();
-                                            }
+            loop {
+                // pings go out more often than this, a connection without pongs is dead even
+                // if other frames still arrive
+                let mut pong_deadline = tokio::time::Instant::now() + pong_timeout().await;
+                loop {
+                    let msg = match tokio::time::timeout_at(pong_deadline, ws_rcvr.next()).await {
+                        Ok(Some(Ok(msg))) => msg,
+                        Ok(_) => break,
+                        Err(_) => {
+                            warn!("no pong from the homeserver in time");
+                            break;
+                        }
+                    };
 
-                                            z.clear();
-                                        } else if v.action == "x3dh" {
-                                            bob_x3dh(
-                                                app_handle.clone(),
-                                                msg_queue.clone(),
-                                                msg.clone(),
-                                            )
-                                            .await;
-                                        }
-                                    }
-                                    None => {
-                                        let path = get_store_path(&format!(
-                                            "{}/secrets.bin",
-                                            msg.recipient
-                                        ))
-                                        .await;
+                    match msg {
+                        Message::Text(txt) => {
+                            let (packet, format) = match protocol::decode(&txt) {
+                                Ok(decoded) => decoded,
+                                Err(e) => {
+                                    error!("received wrong data: {:?}", e);
+                                    continue;
+                                }
+                            };
+                            match packet.message_id() {
+                                Some(id) => info!("received {} {}", packet.kind(), id),
+                                None => info!("received {}", packet.kind()),
+                            }
 
-                                        info!("using {}", format!("{}/secrets.bin", msg.recipient));
+                            // the homeserver read the version announced with the login
+                            if format == WireFormat::Versioned {
+                                ws_sender.lock().await.format = WireFormat::Versioned;
+                            }
 
-                                        let store = app_handle.store_builder(path).build().unwrap();
-                                        let x = store.get(&msg.author).unwrap();
-                                        let sk = x.as_str().unwrap();
+                            match packet {
+                                Packet::AuthResponse(response) => {
+                                    if response.error.is_some() {
+                                        // an expired session token needs a new login
+                                        *resume_auth.lock().await = None;
+                                        vault::discard(&response.user).await;
+                                        x3dh::discard_bundle(&response.user).await;
+                                        let msg = Packet::AuthResponse(response).into_legacy();
+                                        app_handle.emit_or_log("auth_failure", msg);
+                                        continue;
+                                    }
+
+                                    // the keys are only unlocked for a login the homeserver took
+                                    if let Err(e) = commit_login(&app_handle, &response.user).await
+                                    {
+                                        error!("could not unlock the vault: {}", e);
+                                    }
+                                    *user.lock().await = Some(response.user.clone());
+                                    remember_login(&resume_auth, &response).await;
+                                    if let Err(e) = publish_rotated_prekey(
+                                        &app_handle,
+                                        &ws_sender,
+                                        &response.user,
+                                    )
+                                    .await
+                                    {
+                                        error!("prekey rotation failed: {}", e);
+                                    }
+                                    if let Err(e) = flush_queue(
+                                        &app_handle,
+                                        &ws_sender,
+                                        &pending_bundles,
+                                        &response.user,
+                                        None,
+                                    )
+                                    .await
+                                    {
+                                        error!("could not replay outbox: {}", e);
+                                    }
+                                    if let Err(e) =
+                                        resume_transfers(&app_handle, &ws_sender, &response.user)
+                                            .await
+                                    {
+                                        error!("could not resume transfers: {}", e);
+                                    }
+                                    if response.action != AuthAction::Resume {
+                                        let msg = Packet::AuthResponse(response).into_legacy();
+                                        app_handle.emit_or_log("register_token", msg);
+                                    }
+                                }
+                                Packet::BundleResponse(response) => {
+                                    pending_bundles.lock().await.remove(&response.user);
 
-                                        let sk = BASE64_STANDARD.decode(sk).unwrap();
+                                    // a late second response must not replace the session the
+                                    // first one established
+                                    let established = matches!(
+                                        load_session(
+                                            &app_handle,
+                                            &response.recipient,
+                                            &response.user
+                                        )
+                                        .await,
+                                        Ok(Some(_))
+                                    );
 
-                                        info!("sk {:?}", sk);
+                                    if !established {
+                                        let handshake =
+                                            match alice_x3dh(app_handle.clone(), &response).await {
+                                                Ok(handshake) => handshake,
+                                                Err(util::Error::IdentityChanged(contact)) => {
+                                                    // held until the user accepts the new key
+                                                    app_handle.emit_or_log(
+                                                        "identity_changed",
+                                                        IdentityChange {
+                                                            user: response.recipient.clone(),
+                                                            contact,
+                                                        },
+                                                    );
+                                                    continue;
+                                                }
+                                                Err(util::Error::XxxDh(e)) => {
+                                                    // queued messages for this contact stay queued
+                                                    error!(
+                                                        "rejected bundle of {}: {}",
+                                                        response.user, e
+                                                    );
+                                                    let msg = Packet::BundleResponse(response)
+                                                        .into_legacy();
+                                                    app_handle.emit_or_log("bundle_rejected", msg);
+                                                    continue;
+                                                }
+                                                Err(e) => {
+                                                    error!(
+                                                        "x3dh with {} failed: {}",
+                                                        response.user, e
+                                                    );
+                                                    continue;
+                                                }
+                                            };
+                                        let packet = Packet::Handshake(handshake);
+                                        if let Err(e) =
+                                            ws_sender.lock().await.send_packet(&packet).await
+                                        {
+                                            error!("could not send x3dh payload: {}", e);
+                                            continue;
+                                        }
+                                        info!("sent x3dh payload");
+                                    }
 
-                                        let msg_content = msg.content.as_mut().unwrap();
+                                    // only this contact has a session now
+                                    if let Err(e) = flush_queue(
+                                        &app_handle,
+                                        &ws_sender,
+                                        &pending_bundles,
+                                        &response.recipient,
+                                        Some(&response.user),
+                                    )
+                                    .await
+                                    {
+                                        error!("could not flush outbox: {}", e);
+                                    }
+                                }
+                                Packet::Ack(ack) => {
+                                    // the homeserver took the message, or refused it
+                                    let state = match ack.error {
+                                        Some(_) => MsgState::Failed,
+                                        None => MsgState::Acked,
+                                    };
+                                    if let Err(e) = outbox::set_state(
+                                        &app_handle,
+                                        &ack.author,
+                                        &ack.message_id,
+                                        state,
+                                        ack.error,
+                                    )
+                                    .await
+                                    {
+                                        error!("could not record ack: {}", e);
+                                    }
+                                }
+                                Packet::Delivered(receipt) => {
+                                    if let Err(e) = outbox::set_delivered(
+                                        &app_handle,
+                                        &receipt.recipient,
+                                        &receipt.author,
+                                        &receipt.message_id,
+                                    )
+                                    .await
+                                    {
+                                        error!("could not record delivery: {}", e);
+                                    }
+                                }
+                                Packet::OtkCount(count) => {
+                                    let config = PREKEY_CONFIG.lock().await.clone();
 
-                                        let nonce =
-                                            BASE64_STANDARD.decode(&msg_content.nonce).unwrap();
-                                        let ciphertext = BASE64_STANDARD
-                                            .decode(&msg_content.ciphertext)
-                                            .unwrap();
+                                    info!("server has {} one-time keys left", count.count);
 
-                                        let cipher = Aes256Gcm::new(&sk);
-                                        let cleartext =
-                                            cipher.decrypt(&nonce, &ciphertext, None).unwrap();
+                                    if let Some(batch) = otk_refill(count.count, &config) {
+                                        match replenish_onetime_keys(
+                                            &app_handle,
+                                            &count.user,
+                                            batch,
+                                        )
+                                        .await
+                                        {
+                                            Ok(bundle) => {
+                                                let upload = Packet::UploadPrekeys(PrekeyUpload {
+                                                    user: count.user,
+                                                    keybundle: bundle,
+                                                });
+                                                match ws_sender
+                                                    .lock()
+                                                    .await
+                                                    .send_packet(&upload)
+                                                    .await
+                                                {
+                                                    Ok(()) => info!("uploaded new one-time keys"),
+                                                    Err(e) => {
+                                                        error!("could not upload keys: {}", e)
+                                                    }
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!("could not replenish keys: {}", e);
+                                            }
+                                        }
+                                    }
+                                }
+                                Packet::Handshake(handshake) => {
+                                    let author = handshake.author.clone();
+                                    let recipient = handshake.recipient.clone();
+                                    if let Err(e) = bob_x3dh(app_handle.clone(), handshake).await {
+                                        error!("x3dh from {} rejected: {}", author, e);
 
-                                        let cleartext = String::from_utf8(cleartext).unwrap();
+                                        if let util::Error::IdentityChanged(contact) = e {
+                                            app_handle.emit_or_log(
+                                                "identity_changed",
+                                                IdentityChange {
+                                                    user: recipient,
+                                                    contact,
+                                                },
+                                            );
+                                        }
+                                    }
+                                }
+                                Packet::Chat(mut msg) => {
+                                    if let Err(e) = decrypt_msg(&app_handle, &mut msg).await {
+                                        error!("could not decrypt msg: {}", e);
+                                        app_handle.emit_or_log("msg_rejected", msg);
+                                        continue;
+                                    }
 
-                                        msg_content.cleartext = Some(cleartext);
+                                    if let Some(envelope) = Envelope::parse(&msg) {
+                                        if let Err(e) =
+                                            handle_control(&app_handle, &ws_sender, &msg, envelope)
+                                                .await
+                                        {
+                                            error!("control msg from {} failed: {}", msg.author, e);
+                                        }
+                                        continue;
+                                    }
 
-                                        info!("decrypted msg: {:?}", msg.clone());
+                                    info!("decrypted msg {} from {}", msg.message_id, msg.author);
 
-                                        ctx.emit("msg", msg).unwrap();
+                                    send_receipt(&ws_sender, &msg).await;
+                                    if first_delivery(&seen, &msg).await {
+                                        app_handle.emit_or_log("msg", msg);
                                     }
-                                };
+                                }
+                                Packet::Error(failure) => {
+                                    error!("homeserver refused a packet: {}", failure.message);
+                                }
+                                // only the homeserver receives these
+                                Packet::AuthRequest(_)
+                                | Packet::BundleRequest(_)
+                                | Packet::UploadPrekeys(_)
+                                | Packet::RotatePrekey(_) => {
+                                    warn!("unexpected packet from the homeserver");
+                                }
+                            }
+                        }
+                        Message::Binary(data) => match frame::decode(&data) {
+                            Ok(Frame::Attachment(mut msg, ciphertext)) => {
+                                if let Err(e) =
+                                    decrypt_attachment(&app_handle, &mut msg, ciphertext).await
+                                {
+                                    error!("could not decrypt attachment: {}", e);
+                                    app_handle.emit_or_log("msg_rejected", msg);
+                                    continue;
+                                }
+
+                                send_receipt(&ws_sender, &msg).await;
+                                if first_delivery(&seen, &msg).await {
+                                    app_handle.emit_or_log("msg", msg);
+                                }
+                            }
+                            Ok(Frame::Chunk(header, index, ciphertext)) => {
+                                if let Err(e) = handle_chunk(
+                                    &app_handle,
+                                    &ws_sender,
+                                    &header,
+                                    index,
+                                    ciphertext,
+                                )
+                                .await
+                                {
+                                    error!("transfer {} failed: {}", header.message_id, e);
+                                    app_handle.emit_or_log("transfer_failed", header);
+                                }
                             }
                             Err(e) => {
-                                error!("received wrong data: {:?}", e);
+                                error!("received malformed frame: {}", e);
+                            }
+                        },
+                        Message::Ping(_) => {
+                            // tungstenite queued the pong already, it goes out with the next flush
+                            if let Err(e) = ws_sender.lock().await.flush().await {
+                                info!("could not answer ping: {}", e);
                             }
                         }
+                        Message::Pong(data) => {
+                            pong_deadline = tokio::time::Instant::now() + pong_timeout().await;
+                            let mut health = health.lock().await;
+                            health.last_pong = Some(unix_time());
+                            health.rtt_ms = <[u8; 8]>::try_from(data.as_ref()).ok().map(|sent| {
+                                unix_time_millis().saturating_sub(u64::from_be_bytes(sent))
+                            });
+                        }
+                        Message::Close(_) => {
+                            info!("conn closed")
+                        }
+                        // raw frames only show up when writing, never from `next()`
+                        Message::Frame(_) => {}
                     }
-                    Message::Binary(_) => todo!(),
-                    Message::Ping(_) => todo!(),
-                    Message::Pong(_) => todo!(),
-                    Message::Close(_) => {
-                        info!("conn closed")
-                    }
-                    Message::Frame(_) => todo!(),
                 }
+
+                if closing.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                health.lock().await.state = ConnectionState::Reconnecting;
+                ws_rcvr = match reconnect(&app_handle, &ws_sender).await {
+                    Some(ws_rcvr) => ws_rcvr,
+                    None => break,
+                };
+                *health.lock().await = ConnectionHealth::new(ConnectionState::Connected);
+
+                if let Err(e) = resume(&ws_sender, &resume_auth, &pending_bundles).await {
+                    error!("could not resume session: {}", e);
+                }
+                app_handle.emit_or_log("reconnected", ());
             }
 
             info!("Connection closed?");
-            ctx.emit("connection_closed", {}).unwrap();
+            health.lock().await.state = ConnectionState::Closed;
+            app_handle.emit_or_log("connection_closed", ());
         });
     }
 
     async fn close(&mut self) -> Result<(), Error> {
+        self.closing.store(true, Ordering::SeqCst);
+        if let Some(task) = self.rotation_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.heartbeat_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.retry_task.take() {
+            task.abort();
+        }
         self.ws_sender.lock().await.close().await?;
         Ok(())
     }
 }
 
-async fn encrypt_msg(mut msg: MsgPayload, sk: &str) -> Result<Message, util::Error> {
-    let sk = BASE64_STANDARD.decode(sk).unwrap();
+/// Connect to `url`, trusting `rootCA.crt` if present and the OS certificates otherwise.
+async fn connect(url: String) -> Result<WsStream, util::Error> {
+    let tls_config = match Path::new("rootCA.crt").exists() {
+        true => {
+            let mut root_cert_store = RootCertStore::empty();
+
+            let cert_der = CertificateDer::from_pem_file("rootCA.crt").unwrap();
+            root_cert_store.add(cert_der).unwrap();
+
+            info!("using provided root ca");
+
+            rustls::ClientConfig::builder()
+                .with_root_certificates(root_cert_store)
+                .with_no_client_auth()
+        }
+        false => {
+            info!("defaulting to os native certs");
+            rustls_platform_verifier::tls_config()
+        }
+    };
+
+    let connector = Connector::Rustls(Arc::new(tls_config));
+
+    let (ws_stream, _) = connect_async_tls_with_config(url, None, false, Some(connector)).await?;
+
+    Ok(ws_stream)
+}
+
+/// Full jitter exponential backoff, `jitter` is a random number picking a delay below the cap.
+fn backoff_delay(attempt: u32, jitter: u64) -> Duration {
+    let cap = RECONNECT_BASE_DELAY
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_DELAY);
+
+    Duration::from_millis(jitter % (cap.as_millis() as u64 + 1))
+}
+
+/// Reconnect to the homeserver, the new sink replaces the dead one in `ws_sender` so everyone
+/// holding it keeps working. It writes the legacy format again until the homeserver answered
+/// the login. `None` once all attempts failed.
+async fn reconnect<H: Host>(app_handle: &H, ws_sender: &WsSender) -> Option<SplitStream<WsStream>> {
+    let url = HOMESERVER.lock().await.clone();
+
+    for attempt in 0..MAX_RECONNECT_ATTEMPTS {
+        let delay = backoff_delay(attempt, OsRng.next_u64());
+        app_handle.emit_or_log(
+            "reconnecting",
+            Reconnecting {
+                attempt: attempt + 1,
+                delay_ms: delay.as_millis() as u64,
+            },
+        );
+        tokio::time::sleep(delay).await;
+
+        match connect(url.clone()).await {
+            Ok(ws_stream) => {
+                info!("reconnected to {}", url);
+                let (sink, stream) = ws_stream.split();
+                *ws_sender.lock().await = PacketSink::new(sink);
+                return Some(stream);
+            }
+            Err(e) => info!("reconnect attempt {} failed: {}", attempt + 1, e),
+        }
+    }
+
+    error!("giving up on {}", url);
+    None
+}
+
+/// Authenticate again and pick up what was in flight when the connection dropped, the outbox is
+/// replayed once the server confirms the login.
+async fn resume(
+    ws_sender: &WsSender,
+    resume_auth: &Mutex<Option<AuthRequest>>,
+    pending_bundles: &Mutex<HashSet<String>>,
+) -> Result<(), util::Error> {
+    let auth = resume_auth.lock().await.clone();
+    let Some(auth) = auth else {
+        // nobody logged in, nothing to resume
+        return Ok(());
+    };
 
-    let mut nonce = vec![0; Aes256Gcm::NONCE_LEN];
-    OsRng.fill_bytes(&mut nonce);
+    let mut ws_sender = ws_sender.lock().await;
+    ws_sender.send_packet(&Packet::AuthRequest(auth)).await?;
 
-    let cipher = Aes256Gcm::new(&sk);
+    let pending = pending_bundles.lock().await.clone();
+    for contact in pending {
+        let request = Packet::BundleRequest(BundleRequest { user: contact });
+        ws_sender.send_packet(&request).await?;
+    }
 
-    let msg_content = msg.content.as_mut().unwrap();
-    let cleartext = msg_content.clone().cleartext.unwrap();
+    Ok(())
+}
 
-    let ciphertext = cipher.encrypt(&nonce, cleartext.as_bytes(), None).unwrap();
+/// Keep what is needed to authenticate after a reconnect. A session token in the login
+/// response replaces the password, otherwise the login is repeated.
+async fn remember_login(resume_auth: &Mutex<Option<AuthRequest>>, response: &AuthResponse) {
+    let mut resume_auth = resume_auth.lock().await;
+    let Some(stored) = resume_auth.as_mut() else {
+        return;
+    };
 
+    stored.keybundle = None;
+    if let Some(token) = &response.token {
+        stored.action = AuthAction::Resume;
+        stored.password = SecretString::default();
+        stored.token = Some(token.clone());
+    } else if stored.action == AuthAction::Register {
+        stored.action = AuthAction::Login;
+    }
+}
+
+/// The auth request the frontend sends in the legacy format.
+fn auth_request(auth: MsgPayload) -> Result<AuthRequest, util::Error> {
+    match Packet::from_legacy(auth)? {
+        Packet::AuthRequest(request) => Ok(request),
+        packet => Err(util::Error::CustomError(format!(
+            "not an auth request: {}",
+            packet.kind()
+        ))),
+    }
+}
+
+/// Act on a decrypted control message. A manifest is answered with the first acknowledgement,
+/// an acknowledgement with the next chunks.
+async fn handle_control<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    msg: &MsgPayload,
+    envelope: Envelope,
+) -> Result<(), util::Error> {
+    match envelope {
+        Envelope::Manifest(manifest) => {
+            let ack = transfer::accept(app_handle, msg, manifest).await?;
+            let ack = transfer::ack_msg(&msg.recipient, &msg.author, &ack);
+            let payload = encrypt_msg(app_handle, ack).await?;
+            ws_sender.lock().await.send_packet(&payload).await?;
+        }
+        Envelope::TransferAck(ack) => {
+            let (progress, frames) = transfer::next_window(app_handle, msg, &ack).await?;
+            app_handle.emit_or_log("transfer_progress", progress);
+
+            let mut ws_sender = ws_sender.lock().await;
+            for frame in frames {
+                ws_sender.send(Message::binary(frame)).await?;
+            }
+        }
+        Envelope::Read(receipt) => {
+            // with receipts turned off none are sent and none are shown
+            if load_settings(app_handle, &msg.recipient)
+                .await?
+                .read_receipts
+            {
+                let read = MsgRead {
+                    contact: msg.author.clone(),
+                    message_ids: receipt.message_ids,
+                };
+                app_handle.emit_or_log("msg_read", read);
+            }
+        }
+        Envelope::Typing(typing) => {
+            if unix_time().saturating_sub(msg.timestamp) <= TYPING_MAX_AGE_SECS {
+                let state = TypingState {
+                    contact: msg.author.clone(),
+                    typing: typing.typing,
+                };
+                app_handle.emit_or_log("typing", state);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_chunk<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    header: &MsgPayload,
+    index: u32,
+    ciphertext: &[u8],
+) -> Result<(), util::Error> {
+    let ack = match transfer::receive_chunk(app_handle, header, index, ciphertext).await? {
+        Received::Ignored => None,
+        Received::Stored(progress, ack) => {
+            app_handle.emit_or_log("transfer_progress", progress);
+            ack
+        }
+        Received::Complete(msg, ack) => {
+            send_receipt(ws_sender, &msg).await;
+            app_handle.emit_or_log("msg", *msg);
+            Some(ack)
+        }
+    };
+
+    if let Some(ack) = ack {
+        let ack = transfer::ack_msg(&header.recipient, &header.author, &ack);
+        let payload = encrypt_msg(app_handle, ack).await?;
+        ws_sender.lock().await.send_packet(&payload).await?;
+    }
+
+    Ok(())
+}
+
+/// Send what gets the transfers of `user` going again, those without a session wait for the
+/// next login.
+async fn resume_transfers<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    user: &str,
+) -> Result<(), util::Error> {
+    for msg in transfer::resume_msgs(app_handle, user).await? {
+        match encrypt_msg(app_handle, msg.clone()).await {
+            Ok(payload) => ws_sender.lock().await.send_packet(&payload).await?,
+            Err(e) => info!("transfer {} stays paused: {}", msg.message_id, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Unlock the vault of an accepted login, the keys of an accepted registration are stored.
+async fn commit_login<H: Host>(app_handle: &H, user: &str) -> Result<(), util::Error> {
+    vault::commit(app_handle, user).await?;
+    x3dh::commit_bundle(app_handle, user).await
+}
+
+/// Encrypt and send what the outbox holds for `user`, only the messages to `contact` if given.
+/// Recipients without a session get their bundle requested, messages held for a changed
+/// identity key stay queued.
+async fn flush_queue<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    pending_bundles: &Mutex<HashSet<String>>,
+    user: &str,
+    contact: Option<&str>,
+) -> Result<(), util::Error> {
+    let _sending = OUTBOX_LOCK.lock().await;
+
+    let pending = outbox::pending(app_handle, user).await?;
+    for pending in pending
+        .into_iter()
+        .filter(|pending| contact.is_none_or(|contact| contact == pending.msg.recipient))
+    {
+        let msg = pending.msg.clone();
+        if load_session(app_handle, user, &msg.recipient)
+            .await?
+            .is_none()
+        {
+            // nobody asked for the bundle yet after a restart
+            request_bundle(ws_sender, pending_bundles, msg.recipient.clone()).await?;
+            outbox::set_state(
+                app_handle,
+                user,
+                &msg.message_id,
+                MsgState::AwaitingBundle,
+                None,
+            )
+            .await?;
+            continue;
+        }
+
+        match encrypt_pending(app_handle, ws_sender, pending).await {
+            Ok(payload) => {
+                let awaiting_ack = ws_sender.lock().await.acks();
+                if let Err(e) = ws_sender.lock().await.send(payload).await {
+                    // the rest goes out after the next reconnect
+                    info!("holding outbox of {}: {}", user, e);
+                    return Ok(());
+                }
+                outbox::set_sent(app_handle, user, &msg.message_id, awaiting_ack).await?;
+            }
+            Err(util::Error::IdentityChanged(contact)) => {
+                info!("holding msg to {} until the new key is accepted", contact);
+            }
+            Err(e) => {
+                error!("could not encrypt msg to {}: {}", msg.recipient, e);
+                outbox::set_state(
+                    app_handle,
+                    user,
+                    &msg.message_id,
+                    MsgState::Failed,
+                    Some(e.to_string()),
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Tell the author of `msg` it arrived, the homeserver relays the receipt like a message.
+async fn send_receipt(ws_sender: &WsSender, msg: &MsgPayload) {
+    let receipt = Packet::Delivered(Delivered {
+        message_id: msg.message_id.clone(),
+        author: msg.recipient.clone(),
+        recipient: msg.author.clone(),
+    });
+
+    if let Err(e) = ws_sender.lock().await.send_packet(&receipt).await {
+        info!("could not confirm delivery of {}: {}", msg.message_id, e);
+    }
+}
+
+/// Whether a typing notification has to go out, given the last one sent and when.
+#[cfg(any(feature = "gui", test))]
+fn typing_due(last: Option<(bool, Instant)>, typing: bool, now: Instant) -> bool {
+    match last {
+        // nothing to stop before anything was started
+        None => typing,
+        Some((last_typing, at)) if last_typing == typing => {
+            typing && now.duration_since(at) >= TYPING_REFRESH
+        }
+        // stopping is always sent, so no indicator is left hanging
+        Some((_, at)) => !typing || now.duration_since(at) >= TYPING_MIN_INTERVAL,
+    }
+}
+
+/// Whether `msg` arrived for the first time, a copy sent by a retry is only confirmed again.
+async fn first_delivery(seen: &Mutex<VecDeque<String>>, msg: &MsgPayload) -> bool {
+    let id = format!("{}/{}", msg.author, msg.message_id);
+
+    let mut seen = seen.lock().await;
+    if seen.contains(&id) {
+        return false;
+    }
+    if seen.len() == SEEN_IDS {
+        seen.pop_front();
+    }
+    seen.push_back(id);
+    true
+}
+
+/// Ask for the bundle of `contact` unless a request is on its way already, its response flushes
+/// everything queued for the contact.
+async fn request_bundle(
+    ws_sender: &WsSender,
+    pending_bundles: &Mutex<HashSet<String>>,
+    contact: String,
+) -> Result<(), util::Error> {
+    if !pending_bundles.lock().await.insert(contact.clone()) {
+        info!("bundle of {} already requested", contact);
+        return Ok(());
+    }
+
+    info!("requesting the bundle of {}", contact);
+    let request = Packet::BundleRequest(BundleRequest { user: contact });
+
+    ws_sender.lock().await.send_packet(&request).await?;
+
+    Ok(())
+}
+
+/// Periodically rotate the signed prekey of whoever is logged in.
+async fn rotation_loop<H: Host>(
+    app_handle: H,
+    ws_sender: WsSender,
+    user: Arc<Mutex<Option<String>>>,
+) {
+    let mut interval = tokio::time::interval(PREKEY_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let current = user.lock().await.clone();
+        if let Some(current) = current {
+            if let Err(e) = publish_rotated_prekey(&app_handle, &ws_sender, &current).await {
+                error!("prekey rotation failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Send again what the homeserver did not acknowledge in time, until `MAX_SEND_ATTEMPTS`.
+async fn retry_loop<H: Host>(app_handle: H, ws_sender: WsSender, user: Arc<Mutex<Option<String>>>) {
+    let mut interval = tokio::time::interval(ACK_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // a homeserver that answers in the legacy format never acks, nothing would ever count
+        // as acknowledged
+        if !ws_sender.lock().await.acks() {
+            continue;
+        }
+
+        let current = user.lock().await.clone();
+        if let Some(current) = current {
+            if let Err(e) = retry_unacked(&app_handle, &ws_sender, &current).await {
+                error!("could not retry unacknowledged messages: {}", e);
+            }
+        }
+    }
+}
+
+async fn retry_unacked<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    user: &str,
+) -> Result<(), util::Error> {
+    let _sending = OUTBOX_LOCK.lock().await;
+
+    let due =
+        outbox::due_for_retry(app_handle, user, ACK_TIMEOUT.as_secs(), MAX_SEND_ATTEMPTS).await?;
+    for pending in due {
+        let msg = pending.msg.clone();
+        info!("resending {}", msg.message_id);
+        let payload = match encrypt_pending(app_handle, ws_sender, pending).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                // one message that cannot be encrypted does not hold up the others
+                let error = Some(e.to_string());
+                outbox::set_state(app_handle, user, &msg.message_id, MsgState::Failed, error)
+                    .await?;
+                continue;
+            }
+        };
+        let awaiting_ack = ws_sender.lock().await.acks();
+        if let Err(e) = ws_sender.lock().await.send(payload).await {
+            // tried again once reconnected
+            info!("could not resend {}: {}", msg.message_id, e);
+            return Ok(());
+        }
+        outbox::set_sent(app_handle, user, &msg.message_id, awaiting_ack).await?;
+    }
+
+    Ok(())
+}
+
+/// How long the receive loop waits for the next pong.
+async fn pong_timeout() -> Duration {
+    Duration::from_secs(HEARTBEAT_CONFIG.lock().await.pong_timeout_secs)
+}
+
+/// Ping the homeserver, the payload is the send time to measure the round trip with the pong.
+async fn heartbeat_loop(ws_sender: WsSender) {
+    loop {
+        let interval = Duration::from_secs(HEARTBEAT_CONFIG.lock().await.ping_interval_secs);
+        tokio::time::sleep(interval).await;
+
+        let ping = Message::Ping(unix_time_millis().to_be_bytes().to_vec());
+        if let Err(e) = ws_sender.lock().await.send(ping).await {
+            // the receive loop notices the dead connection and reconnects
+            info!("could not send ping: {}", e);
+        }
+    }
+}
+
+/// Number of one-time keys to upload when the homeserver has `count` left, `None` while it
+/// has enough.
+fn otk_refill(count: usize, config: &PrekeyConfig) -> Option<usize> {
+    (count < config.otk_threshold).then_some(config.otk_batch_size)
+}
+
+/// Rotate the signed prekey of `user` if it is due and publish the new one.
+async fn publish_rotated_prekey<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    user: &str,
+) -> Result<(), util::Error> {
+    let config = PREKEY_CONFIG.lock().await.clone();
+
+    if let Some(bundle) = rotate_signed_prekey(app_handle, user, &config).await? {
+        let rotation = Packet::RotatePrekey(PrekeyUpload {
+            user: user.to_string(),
+            keybundle: bundle,
+        });
+        ws_sender.lock().await.send_packet(&rotation).await?;
+        info!("published rotated signed prekey");
+    }
+
+    Ok(())
+}
+
+/// Ratchet encrypt `plaintext` for the recipient of `msg`, nonce and header are set on `msg`.
+async fn encrypt_payload<H: Host>(
+    app_handle: &H,
+    msg: &mut MsgPayload,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, util::Error> {
+    ensure_identity_trusted(app_handle, &msg.author, &msg.recipient).await?;
+
+    let mut session = load_session(app_handle, &msg.author, &msg.recipient)
+        .await?
+        .ok_or(util::Error::CustomError(format!(
+            "no session with {}",
+            msg.recipient
+        )))?;
+
+    let ad = msg.associated_data();
+    let (header, nonce, ciphertext) = session.encrypt(plaintext, &ad)?;
+
+    // persist the advanced chain before anything leaves the device
+    save_session(app_handle, &msg.author, &msg.recipient, &session).await?;
+
+    let msg_content = msg.content.get_or_insert(MsgContent {
+        ciphertext: "".to_string(),
+        nonce: "".to_string(),
+        cleartext: None,
+        header: None,
+    });
     msg_content.cleartext = None;
-    msg_content.ciphertext = BASE64_STANDARD.encode(ciphertext);
     msg_content.nonce = BASE64_STANDARD.encode(nonce);
+    msg_content.header = Some(header);
+
+    Ok(ciphertext)
+}
+
+/// Ratchet decrypt `ciphertext` sent by the author of `msg`.
+async fn decrypt_payload<H: Host>(
+    app_handle: &H,
+    msg: &MsgPayload,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, util::Error> {
+    let mut session = load_session(app_handle, &msg.recipient, &msg.author)
+        .await?
+        .ok_or(util::Error::CustomError(format!(
+            "no session with {}",
+            msg.author
+        )))?;
+
+    let msg_content = msg
+        .content
+        .as_ref()
+        .ok_or(util::Error::CustomError("msg without content".to_string()))?;
+    let header = msg_content.header.as_ref().ok_or(util::Error::CustomError(
+        "msg without ratchet header".to_string(),
+    ))?;
+
+    let nonce = BASE64_STANDARD.decode(&msg_content.nonce)?;
+
+    // fails for ciphertexts that were moved to another sender, recipient, id or time
+    let plaintext = session.decrypt(header, &nonce, ciphertext, &msg.associated_data())?;
+
+    save_session(app_handle, &msg.recipient, &msg.author, &session).await?;
+
+    Ok(plaintext)
+}
+
+async fn encrypt_msg<H: Host>(app_handle: &H, mut msg: MsgPayload) -> Result<Packet, util::Error> {
+    let cleartext = msg.content.as_ref().unwrap().clone().cleartext.unwrap();
+
+    let ciphertext = encrypt_payload(app_handle, &mut msg, cleartext.as_bytes()).await?;
+    msg.content.as_mut().unwrap().ciphertext = BASE64_STANDARD.encode(ciphertext);
+
+    Ok(Packet::Chat(msg))
+}
+
+async fn decrypt_msg<H: Host>(app_handle: &H, msg: &mut MsgPayload) -> Result<(), util::Error> {
+    let ciphertext = match msg.content.as_ref() {
+        Some(content) => BASE64_STANDARD.decode(&content.ciphertext)?,
+        None => return Err(util::Error::CustomError("msg without content".to_string())),
+    };
+
+    let cleartext = decrypt_payload(app_handle, msg, &ciphertext).await?;
+
+    msg.content.as_mut().unwrap().cleartext =
+        Some(String::from_utf8(cleartext).map_err(|e| e.utf8_error())?);
+
+    Ok(())
+}
+
+/// Encrypt a message from the outbox in the format the homeserver reads, an attachment into a
+/// binary frame.
+async fn encrypt_pending<H: Host>(
+    app_handle: &H,
+    ws_sender: &WsSender,
+    pending: Pending,
+) -> Result<Message, util::Error> {
+    if let Some(attachment) = pending.attachment {
+        let data = &attachment.data;
+        return encrypt_attachment(app_handle, pending.msg, &attachment.mime_type, data).await;
+    }
+
+    let packet = encrypt_msg(app_handle, pending.msg).await?;
+    let format = ws_sender.lock().await.format;
+    Ok(Message::text(protocol::encode(&packet, format)?))
+}
+
+/// Encrypt an attachment into a binary frame, `msg` carries the routing metadata only.
+async fn encrypt_attachment<H: Host>(
+    app_handle: &H,
+    mut msg: MsgPayload,
+    mime_type: &str,
+    data: &[u8],
+) -> Result<Message, util::Error> {
+    let body = SecretBytes::from(frame::encode_attachment(mime_type, data));
+    let ciphertext = encrypt_payload(app_handle, &mut msg, &body).await?;
+
+    Ok(Message::binary(frame::encode(&msg, &ciphertext)?))
+}
+
+/// Decrypt an attachment frame into the same shape as a text message, the cleartext is
+/// `{"data": <base64>, "mime_type": ..}` like the frontend sends it.
+async fn decrypt_attachment<H: Host>(
+    app_handle: &H,
+    msg: &mut MsgPayload,
+    ciphertext: &[u8],
+) -> Result<(), util::Error> {
+    let body = SecretBytes::from(decrypt_payload(app_handle, msg, ciphertext).await?);
+    let (mime_type, data) = frame::decode_attachment(&body)?;
+
+    let cleartext = serde_json::json!({
+        "data": BASE64_STANDARD.encode(data),
+        "mime_type": mime_type,
+    });
+    msg.content.as_mut().unwrap().cleartext = Some(cleartext.to_string());
+
+    Ok(())
+}
+
+#[test]
+fn check_otk_refill() {
+    let config = PrekeyConfig {
+        otk_threshold: 20,
+        otk_batch_size: 100,
+        ..Default::default()
+    };
+
+    assert_eq!(otk_refill(0, &config), Some(100));
+    assert_eq!(otk_refill(19, &config), Some(100));
+    assert_eq!(otk_refill(20, &config), None);
+    assert_eq!(otk_refill(500, &config), None);
+}
+
+#[test]
+fn check_backoff_delay() {
+    // the jitter picks a delay between zero and the cap, which doubles up to the maximum
+    assert_eq!(backoff_delay(0, 501), Duration::from_millis(0));
+    assert_eq!(backoff_delay(0, 500), RECONNECT_BASE_DELAY);
+    assert_eq!(backoff_delay(3, 4000), Duration::from_secs(4));
+    assert_eq!(backoff_delay(30, 30_000), RECONNECT_MAX_DELAY);
+
+    for attempt in 0..MAX_RECONNECT_ATTEMPTS {
+        assert!(backoff_delay(attempt, OsRng.next_u64()) <= RECONNECT_MAX_DELAY);
+    }
+}
+
+#[test]
+fn check_typing_rate_limit() {
+    let start = Instant::now();
+    let soon = start + Duration::from_millis(200);
+
+    assert!(typing_due(None, true, start));
+    assert!(!typing_due(None, false, start));
 
-    let json = serde_json::to_string(&msg)?;
-    let payload = Message::text(json);
+    // repeated keystrokes only refresh the indicator now and then
+    assert!(!typing_due(Some((true, start)), true, soon));
+    assert!(typing_due(
+        Some((true, start)),
+        true,
+        start + TYPING_REFRESH
+    ));
+    assert!(!typing_due(
+        Some((false, start)),
+        false,
+        start + TYPING_REFRESH
+    ));
 
-    Ok(payload)
+    // stopping goes out right away, starting again only after a pause
+    assert!(typing_due(Some((true, start)), false, soon));
+    assert!(!typing_due(Some((false, start)), true, soon));
+    assert!(typing_due(
+        Some((false, start)),
+        true,
+        start + TYPING_MIN_INTERVAL
+    ));
 }
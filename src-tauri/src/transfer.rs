@@ -0,0 +1,479 @@
+//! Chunked, resumable file transfer.
+//!
+//! A file is split into chunks of `CHUNK_SIZE` bytes, each sealed with
+//! AES-256-GCM under a random key of that file. Key, size, SHA-512 and chunk
+//! count travel in a manifest through the ratchet like any other message, the
+//! chunks go out as binary frames. The receiver acknowledges every
+//! `ACK_WINDOW` chunks and the sender only sends the window after an
+//! acknowledgement, so after a disconnect or restart the receiver acknowledges
+//! again and the transfer continues from the last chunk it stored.
+//!
+//! State of both ends lives sealed in `{user}/transfers.bin`, chunks are
+//! written into a part file that is moved next to the other attachments once
+//! the hash matches.
+
+use std::path::{Path, PathBuf};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cipher_chat_protocol::SecretBytes;
+#[cfg(any(feature = "gui", test))]
+use cipher_chat_protocol::SecretString;
+use cryptimitives::{aead::aes_gcm::Aes256Gcm, errors::AeadError, hash::sha512::Hash};
+use cryptraits::{aead::Aead, hash::Hash as _};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+
+use crate::{
+    envelope::Envelope,
+    frame,
+    host::Host,
+    util::{get_store_path, Manifest, MsgPayload, TransferAck, TransferProgress},
+    vault, Error,
+};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks sent per acknowledgement.
+pub const ACK_WINDOW: u32 = 16;
+
+#[cfg(any(feature = "gui", test))]
+const KEY_LEN: usize = 32;
+
+const TRANSFERS: &str = "transfers.bin";
+
+/// Transfer errors.
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// Chunk or acknowledgement for a transfer without a manifest.
+    #[error("unknown transfer {0}")]
+    Unknown(String),
+
+    /// Manifest with an id that can not be used as a file name or that contradicts itself.
+    #[error("invalid manifest for transfer {0}")]
+    InvalidManifest(String),
+
+    /// All chunks arrived but the file is not the one announced.
+    #[error("file of transfer {0} does not match its hash")]
+    HashMismatch(String),
+
+    /// Error occurred in the underlying AEAD cipher, a chunk was modified or moved.
+    #[error("{0:?}")]
+    AeadError(AeadError),
+}
+
+impl From<AeadError> for TransferError {
+    fn from(e: AeadError) -> Self {
+        Self::AeadError(e)
+    }
+}
+
+/// Sender side, kept until every chunk is acknowledged.
+#[derive(Serialize, Deserialize)]
+struct Outgoing {
+    path: String,
+    /// Manifest message, sent again after a login to make the receiver acknowledge.
+    msg: MsgPayload,
+    manifest: Manifest,
+}
+
+/// Receiver side, kept after completion so a repeated manifest is only acknowledged.
+#[derive(Serialize, Deserialize)]
+struct Incoming {
+    /// Manifest message without its cleartext, emitted as `msg` once complete.
+    msg: MsgPayload,
+    manifest: Manifest,
+    received: u32,
+}
+
+/// What became of a received chunk.
+pub enum Received {
+    /// Already stored or ahead of a missing one, the sender repeats it after the next ack.
+    Ignored,
+    /// Stored, the ack is set whenever a window is complete.
+    Stored(TransferProgress, Option<TransferAck>),
+    /// Last chunk stored and the hash matches, the message points to the file.
+    Complete(Box<MsgPayload>, TransferAck),
+}
+
+/// Announces the file at `path` in `msg` and remembers the transfer until it is acknowledged.
+#[cfg(any(feature = "gui", test))]
+pub async fn start<H: Host>(
+    app_handle: &H,
+    msg: &mut MsgPayload,
+    path: &str,
+    mime_type: &str,
+) -> Result<(), Error> {
+    let (size, hash) = file_hash(Path::new(path)).await?;
+
+    let mut key = SecretBytes::from(vec![0; KEY_LEN]);
+    OsRng.fill_bytes(&mut key);
+
+    let manifest = Manifest {
+        transfer_id: msg.message_id.clone(),
+        mime_type: mime_type.to_string(),
+        size,
+        hash: BASE64_STANDARD.encode(hash),
+        chunk_count: chunk_count(size),
+        key: SecretString::encode(&key),
+    };
+    info!(
+        "starting transfer {} of {} chunks",
+        manifest.transfer_id, manifest.chunk_count
+    );
+
+    Envelope::Manifest(manifest.clone()).write_into(msg);
+
+    let outgoing = Outgoing {
+        path: path.to_string(),
+        msg: msg.clone(),
+        manifest,
+    };
+    vault::store(
+        app_handle,
+        &msg.author,
+        TRANSFERS,
+        &outgoing_key(&msg.recipient, &msg.message_id),
+        &outgoing,
+    )
+    .await
+}
+
+pub fn ack_msg(user: &str, contact: &str, ack: &TransferAck) -> MsgPayload {
+    let message_id = format!("{}-ack-{}", ack.transfer_id, ack.received);
+    Envelope::TransferAck(ack.clone()).into_msg(message_id, user, contact)
+}
+
+/// Starts receiving the transfer announced in `msg`, a repeated manifest is only acknowledged.
+pub async fn accept<H: Host>(
+    app_handle: &H,
+    msg: &MsgPayload,
+    manifest: Manifest,
+) -> Result<TransferAck, Error> {
+    let valid_id = !manifest.transfer_id.is_empty()
+        && manifest
+            .transfer_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid_id
+        || manifest.transfer_id != msg.message_id
+        || manifest.chunk_count != chunk_count(manifest.size)
+    {
+        return Err(TransferError::InvalidManifest(msg.message_id.clone()).into());
+    }
+
+    let user = &msg.recipient;
+    let key = incoming_key(&msg.author, &manifest.transfer_id);
+
+    let received = match vault::load::<_, Incoming>(app_handle, user, TRANSFERS, &key).await? {
+        Some(incoming) => incoming.received,
+        None => {
+            let part = part_path(app_handle, user, &manifest.transfer_id).await?;
+            if let Some(dir) = part.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            File::create(&part).await?;
+
+            let mut msg = msg.clone();
+            if let Some(content) = msg.content.as_mut() {
+                content.cleartext = None;
+            }
+            let incoming = Incoming {
+                msg,
+                manifest: manifest.clone(),
+                received: 0,
+            };
+            vault::store(app_handle, user, TRANSFERS, &key, &incoming).await?;
+            0
+        }
+    };
+
+    Ok(TransferAck {
+        transfer_id: manifest.transfer_id,
+        received,
+    })
+}
+
+/// Stores chunk `index` of the transfer the frame `header` belongs to, chunks are taken in order.
+pub async fn receive_chunk<H: Host>(
+    app_handle: &H,
+    header: &MsgPayload,
+    index: u32,
+    ciphertext: &[u8],
+) -> Result<Received, Error> {
+    let user = &header.recipient;
+    let transfer_id = &header.message_id;
+    let key = incoming_key(&header.author, transfer_id);
+
+    let mut incoming = vault::load::<_, Incoming>(app_handle, user, TRANSFERS, &key)
+        .await?
+        .ok_or(TransferError::Unknown(transfer_id.clone()))?;
+    let total = incoming.manifest.chunk_count;
+    if index != incoming.received || index >= total {
+        return Ok(Received::Ignored);
+    }
+
+    let file_key = incoming.manifest.key.decode()?;
+    let chunk = SecretBytes::from(open_chunk(&file_key, transfer_id, index, ciphertext)?);
+
+    let part = part_path(app_handle, user, transfer_id).await?;
+    let mut file = OpenOptions::new().write(true).open(&part).await?;
+    file.seek(SeekFrom::Start(index as u64 * CHUNK_SIZE as u64))
+        .await?;
+    file.write_all(&chunk).await?;
+    file.flush().await?;
+
+    incoming.received += 1;
+    vault::store(app_handle, user, TRANSFERS, &key, &incoming).await?;
+
+    let ack = TransferAck {
+        transfer_id: transfer_id.clone(),
+        received: incoming.received,
+    };
+    if incoming.received < total {
+        let progress = TransferProgress {
+            transfer_id: transfer_id.clone(),
+            contact: header.author.clone(),
+            done: incoming.received,
+            total,
+        };
+        let ack = (incoming.received % ACK_WINDOW == 0).then_some(ack);
+        return Ok(Received::Stored(progress, ack));
+    }
+
+    let (size, hash) = file_hash(&part).await?;
+    if size != incoming.manifest.size || BASE64_STANDARD.encode(hash) != incoming.manifest.hash {
+        // a manifest sent again after the next login starts over
+        vault::remove(app_handle, user, TRANSFERS, &key).await?;
+        fs::remove_file(&part).await?;
+        return Err(TransferError::HashMismatch(transfer_id.clone()).into());
+    }
+
+    let path = file_path(app_handle, user, transfer_id).await?;
+    fs::rename(&part, &path).await?;
+    info!("transfer {} complete", transfer_id);
+
+    let mut msg = incoming.msg;
+    let cleartext = json!({
+        "data": path,
+        "mime_type": incoming.manifest.mime_type,
+        "stored": true,
+    });
+    if let Some(content) = msg.content.as_mut() {
+        content.cleartext = Some(cleartext.to_string());
+    }
+
+    Ok(Received::Complete(Box::new(msg), ack))
+}
+
+/// Chunk frames of the window after `ack` from the contact `msg` came from, none once the
+/// receiver has all of them.
+pub async fn next_window<H: Host>(
+    app_handle: &H,
+    msg: &MsgPayload,
+    ack: &TransferAck,
+) -> Result<(TransferProgress, Vec<Vec<u8>>), Error> {
+    let user = &msg.recipient;
+    let key = outgoing_key(&msg.author, &ack.transfer_id);
+
+    let outgoing = vault::load::<_, Outgoing>(app_handle, user, TRANSFERS, &key)
+        .await?
+        .ok_or(TransferError::Unknown(ack.transfer_id.clone()))?;
+    let total = outgoing.manifest.chunk_count;
+    let progress = TransferProgress {
+        transfer_id: ack.transfer_id.clone(),
+        contact: msg.author.clone(),
+        done: ack.received.min(total),
+        total,
+    };
+
+    if ack.received >= total {
+        info!("transfer {} acknowledged", ack.transfer_id);
+        vault::remove(app_handle, user, TRANSFERS, &key).await?;
+        return Ok((progress, Vec::new()));
+    }
+
+    let file_key = outgoing.manifest.key.decode()?;
+    let mut file = File::open(&outgoing.path).await?;
+    file.seek(SeekFrom::Start(ack.received as u64 * CHUNK_SIZE as u64))
+        .await?;
+
+    let header = MsgPayload {
+        content: None,
+        timestamp: outgoing.msg.timestamp,
+        auth: None,
+        message_id: ack.transfer_id.clone(),
+        author: user.clone(),
+        recipient: msg.author.clone(),
+        transient: false,
+    };
+
+    let mut frames = Vec::new();
+    let mut chunk = SecretBytes::from(vec![0; CHUNK_SIZE]);
+    for index in ack.received..total.min(ack.received + ACK_WINDOW) {
+        let n = read_chunk(&mut file, &mut chunk).await?;
+        let ciphertext = seal_chunk(&file_key, &ack.transfer_id, index, &chunk[..n])?;
+        frames.push(frame::encode_chunk(&header, index, &ciphertext)?);
+    }
+
+    Ok((progress, frames))
+}
+
+/// Messages that get transfers of `user` going again after a login. Manifests of outgoing
+/// transfers make the receiver acknowledge, unfinished incoming ones are acknowledged.
+pub async fn resume_msgs<H: Host>(app_handle: &H, user: &str) -> Result<Vec<MsgPayload>, Error> {
+    let mut msgs = Vec::new();
+    for (key, value) in vault::load_all::<_, Value>(app_handle, user, TRANSFERS).await? {
+        if key.starts_with("out/") {
+            let outgoing: Outgoing = serde_json::from_value(value)?;
+            msgs.push(outgoing.msg);
+        } else {
+            let incoming: Incoming = serde_json::from_value(value)?;
+            if incoming.received < incoming.manifest.chunk_count {
+                let ack = TransferAck {
+                    transfer_id: incoming.manifest.transfer_id,
+                    received: incoming.received,
+                };
+                msgs.push(ack_msg(user, &incoming.msg.author, &ack));
+            }
+        }
+    }
+
+    Ok(msgs)
+}
+
+fn chunk_count(size: u64) -> u32 {
+    size.div_ceil(CHUNK_SIZE as u64).max(1) as u32
+}
+
+/// Binds a chunk to its transfer and position, chunks can not be reordered.
+fn chunk_ad(transfer_id: &str, index: u32) -> Vec<u8> {
+    let mut ad = transfer_id.as_bytes().to_vec();
+    ad.extend(index.to_be_bytes());
+    ad
+}
+
+fn seal_chunk(
+    key: &[u8],
+    transfer_id: &str,
+    index: u32,
+    chunk: &[u8],
+) -> Result<Vec<u8>, TransferError> {
+    let mut sealed = vec![0; Aes256Gcm::NONCE_LEN];
+    OsRng.fill_bytes(&mut sealed);
+
+    let cipher = Aes256Gcm::new(key);
+    let ciphertext = cipher.encrypt(&sealed, chunk, Some(&chunk_ad(transfer_id, index)))?;
+
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn open_chunk(
+    key: &[u8],
+    transfer_id: &str,
+    index: u32,
+    sealed: &[u8],
+) -> Result<Vec<u8>, TransferError> {
+    if sealed.len() < Aes256Gcm::NONCE_LEN {
+        return Err(TransferError::AeadError(AeadError));
+    }
+    let (nonce, ciphertext) = sealed.split_at(Aes256Gcm::NONCE_LEN);
+
+    let cipher = Aes256Gcm::new(key);
+    Ok(cipher.decrypt(nonce, ciphertext, Some(&chunk_ad(transfer_id, index)))?)
+}
+
+/// Fills `buf` as far as the file allows, only the last chunk comes up short.
+async fn read_chunk(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+async fn file_hash(path: &Path) -> std::io::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(path).await?;
+    let mut hash = Hash::new();
+    let mut size = 0;
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = read_chunk(&mut file, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok((size, hash.finalize()))
+}
+
+/// Where the frontend keeps attachments, `{app data}/{homeserver}/{user}/{message id}`.
+async fn file_path<H: Host>(
+    app_handle: &H,
+    user: &str,
+    transfer_id: &str,
+) -> Result<PathBuf, Error> {
+    let module = format!("{}/{}", user, transfer_id);
+    Ok(app_handle.data_dir()?.join(get_store_path(&module).await))
+}
+
+async fn part_path<H: Host>(
+    app_handle: &H,
+    user: &str,
+    transfer_id: &str,
+) -> Result<PathBuf, Error> {
+    let module = format!("{}/transfers/{}.part", user, transfer_id);
+    Ok(app_handle.data_dir()?.join(get_store_path(&module).await))
+}
+
+fn outgoing_key(contact: &str, transfer_id: &str) -> String {
+    format!("out/{}/{}", contact, transfer_id)
+}
+
+fn incoming_key(contact: &str, transfer_id: &str) -> String {
+    format!("in/{}/{}", contact, transfer_id)
+}
+
+#[test]
+fn check_chunk_seal() {
+    let key = [7_u8; KEY_LEN];
+    let chunk = vec![1_u8; 100];
+
+    let sealed = seal_chunk(&key, "transfer", 3, &chunk).unwrap();
+    assert_eq!(open_chunk(&key, "transfer", 3, &sealed).unwrap(), chunk);
+
+    // a chunk replayed at another position or into another transfer
+    assert!(open_chunk(&key, "transfer", 4, &sealed).is_err());
+    assert!(open_chunk(&key, "other", 3, &sealed).is_err());
+    assert!(open_chunk(&key, "transfer", 3, &sealed[..8]).is_err());
+
+    assert_eq!(chunk_count(0), 1);
+    assert_eq!(chunk_count(CHUNK_SIZE as u64), 1);
+    assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), 2);
+
+    let ack = ack_msg(
+        "alice",
+        "bob",
+        &TransferAck {
+            transfer_id: "transfer".to_string(),
+            received: 16,
+        },
+    );
+    let Some(Envelope::TransferAck(parsed)) = Envelope::parse(&ack) else {
+        panic!("ack not recognized");
+    };
+    assert_eq!(parsed.received, 16);
+}
@@ -1,9 +1,16 @@
-use std::str::Utf8Error;
+use std::{
+    str::Utf8Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use base64::DecodeError;
+use cipher_chat_protocol::{PacketError, SecretString};
 use sha256::digest;
 
-use crate::{crypt::AesGcmErrorWrapper, HOMESERVER};
+use crate::{
+    crypt::AesGcmErrorWrapper, frame::FrameError, ratchet::RatchetError, transfer::TransferError,
+    vault::VaultError, xxxdh::XxxDhError, HOMESERVER,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -17,69 +24,133 @@ pub enum Error {
     Aes(#[from] AesGcmErrorWrapper),
     #[error(transparent)]
     Join(#[from] tokio::task::JoinError),
+    /// Boxed, a websocket error is several times larger than any other variant.
     #[error(transparent)]
-    Tung(#[from] tokio_tungstenite::tungstenite::Error),
+    Tung(Box<tokio_tungstenite::tungstenite::Error>),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    #[cfg(feature = "gui")]
+    #[error(transparent)]
+    Store(#[from] tauri_plugin_store::Error),
+    #[error(transparent)]
+    Ratchet(#[from] RatchetError),
+    #[error(transparent)]
+    XxxDh(#[from] XxxDhError),
+    #[error(transparent)]
+    Vault(#[from] VaultError),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error(transparent)]
+    Transfer(#[from] TransferError),
+    #[error(transparent)]
+    Packet(#[from] PacketError),
+    #[cfg(feature = "gui")]
+    #[error(transparent)]
+    Tauri(#[from] tauri::Error),
+
+    #[error("identity key of {0} changed, accept the change before messaging")]
+    IdentityChanged(String),
 
     #[error("An error occurred: {0}")]
     CustomError(String),
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct MsgPayload {
-    pub content: Option<MsgContent>,
-    pub timestamp: u64,
-    pub auth: Option<OpAuthPayload>,
-    pub message_id: String,
-    pub author: String,
-    pub recipient: String,
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::Tung(Box::new(e))
+    }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct KeyBundle {
-    pub identity: KeyPairB64,
-    pub prekey: KeyPairB64,
-    pub signature: KeyPairB64,
-    pub onetime_keys: Vec<KeyPairB64>,
-    pub ephemeral_key: Option<KeyPairB64>,
+pub use cipher_chat_protocol::{KeyBundle, KeyPairB64, OpAuthPayload, RetiredPrekey};
+
+pub type MsgPayload = cipher_chat_protocol::MsgPayload<MsgContent>;
+
+/// When and how many one-time prekeys get uploaded and how often the signed prekey rotates.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PrekeyConfig {
+    /// Upload a new batch once the server reports fewer keys than this.
+    pub otk_threshold: usize,
+    /// Number of one-time prekeys generated per batch (and at registration).
+    pub otk_batch_size: usize,
+    /// Age in seconds after which the signed prekey is replaced.
+    pub prekey_rotation_secs: u64,
+    /// How long in seconds a replaced signed prekey still completes x3dh initiations.
+    pub prekey_grace_secs: u64,
 }
 
-impl KeyBundle {
-    pub fn strip(&mut self) {
-        self.identity.strip();
-        self.prekey.strip();
-        self.signature.strip();
-        for otk in &mut self.onetime_keys {
-            otk.strip();
+impl Default for PrekeyConfig {
+    fn default() -> Self {
+        Self {
+            otk_threshold: 20,
+            otk_batch_size: 100,
+            prekey_rotation_secs: 7 * 24 * 60 * 60,
+            prekey_grace_secs: 3 * 24 * 60 * 60,
         }
-        match &mut self.ephemeral_key {
-            Some(v) => v.strip(),
-            None => (),
-        };
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct KeyPairB64 {
-    pub public: String,
-    pub private: Option<String>,
+/// Client side keepalive of the websocket.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Seconds between two pings sent to the homeserver.
+    pub ping_interval_secs: u64,
+    /// Seconds without a pong from the homeserver after which the connection counts as dead.
+    pub pong_timeout_secs: u64,
 }
 
-impl KeyPairB64 {
-    pub fn strip(&mut self) {
-        self.private = None;
+impl HeartbeatConfig {
+    /// A timeout that is not longer than the interval would count every connection as dead.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.ping_interval_secs == 0 || self.pong_timeout_secs == 0 {
+            return Err(Error::CustomError(
+                "heartbeat interval and timeout must not be zero".to_string(),
+            ));
+        }
+        if self.pong_timeout_secs <= self.ping_interval_secs {
+            return Err(Error::CustomError(
+                "pong timeout must be longer than the ping interval".to_string(),
+            ));
+        }
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct OpAuthPayload {
-    pub action: String,
-    pub user: String,
-    pub password: String,
-    pub keybundle: Option<KeyBundle>,
-    pub message: String,
-    pub success: Option<bool>,
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 20,
+            pong_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+    Closed,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ConnectionHealth {
+    pub state: ConnectionState,
+    /// Unix time of the last pong.
+    pub last_pong: Option<u64>,
+    /// Round trip time measured with the last pong.
+    pub rtt_ms: Option<u64>,
+}
+
+impl ConnectionHealth {
+    pub fn new(state: ConnectionState) -> Self {
+        Self {
+            state,
+            last_pong: None,
+            rtt_ms: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -87,6 +158,15 @@ pub struct MsgContent {
     pub ciphertext: String,
     pub nonce: String,
     pub cleartext: Option<String>,
+    pub header: Option<RatchetHeader>,
+}
+
+/// Double Ratchet header sent along with every encrypted message.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RatchetHeader {
+    pub dh: String,
+    pub pn: u32,
+    pub n: u32,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -95,6 +175,145 @@ pub struct ConnectionInfo {
     pub stream_type: String,
 }
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SafetyNumber {
+    pub digits: String,
+    pub qr_payload: String,
+    pub verified: bool,
+}
+
+/// Identity key first seen for a contact and whether the user compared safety numbers for it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ContactIdentity {
+    pub identity: String,
+    pub verified: bool,
+    /// A different identity key the contact presented later, set until the user accepts it.
+    #[serde(default)]
+    pub pending: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IdentityChange {
+    pub user: String,
+    pub contact: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Reconnecting {
+    pub attempt: u32,
+    pub delay_ms: u64,
+}
+
+/// Delivery state of an outgoing message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MsgState {
+    /// Waiting for the connection or for its turn in the outbox.
+    Queued,
+    /// No session with the recipient yet, the bundle was requested.
+    AwaitingBundle,
+    /// Handed to the homeserver. A legacy one never acknowledges, there the message stays here.
+    Sent,
+    /// Accepted by the homeserver.
+    Acked,
+    /// Decrypted by the recipient.
+    Delivered,
+    /// Could not be encrypted, `error` says why.
+    Failed,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MsgStatus {
+    pub message_id: String,
+    pub recipient: String,
+    pub state: MsgState,
+    /// Unix time of the last state change.
+    pub updated_at: u64,
+    pub error: Option<String>,
+}
+
+/// Ids of messages the sender of the receipt has read.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReadReceipt {
+    pub message_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MsgRead {
+    pub contact: String,
+    pub message_ids: Vec<String>,
+}
+
+/// Whether the sender started or stopped typing, never stored or queued.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Typing {
+    pub typing: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TypingState {
+    pub contact: String,
+    pub typing: bool,
+}
+
+/// Preferences of one account, kept next to its other stores.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AccountSettings {
+    /// Send read receipts and show the ones contacts send.
+    pub read_receipts: bool,
+}
+
+impl Default for AccountSettings {
+    fn default() -> Self {
+        Self {
+            read_receipts: true,
+        }
+    }
+}
+
+/// Announces a chunked file transfer, sent through the ratchet before the first chunk.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub transfer_id: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// SHA-512 of the whole file, base64.
+    pub hash: String,
+    pub chunk_count: u32,
+    /// AES-256-GCM key all chunks are sealed with, base64.
+    pub key: SecretString,
+}
+
+/// Number of chunks the receiver stored in order, the sender continues from there.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TransferAck {
+    pub transfer_id: String,
+    pub received: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub contact: String,
+    pub done: u32,
+    pub total: u32,
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 pub async fn get_store_path(module: &str) -> String {
     let identifier = HOMESERVER.lock().await.clone();
 
@@ -107,3 +326,18 @@ pub async fn get_store_path(module: &str) -> String {
 
     path
 }
+
+#[test]
+fn check_heartbeat_config() {
+    assert!(HeartbeatConfig::default().validate().is_ok());
+
+    let config = |ping_interval_secs, pong_timeout_secs| HeartbeatConfig {
+        ping_interval_secs,
+        pong_timeout_secs,
+    };
+    assert!(config(5, 6).validate().is_ok());
+    assert!(config(0, 60).validate().is_err());
+    assert!(config(20, 0).validate().is_err());
+    assert!(config(20, 20).validate().is_err());
+    assert!(config(30, 20).validate().is_err());
+}
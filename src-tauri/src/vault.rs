@@ -0,0 +1,440 @@
+//! Encryption at rest for private key material.
+//!
+//! Entries in `credentials.bin` and `{user}/secrets.bin` are sealed with
+//! AES-256-GCM under a key derived from the account passphrase with Argon2id.
+//! The key only lives in memory between login and logout, the salt and a
+//! check value to detect a wrong passphrase sit in `{user}/vault.bin`. A login
+//! derives the key right away but only unlocks the vault once the homeserver
+//! accepted it, a registration only writes its new vault then.
+//! Entries are sealed one by one because `credentials.bin` is shared by all
+//! accounts on this device.
+
+use std::collections::HashMap;
+
+use argon2::Argon2;
+use base64::{prelude::BASE64_STANDARD, DecodeError, Engine};
+use cipher_chat_protocol::SecretBytes;
+use cryptimitives::aead::aes_gcm::Aes256Gcm;
+use cryptimitives::errors::AeadError;
+use cryptraits::aead::Aead;
+use rand_core::{OsRng, RngCore};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::{
+    host::{Host, KeyValueStore},
+    util::get_store_path,
+};
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Sealed into `vault.bin` to tell a wrong passphrase apart from a damaged entry.
+const CHECK_VALUE: &str = "CipherChatVault";
+
+lazy_static::lazy_static! {
+  /// Unlocked vault keys by username.
+  static ref VAULT_KEYS: Mutex<HashMap<String, SecretBytes>> = Mutex::new(HashMap::new());
+  /// Keys of logins the homeserver did not answer yet, by username.
+  static ref STAGED_KEYS: Mutex<HashMap<String, Staged>> = Mutex::new(HashMap::new());
+}
+
+/// Key of a login, see [`unlock`] and [`commit`].
+struct Staged {
+    key: SecretBytes,
+    /// Salt of an account without vault, the vault is only written once the login is accepted.
+    new_salt: Option<Vec<u8>>,
+}
+
+/// Vault errors.
+#[derive(Debug, Error)]
+pub enum VaultError {
+    /// The account did not log in yet, so there is no key to seal or open entries with.
+    #[error("vault of {0} is locked")]
+    Locked(String),
+
+    /// The passphrase does not open the vault check value.
+    #[error("wrong passphrase")]
+    WrongPassphrase,
+
+    /// Error occurred in the passphrase KDF.
+    #[error("{0}")]
+    Argon2(argon2::Error),
+
+    /// Sealed entry contained invalid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// A plain text entry outside the migration of entries from before the vault.
+    #[error("entry is not sealed")]
+    NotSealed,
+
+    /// Sealed entry was not valid JSON after decryption.
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+
+    /// Error occurred in the underlying AEAD cipher, a sealed entry was modified or moved.
+    #[error("{0:?}")]
+    AeadError(AeadError),
+}
+
+impl From<argon2::Error> for VaultError {
+    fn from(e: argon2::Error) -> Self {
+        Self::Argon2(e)
+    }
+}
+
+impl From<AeadError> for VaultError {
+    fn from(e: AeadError) -> Self {
+        Self::AeadError(e)
+    }
+}
+
+/// `Result` specialized for vault operations.
+pub type VaultResult<T> = Result<T, VaultError>;
+
+/// Argon2id with the crates default cost parameters.
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> VaultResult<SecretBytes> {
+    let mut key = SecretBytes::from(vec![0; KEY_LEN]);
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+    Ok(key)
+}
+
+/// Encrypts `value`, `ad` names the entry so a sealed value can not be moved to another one.
+pub fn seal_with(key: &[u8], ad: &str, value: &Value) -> VaultResult<Value> {
+    let mut nonce = vec![0; Aes256Gcm::NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = Aes256Gcm::new(key);
+    let plaintext = SecretBytes::from(serde_json::to_vec(value)?);
+    let ciphertext = cipher.encrypt(&nonce, &plaintext, Some(ad.as_bytes()))?;
+
+    nonce.extend(ciphertext);
+    Ok(json!({ "sealed": BASE64_STANDARD.encode(nonce) }))
+}
+
+/// Decrypts a value from [`seal_with`]. Entries written before the vault existed are sealed on
+/// unlock, see `seal_legacy_entries`, a plain text entry after that is refused.
+pub fn open_with(key: &[u8], ad: &str, value: Value) -> VaultResult<Value> {
+    let sealed = BASE64_STANDARD.decode(sealed_data(&value).ok_or(VaultError::NotSealed)?)?;
+
+    if sealed.len() < Aes256Gcm::NONCE_LEN {
+        return Err(VaultError::AeadError(AeadError));
+    }
+    let (nonce, ciphertext) = sealed.split_at(Aes256Gcm::NONCE_LEN);
+
+    let cipher = Aes256Gcm::new(key);
+    let plaintext = SecretBytes::from(cipher.decrypt(nonce, ciphertext, Some(ad.as_bytes()))?);
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+fn sealed_data(value: &Value) -> Option<&str> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => object.get("sealed")?.as_str(),
+        _ => None,
+    }
+}
+
+/// Derives the key of `user` and checks the passphrase, the vault stays locked until the
+/// homeserver accepted the login and [`commit`] is called. A vault is created on first use.
+pub async fn unlock<H: Host>(
+    app_handle: &H,
+    user: &str,
+    passphrase: &str,
+) -> Result<(), crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/vault.bin", user)).await)?;
+
+    let staged = match store
+        .get("salt")
+        .and_then(|v| v.as_str().map(str::to_string))
+    {
+        Some(salt) => {
+            let key = derive_key(passphrase, &BASE64_STANDARD.decode(salt)?)?;
+            let check = store
+                .get("check")
+                .ok_or(VaultError::WrongPassphrase)
+                .and_then(|check| open_with(&key, "vault", check))
+                .map_err(|_| VaultError::WrongPassphrase)?;
+            if check != json!(CHECK_VALUE) {
+                return Err(VaultError::WrongPassphrase.into());
+            }
+
+            Staged {
+                key,
+                new_salt: None,
+            }
+        }
+        None => {
+            let salt = new_salt();
+            Staged {
+                key: derive_key(passphrase, &salt)?,
+                new_salt: Some(salt),
+            }
+        }
+    };
+
+    STAGED_KEYS.lock().await.insert(user.to_string(), staged);
+    Ok(())
+}
+
+/// Unlocks the vault of `user` with the key of its login, once the homeserver accepted it.
+pub async fn commit<H: Host>(app_handle: &H, user: &str) -> Result<(), crate::Error> {
+    let Some(staged) = STAGED_KEYS.lock().await.remove(user) else {
+        return Ok(());
+    };
+
+    if let Some(salt) = &staged.new_salt {
+        // sessions sealed under a vault left behind can not be opened anymore
+        if write_vault(app_handle, user, &staged.key, salt).await? {
+            let secrets =
+                app_handle.open_store(&get_store_path(&format!("{}/secrets.bin", user)).await)?;
+            secrets.clear();
+            secrets.save()?;
+        }
+    }
+    VAULT_KEYS.lock().await.insert(user.to_string(), staged.key);
+
+    seal_legacy_entries(app_handle, user).await
+}
+
+/// Forgets the key of a login the homeserver refused.
+pub async fn discard(user: &str) {
+    STAGED_KEYS.lock().await.remove(user);
+}
+
+/// Derives the key of the account `user` registers with a new salt. Like a login it is staged,
+/// [`commit`] writes the new vault once the homeserver accepted the registration.
+pub async fn stage_new(user: &str, passphrase: &str) -> Result<(), crate::Error> {
+    let salt = new_salt();
+    let staged = Staged {
+        key: derive_key(passphrase, &salt)?,
+        new_salt: Some(salt),
+    };
+
+    STAGED_KEYS.lock().await.insert(user.to_string(), staged);
+    Ok(())
+}
+
+fn new_salt() -> Vec<u8> {
+    let mut salt = vec![0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Writes salt and check value of `key` to `vault.bin`, returns whether there was a vault.
+async fn write_vault<H: Host>(
+    app_handle: &H,
+    user: &str,
+    key: &[u8],
+    salt: &[u8],
+) -> Result<bool, crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/vault.bin", user)).await)?;
+    let had_vault = store.has("salt");
+    store.set("salt", json!(BASE64_STANDARD.encode(salt)));
+    store.set("check", seal_with(key, "vault", &json!(CHECK_VALUE))?);
+    store.save()?;
+
+    Ok(had_vault)
+}
+
+/// Forgets the key of `user`, other accounts stay unlocked.
+#[cfg(any(feature = "gui", test))]
+pub async fn lock(user: &str) {
+    VAULT_KEYS.lock().await.remove(user);
+    STAGED_KEYS.lock().await.remove(user);
+}
+
+pub async fn seal(user: &str, entry: &str, value: &Value) -> Result<Value, VaultError> {
+    let keys = VAULT_KEYS.lock().await;
+    let key = keys.get(user).ok_or(VaultError::Locked(user.to_string()))?;
+
+    seal_with(key, entry, value)
+}
+
+pub async fn open(user: &str, entry: &str, value: Value) -> Result<Value, VaultError> {
+    let keys = VAULT_KEYS.lock().await;
+    let key = keys.get(user).ok_or(VaultError::Locked(user.to_string()))?;
+
+    open_with(key, entry, value)
+}
+
+/// Entry name used as associated data for a sealed value.
+pub fn entry(file: &str, key: &str) -> String {
+    format!("{}/{}", file, key)
+}
+
+/// Opens entry `key` of the per-account store `file`, e.g. `transfers.bin`.
+pub async fn load<H: Host, T: DeserializeOwned>(
+    app_handle: &H,
+    user: &str,
+    file: &str,
+    key: &str,
+) -> Result<Option<T>, crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/{}", user, file)).await)?;
+
+    let value = match store.get(key) {
+        Some(v) => open(user, &entry(file, key), v).await?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(serde_json::from_value(value)?))
+}
+
+/// Opens every entry of the per-account store `file`. Entries that do not open or do not
+/// parse are logged and removed, one damaged entry must not hide all the others.
+pub async fn load_all<H: Host, T: DeserializeOwned>(
+    app_handle: &H,
+    user: &str,
+    file: &str,
+) -> Result<Vec<(String, T)>, crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/{}", user, file)).await)?;
+    let keys = VAULT_KEYS.lock().await;
+    let vault_key = keys.get(user).ok_or(VaultError::Locked(user.to_string()))?;
+
+    let mut entries = Vec::new();
+    let mut damaged = false;
+    for (key, value) in store.entries() {
+        let parsed = open_with(vault_key, &entry(file, &key), value)
+            .map_err(crate::Error::from)
+            .and_then(|value| Ok(serde_json::from_value(value)?));
+        match parsed {
+            Ok(value) => entries.push((key, value)),
+            Err(e) => {
+                error!("removing damaged entry {} of {}/{}: {}", key, user, file, e);
+                store.delete(&key);
+                damaged = true;
+            }
+        }
+    }
+    if damaged {
+        store.save()?;
+    }
+
+    Ok(entries)
+}
+
+pub async fn store<H: Host, T: Serialize>(
+    app_handle: &H,
+    user: &str,
+    file: &str,
+    key: &str,
+    value: &T,
+) -> Result<(), crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/{}", user, file)).await)?;
+    store.set(key, seal(user, &entry(file, key), &json!(value)).await?);
+    store.save()?;
+
+    Ok(())
+}
+
+pub async fn remove<H: Host>(
+    app_handle: &H,
+    user: &str,
+    file: &str,
+    key: &str,
+) -> Result<(), crate::Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/{}", user, file)).await)?;
+    store.delete(key);
+    store.save()?;
+
+    Ok(())
+}
+
+/// Seals what was written in plain text before the vault existed.
+async fn seal_legacy_entries<H: Host>(app_handle: &H, user: &str) -> Result<(), crate::Error> {
+    let credentials = app_handle.open_store(&get_store_path("credentials.bin").await)?;
+    if let Some(bundle) = credentials.get(user) {
+        if sealed_data(&bundle).is_none() {
+            info!("sealing keybundle of {}", user);
+            credentials.set(
+                user,
+                seal(user, &entry("credentials.bin", user), &bundle).await?,
+            );
+            credentials.save()?;
+        }
+    }
+
+    let secrets = app_handle.open_store(&get_store_path(&format!("{}/secrets.bin", user)).await)?;
+    let mut changed = false;
+    for (contact, session) in secrets.entries() {
+        if sealed_data(&session).is_none() {
+            secrets.set(
+                &contact,
+                seal(user, &entry("secrets.bin", &contact), &session).await?,
+            );
+            changed = true;
+        }
+    }
+    if changed {
+        info!("sealed sessions of {}", user);
+        secrets.save()?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_vault_seal() {
+    let key = derive_key("correct horse", &[1; SALT_LEN]).unwrap();
+    let value = json!({ "identity": { "public": "pub", "private": "priv" } });
+
+    let sealed = seal_with(&key, "credentials.bin/alice", &value).unwrap();
+    assert!(!sealed.to_string().contains("priv"));
+    assert_eq!(
+        open_with(&key, "credentials.bin/alice", sealed.clone()).unwrap(),
+        value
+    );
+
+    // wrong passphrase, or a sealed entry copied over another one
+    let wrong_key = derive_key("battery staple", &[1; SALT_LEN]).unwrap();
+    assert!(open_with(&wrong_key, "credentials.bin/alice", sealed.clone()).is_err());
+    assert!(open_with(&key, "credentials.bin/bob", sealed).is_err());
+
+    // plain text entries are only read by the migration
+    assert!(matches!(
+        open_with(&key, "credentials.bin/alice", value),
+        Err(VaultError::NotSealed)
+    ));
+}
+
+#[tokio::test]
+async fn check_damaged_entries_removed() {
+    use crate::host::Storage;
+
+    let (host, _events) = crate::harness::TestHost::new();
+    stage_new("vault-vic", "correct horse").await.unwrap();
+    commit(&host, "vault-vic").await.unwrap();
+
+    store(&host, "vault-vic", "notes.bin", "good", &1_u32)
+        .await
+        .unwrap();
+    store(&host, "vault-vic", "notes.bin", "wrong-type", &"one")
+        .await
+        .unwrap();
+    let path = get_store_path("vault-vic/notes.bin").await;
+    host.open_store(&path).unwrap().set("not-sealed", json!(1));
+
+    // the damaged entries are dropped, the good one is still read
+    let entries = load_all::<_, u32>(&host, "vault-vic", "notes.bin")
+        .await
+        .unwrap();
+    assert_eq!(entries, [("good".to_string(), 1)]);
+    let keys: Vec<_> = host
+        .open_store(&path)
+        .unwrap()
+        .entries()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, ["good"]);
+
+    // a locked vault is an error, nothing is removed
+    lock("vault-vic").await;
+    assert!(load_all::<_, u32>(&host, "vault-vic", "notes.bin")
+        .await
+        .is_err());
+    assert!(host.open_store(&path).unwrap().get("good").is_some());
+}
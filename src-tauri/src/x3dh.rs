@@ -1,10 +1,9 @@
 //! Basic example.
 
-use std::{path::PathBuf, sync::Arc};
+use std::collections::HashMap;
 
-use aes_gcm::Key;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use cryptimitives::{aead, kdf::sha256, key::x25519_ristretto};
+use cryptimitives::key::x25519_ristretto;
 use cryptraits::{
     convert::{FromBytes, ToVec},
     key::KeyPair,
@@ -12,46 +11,52 @@ use cryptraits::{
 };
 use rand_core::OsRng;
 use serde_json::json;
-use tauri::{Manager, Wry};
-use tauri_plugin_store::StoreExt;
 // use tauri_plugin_store::{with_store, StoreCollection};
 
+use cipher_chat_protocol::{SecretBytes, SecretString};
 use cryptraits::key::Generate;
 use tokio::sync::Mutex;
 
+#[cfg(feature = "gui")]
+use crate::{fingerprint, util::SafetyNumber};
 use crate::{
-    util::{get_store_path, KeyBundle, KeyPairB64, MsgContent, MsgPayload, OpAuthPayload},
-    xxxdh::Protocol,
-    Error, HOMESERVER,
+    host::{Host, KeyValueStore},
+    protocol::{BundleResponse, Handshake},
+    ratchet::{identity_ad, Session},
+    util::{
+        get_store_path, unix_time, ContactIdentity, KeyBundle, KeyPairB64, MsgContent,
+        PrekeyConfig, RetiredPrekey,
+    },
+    vault,
+    xxxdh::{InitMsg, Protocol, XxxDhError},
+    Error, PREKEY_CONFIG,
 };
 
-pub async fn get_keybundle(app_handle: tauri::AppHandle, auth: MsgPayload) -> KeyBundle {
+lazy_static::lazy_static! {
+  /// Serializes read-modify-write cycles on `credentials.bin`.
+  static ref CREDENTIALS_LOCK: Mutex<()> = Mutex::new(());
+  /// Bundles of registrations the homeserver did not answer yet, by username.
+  static ref STAGED_BUNDLES: Mutex<HashMap<String, KeyBundle>> = Mutex::new(HashMap::new());
+}
+
+/// Generates the keys of the account `user` registers and returns the public bundle to upload.
+/// They are only stored by [`commit_bundle`], once the homeserver accepted the registration.
+pub async fn get_keybundle(user: &str) -> Result<KeyBundle, Error> {
     let identity: cryptimitives::key::KeyPair<x25519_ristretto::SecretKey> =
         x25519_ristretto::KeyPair::generate_with(OsRng);
     let prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
     let signature = identity.sign(&prekey.to_public().to_vec());
 
-    let mut ot_kp: Vec<KeyPairB64> = Vec::new();
-
-    for _i in 0..100 {
-        let onetime_keypair = x25519_ristretto::KeyPair::generate_with(OsRng);
-
-        let kp = KeyPairB64 {
-            public: BASE64_STANDARD.encode(onetime_keypair.public().to_vec()),
-            private: Some(BASE64_STANDARD.encode(onetime_keypair.secret().to_vec())),
-        };
-
-        ot_kp.push(kp);
-    }
+    let ot_kp = generate_onetime_keys(PREKEY_CONFIG.lock().await.otk_batch_size);
 
     let mut public_kb: KeyBundle = KeyBundle {
         identity: KeyPairB64 {
             public: BASE64_STANDARD.encode(identity.public().to_vec()),
-            private: Some(BASE64_STANDARD.encode(identity.secret().to_vec())),
+            private: Some(SecretString::encode(&identity.secret().to_vec())),
         },
         prekey: KeyPairB64 {
             public: BASE64_STANDARD.encode(prekey.public().to_vec()),
-            private: Some(BASE64_STANDARD.encode(prekey.secret().to_vec())),
+            private: Some(SecretString::encode(&prekey.secret().to_vec())),
         },
         signature: KeyPairB64 {
             public: BASE64_STANDARD.encode(signature.to_vec()),
@@ -59,120 +64,292 @@ pub async fn get_keybundle(app_handle: tauri::AppHandle, auth: MsgPayload) -> Ke
         },
         onetime_keys: ot_kp,
         ephemeral_key: None,
+        receiver_prekey: None,
+        prekey_created: Some(unix_time()),
+        retired_prekeys: Vec::new(),
     };
 
-    let store = app_handle
-        .store_builder(get_store_path("credentials.bin").await)
-        .build()
-        .unwrap();
-    store.set(auth.auth.unwrap().user, json!(public_kb));
-
-    store.save().unwrap();
+    STAGED_BUNDLES
+        .lock()
+        .await
+        .insert(user.to_string(), public_kb.clone());
 
     public_kb.strip();
 
-    public_kb
+    Ok(public_kb)
 }
 
-pub async fn bob_x3dh(
-    app_handle: tauri::AppHandle,
-    msg_queue: Arc<Mutex<Vec<MsgPayload>>>,
-    msg: MsgPayload,
-) {
-    let kb = msg.auth.unwrap().keybundle.unwrap();
+/// Stores the keys of a registration the homeserver accepted, the vault of `user` has to be
+/// unlocked.
+pub async fn commit_bundle<H: Host>(app_handle: &H, user: &str) -> Result<(), Error> {
+    let Some(bundle) = STAGED_BUNDLES.lock().await.remove(user) else {
+        return Ok(());
+    };
 
-    let store = app_handle
-        .store_builder(get_store_path("credentials.bin").await)
-        .build()
-        .unwrap();
+    let _guard = CREDENTIALS_LOCK.lock().await;
+    save_bundle(app_handle, user, &bundle).await
+}
 
-    let sndr_keybundle = store.get(msg.recipient.clone()).unwrap();
-    let sndr_keybundle = serde_json::from_value::<KeyBundle>(sndr_keybundle).unwrap();
+/// Forgets the keys of a registration the homeserver refused.
+pub async fn discard_bundle(user: &str) {
+    STAGED_BUNDLES.lock().await.remove(user);
+}
 
-    let bob_identity = get_key_pair(sndr_keybundle.identity).unwrap();
-    let bob_prekey = get_key_pair(sndr_keybundle.prekey).unwrap();
-    let bob_signature = x25519_ristretto::Signature::from_bytes(
-        &BASE64_STANDARD
-            .decode(sndr_keybundle.signature.public)
-            .unwrap(),
-    )
-    .unwrap();
+/// Whether this device keeps the keys of `user`.
+pub async fn has_bundle<H: Host>(app_handle: &H, user: &str) -> Result<bool, Error> {
+    let store = app_handle.open_store(&get_store_path("credentials.bin").await)?;
+    Ok(store.has(user))
+}
+
+/// Generate `count` fresh one-time prekeys, private halves included.
+pub fn generate_onetime_keys(count: usize) -> Vec<KeyPairB64> {
+    let mut ot_kp: Vec<KeyPairB64> = Vec::new();
+
+    for _i in 0..count {
+        let onetime_keypair = x25519_ristretto::KeyPair::generate_with(OsRng);
+
+        let kp = KeyPairB64 {
+            public: BASE64_STANDARD.encode(onetime_keypair.public().to_vec()),
+            private: Some(SecretString::encode(&onetime_keypair.secret().to_vec())),
+        };
 
-    let mut bob_onetime_key: Option<KeyPairB64> = Option::None;
+        ot_kp.push(kp);
+    }
+
+    ot_kp
+}
+
+/// Append `count` new one-time prekeys to the stored bundle of `user`.
+///
+/// Returns the public bundle carrying only the new keys, ready to be uploaded.
+pub async fn replenish_onetime_keys<H: Host>(
+    app_handle: &H,
+    user: &str,
+    count: usize,
+) -> Result<KeyBundle, Error> {
+    let _guard = CREDENTIALS_LOCK.lock().await;
 
-    for k in sndr_keybundle.onetime_keys {
-        if k.public == kb.onetime_keys.get(0).unwrap().public {
-            bob_onetime_key = Some(k);
+    let mut bundle = load_bundle(app_handle, user).await?;
+    let new_keys = generate_onetime_keys(count);
+
+    bundle.onetime_keys.extend(new_keys.iter().cloned());
+    save_bundle(app_handle, user, &bundle).await?;
+
+    bundle.onetime_keys = new_keys;
+    bundle.strip();
+
+    Ok(bundle)
+}
+
+/// Replace the signed prekey of `user` once it is older than the configured rotation interval.
+///
+/// The previous prekey is kept for the grace window so initiations that are already in flight
+/// still succeed. Returns the public bundle to publish when a rotation happened.
+pub async fn rotate_signed_prekey<H: Host>(
+    app_handle: &H,
+    user: &str,
+    config: &PrekeyConfig,
+) -> Result<Option<KeyBundle>, Error> {
+    let _guard = CREDENTIALS_LOCK.lock().await;
+
+    let mut bundle = load_bundle(app_handle, user).await?;
+    let now = unix_time();
+
+    let retired_count = bundle.retired_prekeys.len();
+    bundle
+        .retired_prekeys
+        .retain(|k| now.saturating_sub(k.retired_at) <= config.prekey_grace_secs);
+    let purged = retired_count != bundle.retired_prekeys.len();
+
+    let created = match bundle.prekey_created {
+        Some(v) => v,
+        None => {
+            // bundles from before rotation existed start their clock now
+            bundle.prekey_created = Some(now);
+            save_bundle(app_handle, user, &bundle).await?;
+            return Ok(None);
+        }
+    };
+
+    if now.saturating_sub(created) < config.prekey_rotation_secs {
+        if purged {
+            save_bundle(app_handle, user, &bundle).await?;
         }
+        return Ok(None);
     }
 
-    let bob_onetime_key2 = get_key_pair(bob_onetime_key.clone().unwrap()).unwrap();
+    info!("rotating signed prekey of {}", user);
+
+    let identity = get_key_pair(bundle.identity.clone()).unwrap();
+    let prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let signature = identity.sign(&prekey.to_public().to_vec());
+
+    let old_prekey = std::mem::replace(
+        &mut bundle.prekey,
+        KeyPairB64 {
+            public: BASE64_STANDARD.encode(prekey.public().to_vec()),
+            private: Some(SecretString::encode(&prekey.secret().to_vec())),
+        },
+    );
+    bundle.retired_prekeys.push(RetiredPrekey {
+        prekey: old_prekey,
+        retired_at: now,
+    });
+    bundle.signature = KeyPairB64 {
+        public: BASE64_STANDARD.encode(signature.to_vec()),
+        private: None,
+    };
+    bundle.prekey_created = Some(now);
+
+    save_bundle(app_handle, user, &bundle).await?;
+
+    bundle.onetime_keys.clear();
+    bundle.strip();
+
+    Ok(Some(bundle))
+}
+
+/// Pick the private signed prekey an x3dh init message was derived against.
+fn select_prekey(
+    bundle: &KeyBundle,
+    receiver_prekey: Option<&str>,
+    grace: u64,
+) -> Result<KeyPairB64, Error> {
+    let receiver_prekey = match receiver_prekey {
+        // initiators from before rotation existed always used the current prekey
+        None => return Ok(bundle.prekey.clone()),
+        Some(v) => v,
+    };
+
+    if bundle.prekey.public == receiver_prekey {
+        return Ok(bundle.prekey.clone());
+    }
+
+    let now = unix_time();
+    let retired = bundle
+        .retired_prekeys
+        .iter()
+        .find(|k| k.prekey.public == receiver_prekey && now.saturating_sub(k.retired_at) <= grace)
+        .ok_or(XxxDhError::UnknownPrekey)?;
+
+    Ok(retired.prekey.clone())
+}
+
+pub async fn bob_x3dh<H: Host>(app_handle: H, msg: Handshake) -> Result<(), Error> {
+    let kb = msg.keybundle;
 
-    let bob_onetime_key = x25519_ristretto::PublicKey::from_bytes(
+    // hold the credentials lock until the used one-time key is gone from disk
+    let _guard = CREDENTIALS_LOCK.lock().await;
+
+    let mut sndr_keybundle = load_bundle(&app_handle, &msg.recipient).await?;
+
+    let grace = PREKEY_CONFIG.lock().await.prekey_grace_secs;
+
+    let bob_identity = get_key_pair(sndr_keybundle.identity.clone()).unwrap();
+    let bob_prekey = get_key_pair(select_prekey(
+        &sndr_keybundle,
+        kb.receiver_prekey.as_deref(),
+        grace,
+    )?)
+    .unwrap();
+    let bob_signature = x25519_ristretto::Signature::from_bytes(
         &BASE64_STANDARD
-            .decode(bob_onetime_key.unwrap().public)
+            .decode(&sndr_keybundle.signature.public)
             .unwrap(),
     )
     .unwrap();
 
+    // an init message without one-time key was derived with the 3-DH variant
+    let used_onetime_key = kb.onetime_keys.first().map(|k| k.public.clone());
+
+    let (bob_onetime_key, bob_onetime_keypairs) = match &used_onetime_key {
+        Some(used_onetime_key) => {
+            // a key that is not in our bundle anymore was either never ours or already used
+            let bob_onetime_key = sndr_keybundle
+                .onetime_keys
+                .iter()
+                .find(|k| &k.public == used_onetime_key)
+                .ok_or(XxxDhError::UnknownPrekey)?
+                .clone();
+
+            let bob_onetime_key2 = get_key_pair(bob_onetime_key.clone()).unwrap();
+
+            (
+                Some(decode_public_key(&bob_onetime_key.public)?),
+                Some(vec![bob_onetime_key2]),
+            )
+        }
+        None => (None, None),
+    };
+
+    let bob_identity_public = bob_identity.to_public();
     let mut bob_protocol = Protocol::new(
         bob_identity,
         bob_prekey.clone(),
         bob_signature,
-        Some(vec![bob_onetime_key2]),
+        bob_onetime_keypairs,
     );
 
-    let alice_identity = x25519_ristretto::PublicKey::from_bytes(
-        &BASE64_STANDARD.decode(kb.identity.public).unwrap(),
-    )
-    .unwrap();
+    let alice_identity = decode_public_key(&kb.identity.public)?;
 
-    let alice_ephemeral_key = x25519_ristretto::PublicKey::from_bytes(
-        &BASE64_STANDARD
-            .decode(kb.ephemeral_key.unwrap().public)
-            .unwrap(),
+    // a handshake from a changed identity key must not replace the session we have
+    check_identity(
+        &app_handle,
+        &msg.recipient,
+        &msg.author,
+        &kb.identity.public,
     )
-    .unwrap();
-
-    let bob_sk = bob_protocol
-        .derive_shared_secret(
-            &alice_identity,
-            &alice_ephemeral_key,
-            &bob_onetime_key,
-            &BASE64_STANDARD
-                .decode(msg.content.clone().unwrap().nonce)
-                .unwrap(),
-            &BASE64_STANDARD
-                .decode(msg.content.unwrap().ciphertext)
-                .unwrap(),
-        )
-        .unwrap();
-
-    info!("bob_sk {:?}, author: {}", bob_sk, msg.author.clone());
+    .await?;
+
+    let alice_ephemeral_key =
+        decode_public_key(&kb.ephemeral_key.ok_or(XxxDhError::UnknownPrekey)?.public)?;
+
+    let content = msg.content;
+
+    let bob_sk = bob_protocol.derive_shared_secret(
+        &alice_identity,
+        &alice_ephemeral_key,
+        bob_onetime_key.as_ref(),
+        &BASE64_STANDARD.decode(content.nonce)?,
+        &BASE64_STANDARD.decode(content.ciphertext)?,
+    )?;
+
+    // only a key that passed the AEAD check is trusted on first use
+    pin_identity(
+        &app_handle,
+        &msg.recipient,
+        &msg.author,
+        &kb.identity.public,
+    )
+    .await?;
 
-    // save bob_sk
+    if let Some(used_onetime_key) = used_onetime_key {
+        sndr_keybundle
+            .onetime_keys
+            .retain(|k| k.public != used_onetime_key);
+        save_bundle(&app_handle, &msg.recipient, &sndr_keybundle).await?;
+    }
 
-    info!("saving in {}", format!("{}/secrets.bin", msg.recipient));
+    // our signed prekey is the first ratchet key alice ratchets against
+    let session = Session::init_bob(
+        &bob_sk,
+        &bob_prekey,
+        &identity_ad(&alice_identity, &bob_identity_public),
+    )?;
 
-    let secret_store = app_handle
-        .store_builder(get_store_path(&format!("{}/secrets.bin", msg.recipient)).await)
-        .build()
-        .unwrap();
-    secret_store.set(msg.author.clone(), BASE64_STANDARD.encode(bob_sk));
+    save_session(&app_handle, &msg.recipient, &msg.author, &session).await?;
 
-    secret_store.save().unwrap();
+    Ok(())
 }
 
-pub async fn alice_x3dh(app_handle: tauri::AppHandle, msg: MsgPayload) -> MsgPayload {
-    let rcvr_keybundle = msg.auth.clone().unwrap().keybundle.unwrap();
-
-    let store = app_handle
-        .store_builder(get_store_path("credentials.bin").await)
-        .build()
-        .unwrap();
+pub async fn alice_x3dh<H: Host>(app_handle: H, msg: &BundleResponse) -> Result<Handshake, Error> {
+    let rcvr_keybundle = msg.keybundle.clone().ok_or(Error::CustomError(format!(
+        "no bundle of {}: {}",
+        msg.user,
+        msg.error.as_deref().unwrap_or("missing")
+    )))?;
 
-    let sndr_keybundle = store.get(msg.recipient.clone()).unwrap();
-    let sndr_keybundle = serde_json::from_value::<KeyBundle>(sndr_keybundle).unwrap();
+    let sndr_keybundle = load_bundle(&app_handle, &msg.recipient).await?;
 
     let alice_identity = get_key_pair(sndr_keybundle.identity).unwrap();
     let alice_prekey = get_key_pair(sndr_keybundle.prekey).unwrap();
@@ -185,53 +362,60 @@ pub async fn alice_x3dh(app_handle: tauri::AppHandle, msg: MsgPayload) -> MsgPay
     let mut alice_protocol =
         Protocol::new(alice_identity, alice_prekey.clone(), alice_signature, None);
 
-    let bob_identity = x25519_ristretto::PublicKey::from_bytes(
-        &BASE64_STANDARD
-            .decode(rcvr_keybundle.identity.public)
-            .unwrap(),
+    // everything below comes from the homeserver and must not be trusted blindly
+    let bob_identity = decode_public_key(&rcvr_keybundle.identity.public)?;
+    check_identity(
+        &app_handle,
+        &msg.recipient,
+        &msg.user,
+        &rcvr_keybundle.identity.public,
     )
-    .unwrap();
-    let bob_prekey = x25519_ristretto::PublicKey::from_bytes(
-        &BASE64_STANDARD
-            .decode(rcvr_keybundle.prekey.public)
-            .unwrap(),
-    )
-    .unwrap();
+    .await?;
+    let bob_prekey = decode_public_key(&rcvr_keybundle.prekey.public)?;
     let bob_signature = x25519_ristretto::Signature::from_bytes(
-        &BASE64_STANDARD
-            .decode(rcvr_keybundle.signature.public)
-            .unwrap(),
+        &BASE64_STANDARD.decode(&rcvr_keybundle.signature.public)?,
     )
-    .unwrap();
+    .map_err(XxxDhError::from)?;
+
+    // the server hands out a bundle without one-time keys once they are exhausted
+    let bob_one_time_key = match rcvr_keybundle.onetime_keys.first() {
+        Some(k) => Some(decode_public_key(&k.public)?),
+        None => {
+            info!("no one-time key available, falling back to 3-DH");
+            None
+        }
+    };
 
-    let bob_one_time_key = x25519_ristretto::PublicKey::from_bytes(
-        &BASE64_STANDARD
-            .decode(rcvr_keybundle.onetime_keys.get(0).unwrap().clone().public)
-            .unwrap(),
+    let InitMsg {
+        identity: alice_identity,
+        ephemeral_key: alice_ephemeral_key,
+        onetime_key: bob_onetime_key,
+        sk: alice_sk,
+        nonce,
+        ciphertext,
+    } = alice_protocol.prepare_init_msg(
+        &bob_identity,
+        &bob_prekey,
+        bob_signature,
+        bob_one_time_key.as_ref(),
+    )?;
+
+    // the signature over the prekey checked out, the key can be trusted on first use
+    pin_identity(
+        &app_handle,
+        &msg.recipient,
+        &msg.user,
+        &rcvr_keybundle.identity.public,
     )
-    .unwrap();
-
-    let (alice_identity, alice_ephemeral_key, bob_onetime_key, alice_sk, nonce, ciphertext) =
-        alice_protocol
-            .prepare_init_msg(&bob_identity, &bob_prekey, bob_signature, &bob_one_time_key)
-            .unwrap();
-
-    info!("alice_sk: {:?}", alice_sk);
+    .await?;
 
-    // save alice_sk
+    let session = Session::init_alice(
+        &alice_sk,
+        &bob_prekey,
+        &identity_ad(&alice_identity, &bob_identity),
+    )?;
 
-    info!("saving in {}", format!("{}/secrets.bin", msg.recipient));
-
-    let secret_store = app_handle
-        .store_builder(get_store_path(&format!("{}/secrets.bin", msg.recipient)).await)
-        .build()
-        .unwrap();
-    secret_store.set(
-        msg.auth.clone().unwrap().user,
-        BASE64_STANDARD.encode(alice_sk),
-    );
-
-    secret_store.save().unwrap();
+    save_session(&app_handle, &msg.recipient, &msg.user, &session).await?;
 
     use cryptraits::key::KeyPair;
 
@@ -248,36 +432,287 @@ pub async fn alice_x3dh(app_handle: tauri::AppHandle, msg: MsgPayload) -> MsgPay
             public: BASE64_STANDARD.encode(alice_signature.to_vec()),
             private: None,
         },
-        onetime_keys: vec![KeyPairB64 {
-            public: BASE64_STANDARD.encode(bob_onetime_key.to_vec()),
-            private: None,
-        }],
+        // an empty list tells the receiver that only DH1-DH3 were used
+        onetime_keys: bob_onetime_key
+            .iter()
+            .map(|k| KeyPairB64 {
+                public: BASE64_STANDARD.encode(k.to_vec()),
+                private: None,
+            })
+            .collect(),
         ephemeral_key: Some(KeyPairB64 {
             public: BASE64_STANDARD.encode(alice_ephemeral_key.to_vec()),
             private: None,
         }),
+        receiver_prekey: Some(rcvr_keybundle.prekey.public.clone()),
+        prekey_created: None,
+        retired_prekeys: Vec::new(),
     };
 
-    let x = MsgPayload {
-        content: Some(MsgContent {
+    Ok(Handshake {
+        author: msg.recipient.clone(),
+        recipient: msg.user.clone(),
+        keybundle: generated_kb,
+        content: MsgContent {
             ciphertext: BASE64_STANDARD.encode(ciphertext),
             nonce: BASE64_STANDARD.encode(nonce),
             cleartext: None,
-        }),
-        timestamp: 0,
-        auth: Some(OpAuthPayload {
-            action: "x3dh".to_string(),
-            user: "".to_string(),
-            password: "".to_string(),
-            keybundle: Some(generated_kb),
-            message: "".to_string(),
-            success: Some(true),
-        }),
-        message_id: "".to_string(),
-        author: msg.recipient,
-        recipient: msg.auth.unwrap().user,
+            header: None,
+        },
+    })
+}
+
+/// Load the private key bundle of `user` from `credentials.bin`.
+pub async fn load_bundle<H: Host>(app_handle: &H, user: &str) -> Result<KeyBundle, Error> {
+    let store = app_handle.open_store(&get_store_path("credentials.bin").await)?;
+
+    let bundle = store
+        .get(user)
+        .ok_or(Error::CustomError(format!("no keybundle for {}", user)))?;
+    let bundle = vault::open(user, &vault::entry("credentials.bin", user), bundle).await?;
+
+    Ok(serde_json::from_value::<KeyBundle>(bundle)?)
+}
+
+pub async fn save_bundle<H: Host>(
+    app_handle: &H,
+    user: &str,
+    bundle: &KeyBundle,
+) -> Result<(), Error> {
+    let store = app_handle.open_store(&get_store_path("credentials.bin").await)?;
+    store.set(
+        user,
+        vault::seal(user, &vault::entry("credentials.bin", user), &json!(bundle)).await?,
+    );
+    store.save()?;
+
+    Ok(())
+}
+
+/// Load the ratchet session `owner` keeps with `contact`, if a handshake happened yet.
+pub async fn load_session<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+) -> Result<Option<Session>, Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/secrets.bin", owner)).await)?;
+
+    // entries written before the ratchet existed are plain keys, those need a new handshake
+    let session = match store.get(contact) {
+        Some(v) => {
+            let v = vault::open(owner, &vault::entry("secrets.bin", contact), v).await?;
+            serde_json::from_value::<Session>(v).ok()
+        }
+        None => None,
     };
-    x
+
+    Ok(session)
+}
+
+pub async fn save_session<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+    session: &Session,
+) -> Result<(), Error> {
+    info!("saving session in {}/secrets.bin", owner);
+
+    let store = app_handle.open_store(&get_store_path(&format!("{}/secrets.bin", owner)).await)?;
+    store.set(
+        contact,
+        vault::seal(
+            owner,
+            &vault::entry("secrets.bin", contact),
+            &json!(session),
+        )
+        .await?,
+    );
+    store.save()?;
+
+    Ok(())
+}
+
+pub async fn load_identity<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+) -> Result<Option<ContactIdentity>, Error> {
+    let store =
+        app_handle.open_store(&get_store_path(&format!("{}/identities.bin", owner)).await)?;
+
+    let identity = match store.get(contact) {
+        Some(v) => Some(serde_json::from_value::<ContactIdentity>(v)?),
+        None => None,
+    };
+
+    Ok(identity)
+}
+
+pub async fn save_identity<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+    identity: &ContactIdentity,
+) -> Result<(), Error> {
+    let store =
+        app_handle.open_store(&get_store_path(&format!("{}/identities.bin", owner)).await)?;
+    store.set(contact, json!(identity));
+    store.save()?;
+
+    Ok(())
+}
+
+/// Trust on first use, a key that differs from the pinned identity key of `contact` is parked
+/// as pending and rejected until [`accept_identity_change`] is called. The key of a contact
+/// seen for the first time passes, it is pinned by [`pin_identity`] once the handshake checked
+/// out.
+pub async fn check_identity<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+    identity: &str,
+) -> Result<(), Error> {
+    let Some(mut known) = load_identity(app_handle, owner, contact).await? else {
+        return Ok(());
+    };
+
+    if known.identity == identity && known.pending.is_none() {
+        return Ok(());
+    }
+
+    warn!("identity key of {} changed", contact);
+
+    if known.pending.as_deref() != Some(identity) {
+        known.pending = Some(identity.to_string());
+        save_identity(app_handle, owner, contact, &known).await?;
+    }
+
+    Err(Error::IdentityChanged(contact.to_string()))
+}
+
+/// Pins the identity key of a contact seen for the first time, after the handshake with it
+/// was verified. A key pinned meanwhile by another handshake is checked instead.
+pub async fn pin_identity<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+    identity: &str,
+) -> Result<(), Error> {
+    if load_identity(app_handle, owner, contact).await?.is_some() {
+        return check_identity(app_handle, owner, contact, identity).await;
+    }
+
+    let first_seen = ContactIdentity {
+        identity: identity.to_string(),
+        verified: false,
+        pending: None,
+    };
+    save_identity(app_handle, owner, contact, &first_seen).await
+}
+
+/// Fails while `contact` has an identity change the user did not accept yet.
+pub async fn ensure_identity_trusted<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+) -> Result<(), Error> {
+    match load_identity(app_handle, owner, contact).await? {
+        Some(ContactIdentity {
+            pending: Some(_), ..
+        }) => Err(Error::IdentityChanged(contact.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Pins the pending identity key of `contact`. The session built on the old key is dropped
+/// so the next message runs a new handshake, and the verified flag starts over.
+#[cfg(any(feature = "gui", test))]
+pub async fn accept_identity_change<H: Host>(
+    app_handle: &H,
+    user: &str,
+    contact: &str,
+) -> Result<(), Error> {
+    let mut known = load_identity(app_handle, user, contact)
+        .await?
+        .ok_or(Error::CustomError(format!(
+            "no identity known for {}",
+            contact
+        )))?;
+
+    let pending = known.pending.take().ok_or(Error::CustomError(format!(
+        "no identity change pending for {}",
+        contact
+    )))?;
+    known.identity = pending;
+    known.verified = false;
+
+    save_identity(app_handle, user, contact, &known).await?;
+    delete_session(app_handle, user, contact).await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+pub async fn get_safety_number<H: Host>(
+    app_handle: &H,
+    user: &str,
+    contact: &str,
+) -> Result<SafetyNumber, Error> {
+    let bundle = load_bundle(app_handle, user).await?;
+    let known = load_identity(app_handle, user, contact)
+        .await?
+        .ok_or(Error::CustomError(format!(
+            "no identity known for {}",
+            contact
+        )))?;
+
+    let mut number = fingerprint::safety_number(
+        &BASE64_STANDARD.decode(&bundle.identity.public)?,
+        user,
+        &BASE64_STANDARD.decode(&known.identity)?,
+        contact,
+    );
+    number.verified = known.verified;
+
+    Ok(number)
+}
+
+#[cfg(feature = "gui")]
+pub async fn set_contact_verified<H: Host>(
+    app_handle: &H,
+    user: &str,
+    contact: &str,
+    verified: bool,
+) -> Result<(), Error> {
+    let mut known = load_identity(app_handle, user, contact)
+        .await?
+        .ok_or(Error::CustomError(format!(
+            "no identity known for {}",
+            contact
+        )))?;
+    known.verified = verified;
+
+    save_identity(app_handle, user, contact, &known).await
+}
+
+#[cfg(any(feature = "gui", test))]
+pub async fn delete_session<H: Host>(
+    app_handle: &H,
+    owner: &str,
+    contact: &str,
+) -> Result<(), Error> {
+    let store = app_handle.open_store(&get_store_path(&format!("{}/secrets.bin", owner)).await)?;
+    store.delete(contact);
+    store.save()?;
+
+    Ok(())
+}
+
+fn decode_public_key(public: &str) -> Result<x25519_ristretto::PublicKey, Error> {
+    let public = x25519_ristretto::PublicKey::from_bytes(&BASE64_STANDARD.decode(public)?)
+        .map_err(XxxDhError::from)?;
+
+    Ok(public)
 }
 
 pub fn get_key_pair(
@@ -287,17 +722,10 @@ pub fn get_key_pair(
         x25519_ristretto::PublicKey::from_bytes(&BASE64_STANDARD.decode(key_pair.public).unwrap())
             .unwrap();
     let alice_priv = x25519_ristretto::SecretKey::from_bytes(
-        &BASE64_STANDARD
-            .decode(match key_pair.private {
-                Some(v) => v,
-                None => String::new(),
-            })
-            .unwrap(),
+        &key_pair.private.unwrap_or_default().decode().unwrap(),
     )
     .unwrap();
-    let mut x = alice_pub.to_vec();
-    let mut y = alice_priv.to_vec();
-    y.append(&mut x);
+    let y = SecretBytes::from([alice_priv.to_vec(), alice_pub.to_vec()].concat());
 
     let alice_key_bundle: cryptimitives::key::KeyPair<x25519_ristretto::SecretKey> =
         x25519_ristretto::KeyPair::from_bytes(&y).unwrap();
@@ -332,18 +760,23 @@ fn check_secret_sharing_x3dh() {
 
     let bob_identity = bob_identity;
     let bob_prekey = bob_prekey;
-    let bob_signature = bob_signature;
     let onetime_key = onetime_keypair;
 
-    let (alice_identity, alice_ephemeral_key, bob_onetime_key, alice_sk, nonce, ciphertext) =
-        alice_protocol
-            .prepare_init_msg(
-                bob_identity.public(),
-                bob_prekey.public(),
-                bob_signature,
-                onetime_key.public(),
-            )
-            .unwrap();
+    let InitMsg {
+        identity: alice_identity,
+        ephemeral_key: alice_ephemeral_key,
+        onetime_key: bob_onetime_key,
+        sk: alice_sk,
+        nonce,
+        ciphertext,
+    } = alice_protocol
+        .prepare_init_msg(
+            bob_identity.public(),
+            bob_prekey.public(),
+            bob_signature,
+            Some(onetime_key.public()),
+        )
+        .unwrap();
 
     // Derive shared secret for Bob using Alice credentials.
 
@@ -351,11 +784,178 @@ fn check_secret_sharing_x3dh() {
         .derive_shared_secret(
             &alice_identity,
             &alice_ephemeral_key,
-            &bob_onetime_key,
+            bob_onetime_key.as_ref(),
             &nonce,
             &ciphertext,
         )
         .unwrap();
 
-    assert_eq!(alice_sk, bob_sk);
+    assert_eq!(*alice_sk, *bob_sk);
+}
+
+#[test]
+fn check_onetime_key_consumed() {
+    let alice_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_signature = alice_identity.sign(&alice_prekey.to_public().to_vec());
+    let mut alice_protocol = Protocol::new(alice_identity, alice_prekey, alice_signature, None);
+
+    let onetime_keypair = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_signature = bob_identity.sign(&bob_prekey.to_public().to_vec());
+    let mut bob_protocol = Protocol::new(
+        bob_identity.clone(),
+        bob_prekey.clone(),
+        bob_signature,
+        Some(vec![onetime_keypair.clone()]),
+    );
+
+    let InitMsg {
+        identity: alice_identity,
+        ephemeral_key: alice_ephemeral_key,
+        onetime_key: bob_onetime_key,
+        nonce,
+        ciphertext,
+        ..
+    } = alice_protocol
+        .prepare_init_msg(
+            bob_identity.public(),
+            bob_prekey.public(),
+            bob_signature,
+            Some(onetime_keypair.public()),
+        )
+        .unwrap();
+
+    bob_protocol
+        .derive_shared_secret(
+            &alice_identity,
+            &alice_ephemeral_key,
+            bob_onetime_key.as_ref(),
+            &nonce,
+            &ciphertext,
+        )
+        .unwrap();
+
+    // replaying the same init message must not succeed a second time
+    let replay = bob_protocol.derive_shared_secret(
+        &alice_identity,
+        &alice_ephemeral_key,
+        bob_onetime_key.as_ref(),
+        &nonce,
+        &ciphertext,
+    );
+    assert!(matches!(replay, Err(XxxDhError::UnknownPrekey)));
+}
+
+#[test]
+fn check_prekey_grace_window() {
+    let key = |public: &str| KeyPairB64 {
+        public: public.to_string(),
+        private: Some(format!("{}-private", public).into()),
+    };
+    let now = unix_time();
+
+    let bundle = KeyBundle {
+        identity: key("identity"),
+        prekey: key("current"),
+        signature: key("signature"),
+        onetime_keys: Vec::new(),
+        ephemeral_key: None,
+        receiver_prekey: None,
+        prekey_created: Some(now),
+        retired_prekeys: vec![
+            RetiredPrekey {
+                prekey: key("recent"),
+                retired_at: now - 60,
+            },
+            RetiredPrekey {
+                prekey: key("expired"),
+                retired_at: now - 600,
+            },
+        ],
+    };
+
+    let public = |k: Result<KeyPairB64, Error>| k.unwrap().public;
+    assert_eq!(public(select_prekey(&bundle, None, 300)), "current");
+    assert_eq!(
+        public(select_prekey(&bundle, Some("current"), 300)),
+        "current"
+    );
+    assert_eq!(
+        public(select_prekey(&bundle, Some("recent"), 300)),
+        "recent"
+    );
+    assert!(select_prekey(&bundle, Some("expired"), 300).is_err());
+    assert!(select_prekey(&bundle, Some("unknown"), 300).is_err());
+}
+
+#[test]
+fn check_secret_sharing_x3dh_without_onetime_key() {
+    let alice_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_signature = alice_identity.sign(&alice_prekey.to_public().to_vec());
+    let mut alice_protocol = Protocol::new(alice_identity, alice_prekey, alice_signature, None);
+
+    // bob ran out of one-time keys
+    let bob_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_signature = bob_identity.sign(&bob_prekey.to_public().to_vec());
+    let mut bob_protocol = Protocol::new(
+        bob_identity.clone(),
+        bob_prekey.clone(),
+        bob_signature,
+        None,
+    );
+
+    let InitMsg {
+        identity: alice_identity,
+        ephemeral_key: alice_ephemeral_key,
+        onetime_key: bob_onetime_key,
+        sk: alice_sk,
+        nonce,
+        ciphertext,
+    } = alice_protocol
+        .prepare_init_msg(
+            bob_identity.public(),
+            bob_prekey.public(),
+            bob_signature,
+            None,
+        )
+        .unwrap();
+    assert!(bob_onetime_key.is_none());
+
+    let bob_sk = bob_protocol
+        .derive_shared_secret(
+            &alice_identity,
+            &alice_ephemeral_key,
+            None,
+            &nonce,
+            &ciphertext,
+        )
+        .unwrap();
+
+    assert_eq!(*alice_sk, *bob_sk);
+}
+
+#[test]
+fn check_forged_prekey_signature_rejected() {
+    let alice_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let alice_signature = alice_identity.sign(&alice_prekey.to_public().to_vec());
+    let mut alice_protocol = Protocol::new(alice_identity, alice_prekey, alice_signature, None);
+
+    // the server swapped in a prekey that bob never signed
+    let bob_identity = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let forged_prekey = x25519_ristretto::KeyPair::generate_with(OsRng);
+    let bob_signature = bob_identity.sign(&bob_prekey.to_public().to_vec());
+
+    let result = alice_protocol.prepare_init_msg(
+        bob_identity.public(),
+        forged_prekey.public(),
+        bob_signature,
+        None,
+    );
+    assert!(matches!(result, Err(XxxDhError::SignatureError(_))));
 }
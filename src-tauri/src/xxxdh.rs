@@ -1,13 +1,11 @@
 //! X3DH protocol implementation.
 
-use std::marker::PhantomData;
-
 use cryptimitives::aead::aes_gcm::Aes256Gcm;
 use cryptimitives::key::x25519_ristretto::{PublicKey, SecretKey, Signature};
 use cryptraits::key::KeyPair;
 use cryptraits::{
     aead::Aead,
-    convert::{FromBytes, Len, ToVec},
+    convert::{Len, ToVec},
     kdf::Kdf,
     key_exchange::DiffieHellman,
     signature::Verify,
@@ -16,6 +14,7 @@ use rand_core::{OsRng, RngCore};
 
 use cryptraits::key::Generate;
 
+use cipher_chat_protocol::SecretBytes;
 use cryptimitives::errors::{AeadError, KdfError, KeyPairError, SignatureError};
 use thiserror::Error;
 
@@ -72,6 +71,12 @@ impl From<AeadError> for XxxDhError {
     }
 }
 
+impl From<KeyPairError> for XxxDhError {
+    fn from(e: KeyPairError) -> Self {
+        Self::KeypairError(e)
+    }
+}
+
 impl From<SignatureError> for XxxDhError {
     fn from(e: SignatureError) -> Self {
         Self::SignatureError(e)
@@ -81,10 +86,19 @@ impl From<SignatureError> for XxxDhError {
 /// `Result` specialized to this crate for convenience. Used for protocol related results.
 pub type XxxDhResult<T> = Result<T, XxxDhError>;
 
-/// `Result` specialized to this crate for convenience. Used for storage related results.
-pub type StorageResult<T> = Result<T, StorageError>;
-
-pub const PROTOCOL_INFO: &'static str = "X3DH";
+pub const PROTOCOL_INFO: &str = "X3DH";
+
+/// What `prepare_init_msg` gives the sender: the keys the receiver needs, the shared secret
+/// and the initial message.
+pub struct InitMsg {
+    pub identity: PublicKey,
+    pub ephemeral_key: PublicKey,
+    /// `None` when only DH1-DH3 were used.
+    pub onetime_key: Option<PublicKey>,
+    pub sk: SecretBytes,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
 
 /// X3DH Protocol.
 pub struct Protocol {
@@ -110,25 +124,30 @@ impl Protocol {
     }
 
     /// Derive secret key and create initial message using receiver's keys.
+    ///
+    /// Without a one-time key only DH1-DH3 are used, the returned one-time key is `None` then
+    /// and the receiver has to pick the same variant.
     pub fn prepare_init_msg(
         &mut self,
         receiver_identity: &PublicKey,
         receiver_prekey: &PublicKey,
         receiver_prekey_signature: Signature,
-        receiver_onetime_key: &PublicKey,
-    ) -> XxxDhResult<(PublicKey, PublicKey, PublicKey, Vec<u8>, Vec<u8>, Vec<u8>)> {
-        receiver_identity
-            .verify(&receiver_prekey.to_vec(), &receiver_prekey_signature)
-            .unwrap();
+        receiver_onetime_key: Option<&PublicKey>,
+    ) -> XxxDhResult<InitMsg> {
+        receiver_identity.verify(&receiver_prekey.to_vec(), &receiver_prekey_signature)?;
         let ephemeral_key: cryptimitives::key::x25519_ristretto::KeyPair =
-            cryptimitives::key::x25519_ristretto::KeyPair::generate_with(OsRng).into();
+            cryptimitives::key::x25519_ristretto::KeyPair::generate_with(OsRng);
+
+        let mut source_data = vec![
+            (self._sk.secret(), receiver_prekey),
+            (ephemeral_key.secret(), receiver_identity),
+            (ephemeral_key.secret(), receiver_prekey),
+        ];
+        if let Some(receiver_onetime_key) = receiver_onetime_key {
+            source_data.push((ephemeral_key.secret(), receiver_onetime_key));
+        }
 
-        let sk = self._derive_sk([
-            (self._sk.secret(), &receiver_prekey),
-            (&ephemeral_key.secret(), &receiver_identity),
-            (&ephemeral_key.secret(), &receiver_prekey),
-            (&ephemeral_key.secret(), &receiver_onetime_key),
-        ])?;
+        let sk = self._derive_sk(&source_data)?;
 
         let mut nonce = vec![0; Aes256Gcm::NONCE_LEN];
         OsRng.fill_bytes(&mut nonce);
@@ -138,61 +157,85 @@ impl Protocol {
 
         let cipher = Aes256Gcm::new(&sk);
 
-        let ciphertext = cipher.encrypt(&nonce, &data, None).unwrap();
+        // AD = IK_A || IK_B, binds the initial message to both identities
+        let ciphertext = cipher.encrypt(&nonce, &data, Some(&data))?;
 
-        Ok((
-            self._sk.to_public(),
-            ephemeral_key.to_public(),
-            *receiver_onetime_key,
+        Ok(InitMsg {
+            identity: self._sk.to_public(),
+            ephemeral_key: ephemeral_key.to_public(),
+            onetime_key: receiver_onetime_key.copied(),
             sk,
             nonce,
             ciphertext,
-        ))
+        })
     }
 
     /// Derive secret key from sender's message.
+    ///
+    /// `receiver_onetime_key` is `None` when the sender used the 3-DH variant.
     pub fn derive_shared_secret(
         &mut self,
         sender_identity: &PublicKey,
         sender_ephemeral_key: &PublicKey,
-        receiver_onetime_key: &PublicKey,
+        receiver_onetime_key: Option<&PublicKey>,
         nonce: &[u8],
         ciphertext: &[u8],
-    ) -> XxxDhResult<Vec<u8>> {
+    ) -> XxxDhResult<SecretBytes> {
         let identity_secret = self._sk.secret();
         let prekey_secret = self._esk.secret();
 
-        let otk_storage = self._otk.clone().unwrap();
+        let mut source_data = vec![
+            (prekey_secret, sender_identity),
+            (identity_secret, sender_ephemeral_key),
+            (prekey_secret, sender_ephemeral_key),
+        ];
 
-        let onetime_keypair = otk_storage.get(0).unwrap();
+        let otk_index = match receiver_onetime_key {
+            Some(receiver_onetime_key) => {
+                let otk_storage = self._otk.as_ref().ok_or(XxxDhError::EmptyPrekeyList)?;
 
-        let sk = self._derive_sk([
-            (prekey_secret, &sender_identity),
-            (&identity_secret, sender_ephemeral_key),
-            (prekey_secret, sender_ephemeral_key),
-            (onetime_keypair.secret(), sender_ephemeral_key),
-        ])?;
+                let otk_index = otk_storage
+                    .iter()
+                    .position(|k| k.public() == receiver_onetime_key)
+                    .ok_or(XxxDhError::UnknownPrekey)?;
+                source_data.push((otk_storage[otk_index].secret(), sender_ephemeral_key));
+
+                Some(otk_index)
+            }
+            None => None,
+        };
+
+        let sk = self._derive_sk(&source_data)?;
+
+        let mut ad = sender_identity.to_vec();
+        ad.extend(self._sk.to_public().to_vec());
 
         let cipher = Aes256Gcm::new(&sk);
-        cipher.decrypt(nonce, ciphertext, None)?;
+        cipher.decrypt(nonce, ciphertext, Some(&ad))?;
+
+        // one-time keys are exactly that, a replayed init message must not derive the secret again
+        if let (Some(otk_index), Some(otk_storage)) = (otk_index, self._otk.as_mut()) {
+            otk_storage.remove(otk_index);
+        }
 
         Ok(sk)
     }
 
     /// Derive secret key.
-    fn _derive_sk(&self, source_data: [(&SecretKey, &PublicKey); 4]) -> XxxDhResult<Vec<u8>> {
+    fn _derive_sk(&self, source_data: &[(&SecretKey, &PublicKey)]) -> XxxDhResult<SecretBytes> {
         let mut data = vec![0_u8; <<SecretKey as DiffieHellman>::PK as Len>::LEN];
 
         for (sk, pk) in source_data {
             data.extend(sk.diffie_hellman(pk).to_vec());
         }
+        let data = SecretBytes::from(data);
 
         let h = cryptimitives::kdf::sha256::Kdf::new(
-            Some(&vec![0_u8; <<SecretKey as DiffieHellman>::SSK as Len>::LEN]),
+            Some(&[0_u8; <<SecretKey as DiffieHellman>::SSK as Len>::LEN]),
             &data,
         );
 
-        let mut sk = vec![0_u8; <<SecretKey as DiffieHellman>::SSK as Len>::LEN];
+        let mut sk = SecretBytes::from(vec![0_u8; <<SecretKey as DiffieHellman>::SSK as Len>::LEN]);
 
         h.expand(PROTOCOL_INFO.as_bytes(), &mut sk)?;
 
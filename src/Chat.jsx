@@ -63,6 +63,11 @@ function Chat({token, setToken, user, connection, setConnection}) {
 
   const [messagesLoaded, setMessagesLoaded] = useState(false);
 
+  // contacts currently typing, and the timers that hide a stale indicator or report a pause
+  const [typing, setTyping] = useState({});
+  const typingTimers = useRef({});
+  const typingIdle = useRef(null);
+
   const theme = useTheme();
   const [drawerOpen, setDrawerOpen] = useState(false);
 
@@ -132,14 +137,29 @@ function Chat({token, setToken, user, connection, setConnection}) {
     let filetype = re.exec(file)[1];
 
     let binary_data = await readFile(file);
-    let b64 = btoa(Uint8ToString(binary_data));
 
     let mime_type = getMimeTypeFromExtension(filetype);
 
-    let payload = {data: b64, mime_type: mime_type};
-    let json = JSON.stringify(payload);
+    let payload = {data: '', mime_type: mime_type};
 
-    let msgStruct = await sendPayload(json);
+    // the file is read and sent as a binary frame by the backend, no base64 round trip
+    let msgStruct = {
+      content: {
+        ciphertext: '',
+        nonce: '',
+        cleartext: null
+      },
+      timestamp: Math.floor(Date.now()/1000),
+      auth: null,
+      message_id: crypto.randomUUID(),
+      author: user,
+      recipient: contact
+    }
+
+    invoke("send_file", { msg: msgStruct, path: file, mimeType: mime_type });
+    msgStruct.author = "You";
+
+    setContact(msgStruct.recipient);
 
     let path = await appDataDir();
     const hash = SHA256(connection.host).toString();
@@ -189,6 +209,19 @@ function Chat({token, setToken, user, connection, setConnection}) {
     return msgStruct;
   }
 
+  // the backend rate limits these, so every keystroke can report
+  function reportTyping(active){
+    if(contact === ""){
+      return;
+    }
+    clearTimeout(typingIdle.current);
+    invoke("send_typing", { user: user, contact: contact, typing: active });
+    if(active){
+      const to = contact;
+      typingIdle.current = setTimeout(() => invoke("send_typing", { user: user, contact: to, typing: false }), 3000);
+    }
+  }
+
   async function sendMessage(){
 
     if(contact === ""){
@@ -220,6 +253,7 @@ function Chat({token, setToken, user, connection, setConnection}) {
     });
 
     messageRef.current.value = "";
+    reportTyping(false);
   }
 
   async function closeChat(){
@@ -272,7 +306,8 @@ function Chat({token, setToken, user, connection, setConnection}) {
           toast.info("Message received: 🖊️ " + payload.data);
         }
 
-        if(payload.mime_type !== "text/plain"){
+        // files of a chunked transfer were written by the backend already
+        if(payload.mime_type !== "text/plain" && !payload.stored){
           let path = await appDataDir();
           const hash = SHA256(connection.host).toString();
 
@@ -312,6 +347,177 @@ function Chat({token, setToken, user, connection, setConnection}) {
     }
 
 
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("msg_status", (e) => {
+      const {message_id, recipient, state} = e.payload;
+      setChat(prevChat => {
+        if (!(recipient in prevChat)) {
+          return prevChat;
+        }
+        const newChat = { ...prevChat };
+        // a late delivery receipt must not hide that the message was read
+        newChat[recipient] = newChat[recipient].map(m => m.message_id === message_id && m.status !== "read" ? { ...m, status: state } : m);
+        return newChat;
+      });
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("msg_read", (e) => {
+      const {contact, message_ids} = e.payload;
+      setChat(prevChat => {
+        if (!(contact in prevChat)) {
+          return prevChat;
+        }
+        const newChat = { ...prevChat };
+        newChat[contact] = newChat[contact].map(m => message_ids.includes(m.message_id) ? { ...m, status: "read" } : m);
+        return newChat;
+      });
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("typing", (e) => {
+      const {contact, typing} = e.payload;
+      clearTimeout(typingTimers.current[contact]);
+      if(typing){
+        // refreshed every few seconds while the contact types
+        typingTimers.current[contact] = setTimeout(() => setTyping(prev => ({ ...prev, [contact]: false })), 8000);
+      }
+      setTyping(prev => ({ ...prev, [contact]: typing }));
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+  }, []);
+
+  // report messages of the open chat as read, the backend drops this if receipts are off
+  useEffect(() => {
+    if (contact === "" || !(contact in chat)) {
+      return;
+    }
+    const unread = chat[contact].filter(m => m.author === contact && !m.read_reported);
+    if (unread.length === 0) {
+      return;
+    }
+    invoke("send_read_receipt", { user: user, contact: contact, messageIds: unread.map(m => m.message_id) });
+    setChat(prevChat => {
+      const newChat = { ...prevChat };
+      newChat[contact] = newChat[contact].map(m => m.author === contact ? { ...m, read_reported: true } : m);
+      return newChat;
+    });
+  }, [contact, chat]);
+
+  useEffect(() => {
+    const unlisten = listen("bundle_rejected", (e) => {
+      toast.error("The server sent an untrusted key bundle for " + e.payload.auth.user + ", messages are held back 🛑", {autoClose: false});
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("identity_changed", (e) => {
+      const {user, contact} = e.payload;
+      toast.warn("The identity key of " + contact + " changed, sending is blocked until you accept it ⚠️", {autoClose: false});
+      if (window.confirm("The identity key of " + contact + " changed. This can mean a new device or an attack. Accept the new key?")) {
+        invoke("accept_identity_change", { user: user, contact: contact });
+      }
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("msg_rejected", (e) => {
+      toast.error("Dropped a message from " + e.payload.author + " that failed authentication 🛑");
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("reconnecting", (e) => {
+      toast.info("Connection lost, reconnecting (attempt " + e.payload.attempt + ") 🔌", {toastId: "reconnecting"});
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("reconnected", (e) => {
+      toast.dismiss("reconnecting");
+      toast.success("Reconnected 🔌");
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("transfer_progress", (e) => {
+      const {transfer_id, contact, done, total} = e.payload;
+      if (!toast.isActive(transfer_id)) {
+        toast.info("File transfer with " + contact + " 📦", {toastId: transfer_id, progress: done / total});
+      } else if (done < total) {
+        toast.update(transfer_id, {progress: done / total});
+      } else {
+        toast.done(transfer_id);
+      }
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen("transfer_failed", (e) => {
+      toast.dismiss(e.payload.message_id);
+      toast.error("File from " + e.payload.author + " arrived damaged and was dropped 🛑");
+    });
+
+    return () => {
+      unlisten.then(f => f());
+    }
+
+
   }, []);
 
   useEffect(() => {
@@ -405,7 +611,7 @@ function Chat({token, setToken, user, connection, setConnection}) {
         sx={{ flexGrow: 1, bgcolor: 'background.default', p: 3 }}
       >
         
-        <ChatComponent chat={chat} contact={contact} message={messageRef}/>
+        <ChatComponent chat={chat} contact={contact} message={messageRef} typing={typing} onTyping={reportTyping}/>
         
             <BottomNavigation
               showLabels
@@ -7,7 +7,7 @@ import Message from './Message';
 
 import { writeFile, BaseDirectory } from '@tauri-apps/plugin-fs';
 
-const ChatComponent = ({ chat, contact, message }) => {
+const ChatComponent = ({ chat, contact, message, typing, onTyping }) => {
 
   const image_types = ["image/apng", "image/avif", "image/gif", "image/jpeg", "image/png", "image/svg+xml", "image/webp"];
   const video_types = ["video/mp4", "video/webm", "video/mpeg"]
@@ -45,15 +45,15 @@ const ChatComponent = ({ chat, contact, message }) => {
         
 
         {/*This is triggered when a user is typing*/}
-        {/* <div className="message__status">
-          <p>Someone is typing...</p>
-        </div> */}
+        {contact !== "" && typing[contact] && <div className="message__status">
+          <p>{contact} is typing...</p>
+        </div>}
 
           <TextField
             autoComplete='off'
             style={{marginTop: "auto"}}
             id="chatTextbox"
-            // onChange={(e) => setMessage(e.currentTarget.value)}
+            onChange={(e) => onTyping(e.currentTarget.value !== "")}
             inputRef={message}
             placeholder="Enter your Message..."
             type="Message"
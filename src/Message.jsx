@@ -15,6 +15,8 @@ const Message = ({ message, index, image_types, video_types }) => {
 
     let specific_css = " message__text";
 
+    const status_ticks = {sent: "✓", acked: "✓", delivered: "✓✓", read: "✓✓ read", failed: "⚠️ not sent"};
+
     const [url, setUrl] = useState("");
     const [output_message, setOutput_message] = useState("");
 
@@ -73,6 +75,7 @@ const Message = ({ message, index, image_types, video_types }) => {
                 <div className={message_css_class + specific_css}>
                     {cur_elem}
                 </div>
+                {message.author === "You" && message.status in status_ticks && <p className="message__status">{status_ticks[message.status]}</p>}
             </div>
       </>
     